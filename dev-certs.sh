@@ -0,0 +1,25 @@
+#!/bin/sh
+# Self-signed CA and ws server certificate for local wss:// tests.
+# Server: WS_TLS_CERT=certs/server.pem WS_TLS_KEY=certs/server.key
+# Bot: WS_SERVER_URL=wss://localhost WS_SERVER_CA=certs/ca.pem
+set -e
+DIR=${1:-certs}
+HOST=${2:-localhost}
+mkdir -p "$DIR"
+
+openssl req -x509 -newkey rsa:2048 -nodes -days 365 \
+    -subj "/CN=rs-algo dev CA" \
+    -keyout "$DIR/ca.key" -out "$DIR/ca.pem"
+
+openssl req -newkey rsa:2048 -nodes \
+    -subj "/CN=$HOST" \
+    -keyout "$DIR/server.key" -out "$DIR/server.csr"
+
+printf "subjectAltName=DNS:%s,IP:127.0.0.1\n" "$HOST" > "$DIR/server.ext"
+
+openssl x509 -req -days 365 -in "$DIR/server.csr" \
+    -CA "$DIR/ca.pem" -CAkey "$DIR/ca.key" -CAcreateserial \
+    -extfile "$DIR/server.ext" -out "$DIR/server.pem"
+
+rm "$DIR/server.csr" "$DIR/server.ext"
+echo "Certificates written to $DIR"
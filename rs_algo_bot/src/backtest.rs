@@ -0,0 +1,404 @@
+use crate::config::BotConfig;
+use crate::helpers::candles;
+use crate::reconciliation;
+use crate::risk_guard::{GuardStatus, RiskGuard, RiskLimits};
+use crate::strategies::registry::StrategyError;
+use crate::strategies::sizing::{self, PositionSizing, TradeRisk};
+use crate::strategies::strategy::*;
+
+use rs_algo_shared::broker::{DOHLC, VEC_DOHLC};
+use rs_algo_shared::helpers::date::{DateTime, Local};
+use rs_algo_shared::models::order::{self, Order};
+use rs_algo_shared::models::strategy::*;
+use rs_algo_shared::models::tick::InstrumentTick;
+use rs_algo_shared::models::time_frame::*;
+use rs_algo_shared::models::trade::*;
+use rs_algo_shared::scanner::instrument::{HTFInstrument, Instrument};
+
+use std::fs;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum BacktestError {
+    #[error("Usage: rs_algo_bot backtest <history.csv> <stream.csv> [htf_history.csv]")]
+    WrongArguments,
+    #[error("Can't read {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("Invalid candle in {0} line {1}: {2}")]
+    InvalidCandle(String, usize, String),
+    #[error("{0} strategies need HTF history data")]
+    MissingHTFData(String),
+    #[error(transparent)]
+    Strategy(#[from] StrategyError),
+}
+
+pub struct Backtest {
+    symbol: String,
+    time_frame: TimeFrameType,
+    higher_time_frame: Option<TimeFrameType>,
+    strategy_type: StrategyType,
+    instrument: Instrument,
+    htf_instrument: HTFInstrument,
+    strategy: Box<dyn Strategy>,
+    tick: InstrumentTick,
+    spread: f64,
+    pip_size: f64,
+    trades_in: Vec<TradeIn>,
+    trades_out: Vec<TradeOut>,
+    orders: Vec<Order>,
+    position_sizing: PositionSizing,
+    trade_risks: Vec<TradeRisk>,
+    equity: f64,
+    risk_limits: RiskLimits,
+    risk_guard: RiskGuard,
+}
+
+impl Backtest {
+    pub fn new(config: &BotConfig) -> Result<Self, BacktestError> {
+        let symbol = config.symbol.clone();
+        let market = config.market();
+        let time_frame = config.time_frame();
+        let higher_time_frame = config.higher_time_frame();
+        let strategy_type = config.strategy_type();
+        let pip_size = config.backtest_pip_size;
+        let spread = config.backtest_spread_pips * pip_size;
+
+        let strategy = set_strategy(
+            &config.strategy_name,
+            &time_frame.to_string(),
+            Some(&higher_time_frame.to_string()),
+            strategy_type.clone(),
+            config,
+        )?;
+
+        let instrument = Instrument::new()
+            .symbol(&symbol)
+            .market(market.to_owned())
+            .time_frame(time_frame.to_owned())
+            .build()
+            .unwrap();
+
+        let htf_instrument = match is_mtf_strategy(&strategy_type) {
+            true => HTFInstrument::HTFInstrument(
+                Instrument::new()
+                    .symbol(&symbol)
+                    .market(market)
+                    .time_frame(higher_time_frame.to_owned())
+                    .build()
+                    .unwrap(),
+            ),
+            false => HTFInstrument::None,
+        };
+
+        Ok(Self {
+            symbol,
+            time_frame,
+            higher_time_frame: Some(higher_time_frame),
+            strategy_type,
+            instrument,
+            htf_instrument,
+            strategy,
+            tick: InstrumentTick::default(),
+            spread,
+            pip_size,
+            trades_in: vec![],
+            trades_out: vec![],
+            orders: vec![],
+            position_sizing: config.position_sizing.clone(),
+            trade_risks: vec![],
+            equity: config.equity,
+            risk_limits: config.risk_limits.clone(),
+            risk_guard: RiskGuard::default(),
+        })
+    }
+
+    pub fn set_data(
+        &mut self,
+        data: VEC_DOHLC,
+        htf_data: Option<VEC_DOHLC>,
+    ) -> Result<(), BacktestError> {
+        log::info!("Backtest {} history bars loaded", data.len());
+        self.instrument.set_data(data).unwrap();
+
+        if let HTFInstrument::HTFInstrument(ref mut htf_instrument) = self.htf_instrument {
+            match htf_data {
+                Some(htf_data) => {
+                    log::info!("Backtest {} HTF history bars loaded", htf_data.len());
+                    htf_instrument.set_data(htf_data).unwrap();
+                }
+                None => {
+                    return Err(BacktestError::MissingHTFData(
+                        self.strategy_type.to_string(),
+                    ))
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn run(&mut self, stream: VEC_DOHLC) -> StrategyStats {
+        log::info!(
+            "Backtesting {} {}_{} over {} stream bars",
+            self.strategy.name(),
+            &self.symbol,
+            &self.time_frame,
+            stream.len()
+        );
+
+        for data in stream {
+            let index = self.instrument.data.len().checked_sub(1).unwrap();
+            let (new_candle, higher_candle) = candles::next_candle(
+                data,
+                &mut self.instrument,
+                &mut self.htf_instrument,
+                &self.strategy_type,
+            );
+
+            self.tick = self.candle_tick(&data);
+            let allow_entries = self.check_risk_limits(index, data.0);
+
+            let (new_position, new_orders, modified_stop) = self
+                .strategy
+                .next(
+                    &self.instrument,
+                    &self.htf_instrument,
+                    &self.trades_in,
+                    &self.trades_out,
+                    &self.orders,
+                    &self.tick,
+                    false,
+                    allow_entries,
+                )
+                .await;
+
+            self.fill(new_position);
+            self.fill(new_orders);
+
+            if let Some(stop) = modified_stop {
+                if self.open_positions() {
+                    order::update_orders(&mut self.orders, &vec![stop]);
+                }
+            }
+
+            if new_candle.is_closed() {
+                candles::close_candle(data, &mut self.instrument, &self.time_frame);
+            }
+
+            if higher_candle.is_closed() && is_mtf_strategy(&self.strategy_type) {
+                candles::close_htf_candle(
+                    &higher_candle,
+                    &mut self.htf_instrument,
+                    &self.higher_time_frame,
+                );
+            }
+
+            if !self.open_positions() {
+                self.orders =
+                    order::cancel_pending_expired_orders(index, &self.instrument, &mut self.orders);
+            }
+        }
+
+        log::info!(
+            "Backtest {} trades, average risk per trade {}",
+            self.trade_risks.len(),
+            sizing::average_risk(&self.trade_risks)
+        );
+
+        self.strategy
+            .update_stats(&self.instrument, &self.trades_in, &self.trades_out)
+    }
+
+    fn check_risk_limits(&mut self, index: usize, date: DateTime<Local>) -> bool {
+        let open_trade = reconciliation::open_trades(&self.trades_in, &self.trades_out)
+            .last()
+            .cloned();
+
+        match self.risk_guard.update(
+            &self.risk_limits,
+            self.equity,
+            self.position_sizing.pip_value,
+            open_trade.as_ref(),
+            &self.trades_in,
+            &self.trades_out,
+            &self.tick,
+            date,
+        ) {
+            GuardStatus::Trading => true,
+            GuardStatus::Halted => false,
+            GuardStatus::NewHalt { flatten } => {
+                if let (true, Some(trade_in)) = (flatten, open_trade) {
+                    let trade_out =
+                        reconciliation::close_trade(index, &self.instrument, &trade_in, &self.tick);
+                    self.fill(PositionResult::MarketOut(trade_out));
+                }
+                false
+            }
+        }
+    }
+
+    fn open_positions(&self) -> bool {
+        self.trades_in.len() > self.trades_out.len()
+    }
+
+    fn candle_tick(&self, data: &DOHLC) -> InstrumentTick {
+        let (date, _open, high, low, close, _volume) = *data;
+
+        InstrumentTick::new()
+            .symbol(self.symbol.clone())
+            .ask(close + self.spread)
+            .bid(close)
+            .high(high)
+            .low(low)
+            .spread(self.spread)
+            .pip_size(self.pip_size)
+            .time(date.timestamp())
+            .build()
+            .unwrap()
+    }
+
+    // Positions are filled as soon as they are emitted, at the price resolved
+    // by the strategy, mirroring an accepted TradeIn/TradeOutFulfilled response.
+    fn fill(&mut self, position: PositionResult) {
+        let open_positions = self.open_positions();
+
+        match position {
+            PositionResult::MarketIn(TradeResult::TradeIn(trade_in), associated_orders)
+                if !open_positions =>
+            {
+                if let Some(new_orders) = associated_orders {
+                    self.orders = order::add_pending(self.orders.clone(), new_orders);
+                }
+                self.fill_trade_in(trade_in);
+            }
+            PositionResult::MarketOut(TradeResult::TradeOut(trade_out)) if open_positions => {
+                self.fill_trade_out(trade_out);
+            }
+            PositionResult::PendingOrder(new_orders) if !open_positions => {
+                self.orders = order::add_pending(self.orders.clone(), new_orders);
+            }
+            PositionResult::MarketInOrder(TradeResult::TradeIn(trade_in), order)
+                if !open_positions =>
+            {
+                order::fulfill_bot_order::<TradeIn>(
+                    &trade_in,
+                    &order,
+                    &mut self.orders,
+                    &self.instrument,
+                );
+                self.fill_trade_in(trade_in);
+            }
+            PositionResult::MarketOutOrder(TradeResult::TradeOut(trade_out), order)
+                if open_positions =>
+            {
+                order::fulfill_bot_order::<TradeOut>(
+                    &trade_out,
+                    &order,
+                    &mut self.orders,
+                    &self.instrument,
+                );
+                self.fill_trade_out(trade_out);
+            }
+            _ => (),
+        }
+    }
+
+    fn fill_trade_in(&mut self, trade_in: TradeIn) {
+        order::update_trade_pending_orders(&mut self.orders, &trade_in);
+
+        let trade_risk = self
+            .position_sizing
+            .trade_risk(&trade_in, &self.orders, self.pip_size);
+
+        log::info!(
+            "{:?} {} filled at {} size {} risk {}",
+            trade_in.trade_type,
+            trade_in.id,
+            trade_in.price_in,
+            trade_in.size,
+            trade_risk.risk
+        );
+
+        self.trade_risks.push(trade_risk);
+        self.trades_in.push(trade_in);
+    }
+
+    fn fill_trade_out(&mut self, trade_out: TradeOut) {
+        let trade_out = self.strategy.update_trade_stats(
+            self.trades_in.last().unwrap(),
+            &trade_out,
+            &self.instrument.data,
+        );
+
+        log::info!(
+            "{:?} {} filled profit {} profit_per {}",
+            trade_out.trade_type,
+            trade_out.id,
+            trade_out.profit,
+            trade_out.profit_per
+        );
+
+        order::update_state_pending_orders(&trade_out, &mut self.orders);
+        self.trades_out.push(trade_out);
+    }
+}
+
+pub fn read_csv(path: &str) -> Result<VEC_DOHLC, BacktestError> {
+    let content =
+        fs::read_to_string(path).map_err(|err| BacktestError::Read(path.to_owned(), err))?;
+
+    let mut result: VEC_DOHLC = vec![];
+    for (line_number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.to_lowercase().starts_with("date") {
+            continue;
+        }
+
+        let invalid = |reason: &str| {
+            BacktestError::InvalidCandle(path.to_owned(), line_number + 1, reason.to_owned())
+        };
+
+        let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+        if fields.len() != 6 {
+            return Err(invalid("expected date,open,high,low,close,volume"));
+        }
+
+        let date = DateTime::<Local>::from_str(fields[0]).map_err(|_| invalid("wrong date"))?;
+        let mut values = [0.; 5];
+        for (i, value) in fields[1..].iter().enumerate() {
+            values[i] = value.parse::<f64>().map_err(|_| invalid("wrong number"))?;
+        }
+
+        result.push((date, values[0], values[1], values[2], values[3], values[4]));
+    }
+
+    Ok(result)
+}
+
+pub async fn run(args: &[String], config: &BotConfig) -> Result<StrategyStats, BacktestError> {
+    let (history, stream, htf_history) = match args {
+        [history, stream] => (history, stream, None),
+        [history, stream, htf_history] => (history, stream, Some(htf_history)),
+        _ => return Err(BacktestError::WrongArguments),
+    };
+
+    let history = read_csv(history)?;
+    let stream = read_csv(stream)?;
+    let htf_history = match htf_history {
+        Some(path) => Some(read_csv(path)?),
+        None => None,
+    };
+
+    let mut backtest = Backtest::new(config)?;
+
+    backtest.set_data(history, htf_history)?;
+
+    let stats = backtest.run(stream).await;
+    log::info!(
+        "Backtest finished with {} trades",
+        backtest.trades_out.len()
+    );
+
+    Ok(stats)
+}
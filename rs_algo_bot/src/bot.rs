@@ -1,13 +1,28 @@
+use crate::config::BotConfig;
 use crate::error::{Result, RsAlgoError, RsAlgoErrorKind};
+use crate::gaps::{self, TradingWeek};
+use crate::helpers::candles;
 use crate::helpers::vars::*;
-use crate::message;
+use crate::message::{
+    self, DecodeErrors, ErrorCode, ErrorResponse, ExtendedCommand, ExtendedCommandType,
+    ModifyOrderPayload, Response, SymbolPayload,
+};
+use crate::pending::PendingRequests;
+use crate::position::{PositionEvent, PositionState, PositionTracker, TransitionError};
+use crate::reconciliation::{self, ReconciliationAction, ReconciliationReport};
+use crate::reconnect::ReconnectCause;
+use crate::risk_guard::{GuardStatus, RiskGuard};
+use crate::strategies::params::{self, ParamSet};
+use crate::strategies::sizing::{self, LotLimits, TradeRisk};
 use crate::strategies::strategy::*;
+use crate::ws_client::WebSocket;
 
+use rs_algo_shared::broker::VEC_DOHLC;
 use rs_algo_shared::helpers::date::{self, Local, Timelike};
 use rs_algo_shared::helpers::uuid::*;
 use rs_algo_shared::helpers::{date::*, uuid};
 use rs_algo_shared::models::bot::BotData;
-use rs_algo_shared::models::environment::{self, *};
+use rs_algo_shared::models::environment::*;
 use rs_algo_shared::models::mode::ExecutionMode;
 use rs_algo_shared::models::order::{Order, OrderStatus};
 use rs_algo_shared::models::tick::InstrumentTick;
@@ -15,17 +30,35 @@ use rs_algo_shared::models::time_frame::*;
 use rs_algo_shared::models::trade::*;
 use rs_algo_shared::models::{market::*, order};
 use rs_algo_shared::models::{strategy::*, trade};
-use rs_algo_shared::scanner::candle::Candle;
 use rs_algo_shared::scanner::instrument::{HTFInstrument, Instrument};
 use rs_algo_shared::ws::message::*;
-use rs_algo_shared::ws::ws_client::WebSocket;
 
 use futures::Future;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::cmp::Ordering;
-use std::env;
+use std::collections::VecDeque;
 use std::time::Duration;
-use tokio::time::sleep;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time::{sleep, sleep_until, Instant};
+use tokio_tungstenite::tungstenite::Message;
+
+// Bars fetched before the last one received on incremental syncs
+const SYNC_OVERLAP_BARS: i64 = 2;
+
+/// How the next InstrumentData responses are applied
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DataSync {
+    /// Replace the local data, on startup and clean reconnects
+    Full,
+    /// Merge the bars missing since the last stream data into the local ones
+    Incremental(DateTime<Local>),
+    /// Merge the bars missing since the given candle, skipped by the stream
+    Backfill(DateTime<Local>),
+    /// Data loaded. Stream data is processed
+    Synced,
+}
 
 #[derive(Serialize)]
 pub struct Bot {
@@ -58,6 +91,46 @@ pub struct Bot {
     strategy_stats: StrategyStats,
     #[serde(skip_serializing)]
     strategy: Box<dyn Strategy>,
+    position: PositionTracker,
+    strategy_params: Vec<ParamSet>,
+    trade_risks: Vec<TradeRisk>,
+    risk_guard: RiskGuard,
+    /// Sent so the server converts trade profits to account currency
+    pip_value: f64,
+    pip_size: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_reconciliation: Option<ReconciliationReport>,
+    /// Orphan broker positions left to close under the Close policy
+    #[serde(skip_serializing)]
+    orphans_to_close: VecDeque<TradeOut>,
+    /// Cleared once the broker answers it can't move stops, which are then
+    /// only moved locally
+    #[serde(skip_serializing)]
+    modifies_orders: bool,
+    #[serde(skip_serializing)]
+    consecutive_errors: u32,
+    /// Commands retried once their error backoff is over
+    #[serde(skip_serializing)]
+    scheduled_retries: Vec<(Instant, String)>,
+    #[serde(skip_serializing)]
+    decode_errors: DecodeErrors,
+    #[serde(skip_serializing)]
+    pending_requests: PendingRequests,
+    #[serde(skip_serializing)]
+    subscribed: bool,
+    #[serde(skip_serializing)]
+    reconnect_attempts: u32,
+    #[serde(skip_serializing)]
+    data_sync: DataSync,
+    /// Set once shutting down, until when in flight executions are awaited
+    #[serde(skip_serializing)]
+    shutdown_deadline: Option<Instant>,
+    /// True once SIGTERM or SIGINT is received. Long waits are raced
+    /// against it.
+    #[serde(skip_serializing)]
+    terminate: watch::Receiver<bool>,
+    #[serde(skip_serializing)]
+    config: BotConfig,
 }
 
 impl Bot {
@@ -96,14 +169,12 @@ impl Bot {
             data: Some(&self),
         };
 
-        self.websocket
-            .send(&serde_json::to_string(&update_bot_data_command).unwrap())
-            .await
-            .unwrap();
+        let command = serde_json::to_value(&update_bot_data_command).unwrap();
+        self.send_command(command).await;
     }
 
     pub async fn get_instrument_data(&mut self) {
-        let num_bars = env::var("NUM_BARS").unwrap().parse::<i64>().unwrap();
+        let num_bars = self.bars_to_fetch(&self.time_frame);
         let time_frame_from =
             TimeFrame::get_starting_bar(num_bars, &self.time_frame, &ExecutionMode::Bot);
 
@@ -124,10 +195,8 @@ impl Bot {
             }),
         };
 
-        self.websocket
-            .send(&serde_json::to_string(&get_instrument_data).unwrap())
-            .await
-            .unwrap();
+        self.send_command(serde_json::to_value(&get_instrument_data).unwrap())
+            .await;
 
         let higher_time_frame = match &self.higher_time_frame {
             Some(htf) => htf,
@@ -142,14 +211,12 @@ impl Bot {
                     strategy: &self.strategy_name,
                     strategy_type: self.strategy_type.to_owned(),
                     time_frame: higher_time_frame.to_owned(),
-                    num_bars,
+                    num_bars: self.bars_to_fetch(higher_time_frame),
                 }),
             };
 
-            self.websocket
-                .send(&serde_json::to_string(&get_higher_instrument_data).unwrap())
-                .await
-                .unwrap();
+            self.send_command(serde_json::to_value(&get_higher_instrument_data).unwrap())
+                .await;
 
             log::info!(
                 "Requesting HTF {}_{} data from {:?}",
@@ -160,6 +227,69 @@ impl Bot {
         }
     }
 
+    // Only the bars missing since the last stream data on incremental syncs
+    fn bars_to_fetch(&self, time_frame: &TimeFrameType) -> i64 {
+        match self.data_sync {
+            DataSync::Incremental(since) | DataSync::Backfill(since) => {
+                let minutes = (Local::now() - since).num_minutes().max(0);
+                let bars = minutes / (time_frame.to_number() as i64).max(1) + SYNC_OVERLAP_BARS;
+                bars.min(self.config.num_bars)
+            }
+            _ => self.config.num_bars,
+        }
+    }
+
+    // Indicators are always calculated again, over the merged bars when syncing
+    // incrementally
+    fn load_data(&self, instrument: &Instrument, data: VEC_DOHLC) -> Instrument {
+        let data = match self.data_sync {
+            DataSync::Incremental(_) | DataSync::Backfill(_) => candles::merge_data(
+                &instrument.data,
+                data,
+                self.config.num_bars as usize,
+                &instrument.time_frame(),
+            ),
+            _ => data,
+        };
+
+        let mut loaded =
+            candles::new_instrument(&self.symbol, &self.market, &instrument.time_frame());
+        loaded.set_data(data).unwrap();
+        loaded
+    }
+
+    // Candles skipped by the stream are requested again. Stream data and
+    // entries are suspended until they are merged.
+    async fn backfill_gap(&mut self, bar_date: DateTime<Local>) -> bool {
+        let last_candle = match self.instrument.data.last() {
+            Some(candle) => candle.date(),
+            None => return false,
+        };
+
+        let period_minutes = self.time_frame.to_number() as i64;
+        if bar_date - last_candle < date::Duration::minutes(2 * period_minutes) {
+            return false;
+        }
+
+        let week = TradingWeek::new(&self.market_hours, self.config.market_timezone());
+        let missing = gaps::missing_candles(last_candle, bar_date, period_minutes, &week);
+        if missing == 0 {
+            return false;
+        }
+
+        log::warn!(
+            "{} {} candles missing between {} and {}. Backfilling",
+            self.symbol,
+            missing,
+            last_candle,
+            bar_date
+        );
+
+        self.data_sync = DataSync::Backfill(last_candle);
+        self.get_instrument_data().await;
+        true
+    }
+
     pub async fn get_tick_data(&mut self) {
         let instrument_tick_data = Command {
             command: CommandType::GetInstrumentTick,
@@ -169,10 +299,8 @@ impl Bot {
             }),
         };
 
-        self.websocket
-            .send(&serde_json::to_string(&instrument_tick_data).unwrap())
-            .await
-            .unwrap();
+        self.send_command(serde_json::to_value(&instrument_tick_data).unwrap())
+            .await;
     }
 
     pub async fn is_market_open(&mut self) {
@@ -186,10 +314,8 @@ impl Bot {
             }),
         };
 
-        self.websocket
-            .send(&serde_json::to_string(&instrument_pricing_data).unwrap())
-            .await
-            .unwrap();
+        self.send_command(serde_json::to_value(&instrument_pricing_data).unwrap())
+            .await;
     }
 
     pub async fn subscribing_to_stream(&mut self) {
@@ -199,6 +325,8 @@ impl Bot {
             &self.time_frame
         );
 
+        self.subscribed = true;
+
         let subscribe_command = Command {
             command: CommandType::SubscribeStream,
             data: Some(Payload {
@@ -209,17 +337,12 @@ impl Bot {
             }),
         };
 
-        self.websocket
-            .send(&serde_json::to_string(&subscribe_command).unwrap())
-            .await
-            .unwrap();
+        self.send_command(serde_json::to_value(&subscribe_command).unwrap())
+            .await;
     }
 
     pub async fn restore_values(&mut self, data: BotData) {
-        let max_historical_positions = env::var("MAX_HISTORICAL_POSITIONS")
-            .unwrap()
-            .parse::<usize>()
-            .unwrap();
+        let max_historical_positions = self.config.max_historical_positions;
 
         self.strategy_stats = data.strategy_stats().clone();
 
@@ -247,6 +370,8 @@ impl Bot {
             .take(max_historical_positions)
             .cloned()
             .collect();
+
+        sizing::retain_trades(&mut self.trade_risks, &self.trades_in);
     }
 
     pub async fn fullfill_activated_order<T: Trade>(
@@ -254,41 +379,41 @@ impl Bot {
         activated_orders: &PositionResult,
         trade: &T,
         order: &Order,
-        open_positions: &mut bool,
-    ) {
-        let should_open = match activated_orders {
-            PositionResult::MarketInOrder(_, _) => true,
-            _ => false,
-        };
+        event: PositionEvent,
+    ) -> bool {
+        if let Err(err) = self.position.apply(event) {
+            log::warn!(
+                "Activated order {} {:?} rejected. {}",
+                trade.get_index_in(),
+                trade.get_type(),
+                err
+            );
+            return false;
+        }
 
-        if *open_positions != should_open {
-            order::fulfill_bot_order::<T>(trade, order, &mut self.orders, &self.instrument);
+        order::fulfill_bot_order::<T>(trade, order, &mut self.orders, &self.instrument);
 
-            *open_positions = should_open;
+        log::info!(
+            "Sending activated order {} {:?} ...",
+            trade.get_index_in(),
+            trade.get_type()
+        );
 
-            log::info!(
-                "Sending activated order {} {:?} ...",
-                trade.get_index_in(),
-                trade.get_type()
-            );
+        self.send_position::<PositionResult>(
+            activated_orders,
+            self.symbol.clone(),
+            self.strategy_name.clone(),
+        )
+        .await;
 
-            self.send_position::<PositionResult>(
-                activated_orders,
-                self.symbol.clone(),
-                self.strategy_name.clone(),
-            )
-            .await;
-        }
+        true
     }
 
     fn add_trade_in(&mut self, trade_in: &TradeIn) {
         let num_trades_in = self.trades_in.len();
         let num_trades_out = self.trades_out.len();
 
-        let max_buy_orders = env::var("MAX_BUY_ORDERS")
-            .unwrap()
-            .parse::<usize>()
-            .unwrap();
+        let max_buy_orders = self.config.max_buy_orders;
 
         let has_active_trade = match num_trades_in.checked_sub(num_trades_out) {
             Some(diff) => diff < max_buy_orders,
@@ -327,91 +452,89 @@ impl Bot {
         &mut self,
         new_position: &PositionResult,
         associated_orders: Option<&Vec<Order>>,
-        open_positions: &mut bool,
-    ) {
-        match new_position {
-            PositionResult::MarketIn(TradeResult::TradeIn(trade_in), _) if !*open_positions => {
-                log::info!(
-                    "Sending tradeIn {} {:?} ...",
-                    trade_in.get_index_in(),
-                    trade_in.get_type()
-                );
+    ) -> bool {
+        let (event, index_in, trade_type) = match new_position {
+            PositionResult::MarketIn(TradeResult::TradeIn(trade_in), _) => (
+                PositionEvent::EntrySent,
+                trade_in.get_index_in(),
+                trade_in.get_type(),
+            ),
+            PositionResult::MarketOut(TradeResult::TradeOut(trade_out)) => (
+                PositionEvent::ExitSent,
+                trade_out.get_index_in(),
+                trade_out.get_type(),
+            ),
+            _ => return false,
+        };
 
-                self.send_position::<PositionResult>(
-                    &new_position,
-                    self.symbol.clone(),
-                    self.strategy_name.clone(),
-                )
-                .await;
+        if let Err(err) = self.position.apply(event) {
+            log::warn!("Trade {} {:?} rejected. {}", index_in, trade_type, err);
+            return false;
+        }
 
-                if let Some(new_ords) = associated_orders {
-                    self.orders = order::add_pending(self.orders.clone(), new_ords.clone());
-                }
-                *open_positions = true;
-            }
-            PositionResult::MarketOut(TradeResult::TradeOut(trade_out)) if *open_positions => {
-                log::info!(
-                    "Sending tradeOut {} {:?} ...",
-                    trade_out.get_index_in(),
-                    trade_out.get_type()
-                );
-                self.send_position::<PositionResult>(
-                    &new_position,
-                    self.symbol.clone(),
-                    self.strategy_name.clone(),
-                )
-                .await;
-                *open_positions = false;
-            }
-            PositionResult::PendingOrder(associated_orders) if !*open_positions => {
-                self.orders = order::add_pending(self.orders.clone(), associated_orders.clone());
-            }
-            _ => (),
+        log::info!("Sending trade {} {:?} ...", index_in, trade_type);
+
+        self.send_position::<PositionResult>(
+            new_position,
+            self.symbol.clone(),
+            self.strategy_name.clone(),
+        )
+        .await;
+
+        if let Some(new_ords) = associated_orders {
+            self.orders = order::add_pending(self.orders.clone(), new_ords.clone());
         }
+
+        true
     }
 
     async fn process_new_positions_and_orders(
         &mut self,
         new_position: PositionResult,
         new_orders: PositionResult,
-        open_positions: &mut bool,
+        modified_stop: Option<Order>,
     ) {
         match new_position {
             PositionResult::None => (),
             _ => {
-                self.process_activated_positions(&new_position, open_positions)
-                    .await;
+                self.process_activated_positions(&new_position).await;
             }
         };
 
         match new_orders {
             PositionResult::None => (),
             _ => {
-                self.process_activated_orders(&new_orders, open_positions)
-                    .await;
+                self.process_activated_orders(&new_orders).await;
             }
         };
+
+        if let Some(stop) = modified_stop {
+            if self.position.state() == PositionState::Open {
+                match self.modifies_orders {
+                    true => self.modify_order(stop).await,
+                    false => self.move_local_stop(stop).await,
+                }
+            }
+        }
     }
 
-    async fn process_activated_positions(
-        &mut self,
-        new_position: &PositionResult,
-        open_positions: &mut bool,
-    ) {
+    async fn process_activated_positions(&mut self, new_position: &PositionResult) {
         match new_position {
             PositionResult::MarketIn(TradeResult::TradeIn(trade_in), associated_orders) => {
-                self.process_trade(new_position, associated_orders.as_ref(), open_positions)
-                    .await;
-
-                self.trades_in.push(trade_in.clone());
+                if self
+                    .process_trade(new_position, associated_orders.as_ref())
+                    .await
+                {
+                    self.add_trade_in(trade_in);
+                }
             }
             PositionResult::MarketOut(TradeResult::TradeOut(trade_out)) => {
-                self.process_trade(new_position, None, open_positions).await;
-
-                self.trades_out.push(trade_out.clone());
+                if self.process_trade(new_position, None).await {
+                    self.add_trade_out(trade_out);
+                }
             }
             PositionResult::PendingOrder(associated_orders) => {
-                if !*open_positions {
+                if self.position.is_flat() {
                     self.orders =
                         order::add_pending(self.orders.clone(), associated_orders.clone());
                 }
@@ -420,33 +543,33 @@ impl Bot {
         }
     }
 
-    async fn process_activated_orders(
-        &mut self,
-        activated_orders: &PositionResult,
-        open_positions: &mut bool,
-    ) {
+    async fn process_activated_orders(&mut self, activated_orders: &PositionResult) {
         match activated_orders {
             PositionResult::MarketInOrder(TradeResult::TradeIn(trade_in), order) => {
-                self.fullfill_activated_order::<TradeIn>(
-                    activated_orders,
-                    trade_in,
-                    order,
-                    open_positions,
-                )
-                .await;
-
-                self.trades_in.push(trade_in.clone());
+                if self
+                    .fullfill_activated_order::<TradeIn>(
+                        activated_orders,
+                        trade_in,
+                        order,
+                        PositionEvent::EntrySent,
+                    )
+                    .await
+                {
+                    self.add_trade_in(trade_in);
+                }
             }
             PositionResult::MarketOutOrder(TradeResult::TradeOut(trade_out), order) => {
-                self.fullfill_activated_order::<TradeOut>(
-                    activated_orders,
-                    trade_out,
-                    order,
-                    open_positions,
-                )
-                .await;
-
-                self.add_trade_out(trade_out);
+                if self
+                    .fullfill_activated_order::<TradeOut>(
+                        activated_orders,
+                        trade_out,
+                        order,
+                        PositionEvent::ExitSent,
+                    )
+                    .await
+                {
+                    self.add_trade_out(trade_out);
+                }
             }
             _ => (),
         };
@@ -463,18 +586,72 @@ impl Bot {
                 strategy_name,
                 data: trade,
                 options: TradeOptions {
-                    non_profitable_out: env::var("NON_PROFITABLE_OUTS")
-                        .unwrap()
-                        .parse::<bool>()
-                        .unwrap(),
+                    non_profitable_out: self.config.non_profitable_outs,
                 },
             }),
         };
 
-        self.websocket
-            .send(&serde_json::to_string(&execute_trade).unwrap())
-            .await
-            .unwrap();
+        self.send_command(serde_json::to_value(&execute_trade).unwrap())
+            .await;
+    }
+
+    // The local stop is only moved once the broker confirms it
+    pub async fn modify_order(&mut self, order: Order) {
+        log::info!(
+            "Moving {:?} {} to {} ...",
+            order.order_type,
+            order.id,
+            order.target_price
+        );
+
+        let modify_order = ExtendedCommand {
+            command: ExtendedCommandType::ModifyOrder,
+            data: Some(ModifyOrderPayload {
+                symbol: &self.symbol,
+                strategy_name: &self.strategy_name,
+                data: &order,
+            }),
+        };
+
+        self.send_command(serde_json::to_value(&modify_order).unwrap())
+            .await;
+    }
+
+    // Brokers that can't move stops keep the original one. The bot order
+    // engine closes the trade once the moved stop is reached.
+    async fn move_local_stop(&mut self, order: Order) {
+        log::info!(
+            "{:?} {} moved to {}. Enforced by the bot",
+            order.order_type,
+            order.id,
+            order.target_price
+        );
+
+        order::update_orders(&mut self.orders, &vec![order]);
+        self.send_bot_status(&self.symbol.clone()).await;
+    }
+
+    pub async fn get_lot_limits(&mut self) {
+        let get_lot_limits = ExtendedCommand {
+            command: ExtendedCommandType::GetLotLimits,
+            data: Some(SymbolPayload {
+                symbol: &self.symbol,
+            }),
+        };
+
+        self.send_command(serde_json::to_value(&get_lot_limits).unwrap())
+            .await;
+    }
+
+    fn apply_lot_limits(&mut self, limits: Option<LotLimits>) {
+        match limits {
+            Some(limits) => {
+                log::info!("Broker lot limits {:?}", limits);
+                self.config.position_sizing.apply_limits(&limits);
+                self.strategy.set_lot_limits(&limits);
+            }
+            None => log::info!("No broker lot limits. Using the configured ones"),
+        }
     }
 
     pub async fn send_bot_status(&mut self, _bot_str: &str) {
@@ -485,10 +662,8 @@ impl Bot {
             data: Some(&self),
         };
 
-        self.websocket
-            .send(&serde_json::to_string(&update_bot_data_command).unwrap())
-            .await
-            .unwrap();
+        let command = serde_json::to_value(&update_bot_data_command).unwrap();
+        self.send_command(command).await;
     }
 
     pub async fn get_active_positions(&mut self) {
@@ -502,10 +677,191 @@ impl Bot {
             }),
         };
 
-        self.websocket
-            .send(&serde_json::to_string(&active_positions_command).unwrap())
-            .await
-            .unwrap();
+        self.send_command(serde_json::to_value(&active_positions_command).unwrap())
+            .await;
+    }
+
+    // Broker positions are only reconciled in prod, where they are real
+    pub async fn instrument_data_loaded(&mut self) {
+        let backfill = matches!(self.data_sync, DataSync::Backfill(_));
+        self.data_sync = DataSync::Synced;
+
+        if backfill {
+            log::info!("{} backfilled. Resuming stream data", self.symbol);
+            return;
+        }
+
+        match self.env.is_prod() {
+            true => self.get_active_positions().await,
+            false => self.subscribing_to_stream().await,
+        }
+    }
+
+    pub async fn reconcile_positions(&mut self, broker_position: PositionResult) {
+        if let Err(err) = self.position.apply(PositionEvent::Reconcile) {
+            log::error!("Can't reconcile positions. {}", err);
+            return;
+        }
+
+        let index = self.instrument.data.len().saturating_sub(1);
+
+        let reconciliation = reconciliation::reconcile(
+            self.config.reconciliation_policy(),
+            broker_position,
+            index,
+            &self.instrument,
+            &self.tick,
+            &mut self.trades_in,
+            &self.trades_out,
+            &mut self.orders,
+        );
+
+        for (trade_in, trade_out) in reconciliation.closed_externally {
+            let trade_out =
+                self.strategy
+                    .update_trade_stats(&trade_in, &trade_out, &self.instrument.data);
+
+            order::update_state_pending_orders(&trade_out, &mut self.orders);
+            self.add_trade_out(&trade_out);
+        }
+
+        let open_positions = self.trades_in.len() > self.trades_out.len();
+        if let Err(err) = self
+            .position
+            .apply(PositionEvent::Reconciled(open_positions))
+        {
+            log::error!("Reconciliation not applied. {}", err);
+        }
+
+        self.orphans_to_close = reconciliation.to_close.into();
+        self.close_next_orphan().await;
+
+        let report = reconciliation.report;
+
+        match report.in_sync() {
+            true => log::info!(
+                "Reconciliation: {} db open trades in sync with broker",
+                report.db_open_trades
+            ),
+            false => {
+                log::warn!(
+                    "Reconciliation: {} db open trades, {} broker positions. {:?} policy",
+                    report.db_open_trades,
+                    report.broker_positions,
+                    report.policy
+                );
+
+                for action in report.actions.iter() {
+                    match action {
+                        ReconciliationAction::Updated(id) => {
+                            log::info!("Position {} updated from broker", id)
+                        }
+                        ReconciliationAction::Adopted(id) => {
+                            log::warn!("Broker position {} adopted", id)
+                        }
+                        ReconciliationAction::Closed(id) => {
+                            log::warn!("Broker position {} closed", id)
+                        }
+                        ReconciliationAction::ClosedExternally(id) => {
+                            log::warn!("Trade {} closed externally", id)
+                        }
+                    }
+                }
+            }
+        };
+
+        self.strategy_stats =
+            self.strategy
+                .update_stats(&self.instrument, &self.trades_in, &self.trades_out);
+
+        self.last_reconciliation = Some(report);
+        self.send_bot_status("").await;
+    }
+
+    // Orphans are closed one at a time, each one through its own exit
+    async fn close_next_orphan(&mut self) {
+        let trade_out = match self.orphans_to_close.pop_front() {
+            Some(trade_out) => trade_out,
+            None => return,
+        };
+
+        // Flat once the previous orphan exit is filled, with the rest still open
+        let ready = match self.position.state() {
+            PositionState::Open => Ok(PositionState::Open),
+            PositionState::Flat => self
+                .position
+                .apply(PositionEvent::Reconcile)
+                .and_then(|_| self.position.apply(PositionEvent::Reconciled(true))),
+            state => Err(TransitionError {
+                from: state,
+                event: PositionEvent::ExitSent,
+            }),
+        };
+
+        if let Err(err) = ready {
+            log::warn!("Orphan position {} not closed yet. {}", trade_out.id, err);
+            self.orphans_to_close.push_front(trade_out);
+            return;
+        }
+
+        log::info!(
+            "Closing orphan position {:?} {} ...",
+            trade_out.trade_type,
+            trade_out.id
+        );
+
+        self.process_activated_positions(&PositionResult::MarketOut(TradeResult::TradeOut(
+            trade_out,
+        )))
+        .await;
+    }
+
+    fn restore_risk_guard(&mut self, txt: &str) {
+        if let Some(risk_guard) = message::parse_payload_field(txt, "risk_guard") {
+            self.risk_guard = risk_guard;
+        }
+
+        if self.config.risk_limits.reset {
+            self.risk_guard.reset();
+        }
+
+        if let Some(halt) = self.risk_guard.halted() {
+            log::warn!("Entries halted since {} by {:?}", halt.since, halt.reason);
+        }
+    }
+
+    async fn check_risk_limits(&mut self) -> bool {
+        let open_trade = reconciliation::open_trades(&self.trades_in, &self.trades_out)
+            .last()
+            .cloned();
+
+        match self.risk_guard.update(
+            &self.config.risk_limits,
+            self.config.equity,
+            self.config.position_sizing.pip_value,
+            open_trade.as_ref(),
+            &self.trades_in,
+            &self.trades_out,
+            &self.tick,
+            Local::now(),
+        ) {
+            GuardStatus::Trading => true,
+            GuardStatus::Halted => false,
+            GuardStatus::NewHalt { flatten } => {
+                if let (true, Some(trade_in)) = (flatten, open_trade) {
+                    log::warn!("Flattening trade {} after risk limit breach", trade_in.id);
+
+                    let index = self.instrument.data.len().saturating_sub(1);
+                    let trade_out =
+                        reconciliation::close_trade(index, &self.instrument, &trade_in, &self.tick);
+                    self.process_activated_positions(&PositionResult::MarketOut(trade_out))
+                        .await;
+                }
+
+                self.send_bot_status("").await;
+                false
+            }
+        }
     }
 
     pub async fn get_market_hours(&mut self) {
@@ -519,61 +875,417 @@ impl Bot {
             }),
         };
 
-        self.websocket
-            .send(&serde_json::to_string(&data).unwrap())
-            .await
-            .unwrap();
+        self.send_command(serde_json::to_value(&data).unwrap())
+            .await;
     }
 
-    pub async fn reconnect(&mut self) {
-        let mut secs = env::var("DISCONNECTED_RETRY")
-            .unwrap()
-            .parse::<u64>()
-            .unwrap();
+    pub async fn reconnect(&mut self, cause: ReconnectCause) {
+        if self.shutdown_deadline.is_some() {
+            log::info!("Shutting down. Not reconnecting. {:?}", cause);
+            return;
+        }
 
-        let ten_random_secs = (std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .expect("Time went backwards")
-            .subsec_nanos()
-            % 11) as u64;
+        let initial = self.config.disconnected_retry;
 
-        // Add the random number to secs
-        secs += ten_random_secs;
+        let delay = match cause {
+            ReconnectCause::Disconnected => {
+                self.reconnect_attempts += 1;
+                let failed_attempts = self.reconnect_attempts - 1;
 
-        log::info!("Reconnecting in {} secs...", secs);
+                if self.config.reconnect.alert(failed_attempts) {
+                    log::error!(
+                        "[ALERT] {} can't reconnect to {} after {} attempts",
+                        self.symbol,
+                        self.config.ws_server_url,
+                        failed_attempts
+                    );
+                }
 
-        sleep(Duration::from_secs(secs)).await;
-        self.websocket.re_connect().await;
+                self.config
+                    .reconnect
+                    .delay(initial, self.reconnect_attempts)
+            }
+            ReconnectCause::Requested { clean_data } => {
+                if clean_data {
+                    self.clean_data();
+                }
+                self.config.reconnect.delay(initial, 1)
+            }
+        };
+
+        self.data_sync = match cause {
+            ReconnectCause::Requested { clean_data: true } => DataSync::Full,
+            _ if self.instrument.data.is_empty() => DataSync::Full,
+            _ => DataSync::Incremental(self.last_stream_received),
+        };
+
+        log::info!(
+            "Reconnecting in {:.1} secs. {:?}",
+            delay.as_secs_f64(),
+            cause
+        );
+
+        if !self.sleep_unless_terminated(delay).await {
+            return;
+        }
+
+        self.pending_requests.clear();
+        self.subscribed = false;
+        self.websocket.connect().await;
         self.init_session().await;
     }
 
+    // Market data is loaded again from scratch once the session starts
+    fn clean_data(&mut self) {
+        log::info!("Cleaning {} market data", self.symbol);
+
+        self.instrument = candles::new_instrument(&self.symbol, &self.market, &self.time_frame);
+
+        if let (HTFInstrument::HTFInstrument(htf_instrument), Some(higher_time_frame)) =
+            (&mut self.htf_instrument, &self.higher_time_frame)
+        {
+            *htf_instrument =
+                candles::new_instrument(&self.symbol, &self.market, higher_time_frame);
+        }
+    }
+
+    // False when shutdown started before the delay elapsed
+    async fn sleep_unless_terminated(&mut self, delay: Duration) -> bool {
+        if self.shutdown_deadline.is_some() {
+            return false;
+        }
+
+        let mut terminate = self.terminate.clone();
+
+        tokio::select! {
+            _ = sleep(delay) => true,
+            Ok(_) = terminate.wait_for(|terminated| *terminated) => {
+                self.start_shutdown();
+                false
+            }
+        }
+    }
+
+    fn start_shutdown(&mut self) {
+        let timeout = Duration::from_secs(self.config.shutdown_timeout);
+        self.shutdown_deadline = Some(Instant::now() + timeout);
+
+        log::warn!(
+            "Shutting down. No new entries. Position {:?}",
+            self.position.state()
+        );
+    }
+
+    // Done once no response is pending, like an execution or a ModifyOrder,
+    // or the deadline is reached
+    fn shutdown_finished(&self) -> bool {
+        let deadline = match self.shutdown_deadline {
+            Some(deadline) => deadline,
+            None => return false,
+        };
+
+        let state = self.position.state();
+        let in_flight = matches!(
+            state,
+            PositionState::PendingEntry | PositionState::PendingExit
+        ) || !self.pending_requests.is_empty();
+
+        let timed_out = Instant::now() >= deadline;
+
+        if in_flight && timed_out {
+            log::error!(
+                "Shutdown timeout reached with position {:?} and {} pending requests. Exiting without their responses",
+                state,
+                self.pending_requests.len()
+            );
+        }
+
+        !in_flight || timed_out
+    }
+
+    // Exponential backoff while the server keeps failing
+    fn error_backoff(&self) -> Duration {
+        let exponent = self.consecutive_errors.saturating_sub(1).min(16);
+        let secs = self.config.error_retry.saturating_mul(2u64.pow(exponent));
+        Duration::from_secs(secs.min(self.config.max_error_retry))
+    }
+
+    async fn handle_error(&mut self, error: ErrorResponse) {
+        self.consecutive_errors += 1;
+        let backoff = self.error_backoff();
+
+        log::error!(
+            "{} {:?} error: {}. {} consecutive errors",
+            error.command,
+            error.code,
+            error.message,
+            self.consecutive_errors
+        );
+
+        if error.code == ErrorCode::Unsupported && error.command == message::MODIFY_ORDER {
+            log::warn!("The broker can't modify orders. Stops are enforced by the bot");
+            self.modifies_orders = false;
+            return;
+        }
+
+        if error.code == ErrorCode::Unauthorized {
+            log::error!(
+                "Check the ws_server_token grants. {} not retried",
+                error.command
+            );
+            return;
+        }
+
+        match error.command.as_str() {
+            "ExecutePosition" => match error.code {
+                // The broker may have filled the trade before timing out
+                ErrorCode::Timeout => self.recover_execution().await,
+                _ => self.rollback_pending_trade(),
+            },
+            message::GET_LOT_LIMITS => {
+                log::warn!("Lot limits not received. Using the configured ones")
+            }
+            // Not retried, so there is nothing to back off
+            "UpdateBotData" | message::MODIFY_ORDER => self.retry_command(&error.command).await,
+            command => self.schedule_retry(command, backoff),
+        }
+    }
+
+    // Retried from the run loop, so messages are still read while backing off
+    fn schedule_retry(&mut self, command: &str, backoff: Duration) {
+        self.scheduled_retries
+            .retain(|(_, scheduled)| scheduled != command);
+        self.scheduled_retries
+            .push((Instant::now() + backoff, command.to_owned()));
+    }
+
+    fn next_retry(&self) -> Option<Instant> {
+        self.scheduled_retries.iter().map(|(at, _)| *at).min()
+    }
+
+    async fn run_due_retries(&mut self) {
+        let now = Instant::now();
+        let (due, scheduled): (Vec<_>, Vec<_>) = self
+            .scheduled_retries
+            .drain(..)
+            .partition(|(at, _)| *at <= now);
+        self.scheduled_retries = scheduled;
+
+        for (_, command) in due {
+            self.retry_command(&command).await;
+        }
+    }
+
+    async fn retry_command(&mut self, command: &str) {
+        match command {
+            "InitSession" => self.init_session().await,
+            "GetInstrumentData" => self.get_instrument_data().await,
+            "GetMarketHours" => self.get_market_hours().await,
+            "IsMarketOpen" => self.is_market_open().await,
+            "GetActivePositions" => self.get_active_positions().await,
+            "GetInstrumentTick" => self.get_tick_data().await,
+            "ExecutePosition" => self.recover_execution().await,
+            message::MODIFY_ORDER => {
+                log::warn!("Stop modification failed. The stop wasn't moved")
+            }
+            // Bot data is sent again on the next update
+            _ => (),
+        }
+    }
+
+    // Rolls the pending trade back and checks whether the broker filled it
+    async fn recover_execution(&mut self) {
+        self.rollback_pending_trade();
+
+        if self.env.is_prod() {
+            self.get_active_positions().await;
+        }
+    }
+
+    async fn check_pending_requests(&mut self) {
+        let mut commands: Vec<String> = vec![];
+
+        for request in self.pending_requests.expired() {
+            log::error!(
+                "{} request {} not answered after {:?}",
+                request.command,
+                request.id,
+                request.sent_at.elapsed()
+            );
+
+            if !commands.contains(&request.command) {
+                commands.push(request.command);
+            }
+        }
+
+        for command in commands {
+            self.retry_command(&command).await;
+        }
+    }
+
+    // False for responses to requests no longer pending, already handled as
+    // timed out or sent on a previous connection
+    fn resolve_request(&mut self, txt: &str) -> bool {
+        match message::parse_request_id(txt) {
+            Some(request_id) => match self.pending_requests.resolve(request_id) {
+                Some(_) => true,
+                None => {
+                    log::warn!("Late response to request {} dropped", request_id);
+                    false
+                }
+            },
+            None => true,
+        }
+    }
+
+    async fn send_command(&mut self, mut command: Value) {
+        let name = command["command"].as_str().unwrap_or_default().to_owned();
+        let timeout = self.config.request_timeouts.timeout(&name);
+
+        if let Some(request_id) = self.pending_requests.register(&name, timeout) {
+            command["request_id"] = json!(request_id);
+        }
+
+        if let Err(err) = self.websocket.send(&command.to_string()).await {
+            log::error!("{} not sent. {}", name, err);
+        }
+    }
+
+    // Undoes the trade sent with the failed ExecutePosition
+    fn rollback_pending_trade(&mut self) {
+        let event = match self.position.state() {
+            PositionState::PendingEntry => PositionEvent::EntryRejected,
+            PositionState::PendingExit => PositionEvent::ExitRejected,
+            state => {
+                log::warn!("No pending trade to roll back in {:?} state", state);
+                return;
+            }
+        };
+
+        if let Err(err) = self.position.apply(event) {
+            log::error!("Pending trade not rolled back. {}", err);
+            return;
+        }
+
+        match event {
+            PositionEvent::EntryRejected => {
+                if let Some(trade_in) = self.trades_in.last().cloned() {
+                    log::warn!("Rolling back {:?} {}", trade_in.trade_type, trade_in.id);
+                    trade::delete_last(&mut self.trades_in);
+                    order::update_state_pending_orders(&trade_in, &mut self.orders);
+                }
+            }
+            _ => {
+                if let Some(trade_out) = self.trades_out.last() {
+                    log::warn!("Rolling back {:?} {}", trade_out.trade_type, trade_out.id);
+                    trade::delete_last(&mut self.trades_out);
+                }
+            }
+        }
+    }
+
     pub async fn run(&mut self) {
+        self.websocket.connect().await;
         self.init_session().await;
-        let mut open_positions = false;
         let bot_str = [&self.symbol, "_", &self.time_frame.to_string()].concat();
+        let (terminated, terminate) = watch::channel(false);
+        self.terminate = terminate.clone();
+
+        tokio::spawn(async move {
+            terminate_signal().await;
+            terminated.send(true).ok();
+        });
+
+        let mut terminate = terminate;
 
         loop {
-            match self.websocket.read().await {
+            if self.shutdown_finished() {
+                break;
+            }
+
+            let shutting_down = self.shutdown_deadline.is_some();
+            let deadline = self.shutdown_deadline.unwrap_or_else(Instant::now);
+            let next_retry = self.next_retry();
+            let next_request_deadline =
+                self.pending_requests.next_deadline().map(Instant::from_std);
+
+            let read = tokio::select! {
+                read = self.websocket.read() => read,
+                Ok(_) = terminate.wait_for(|terminated| *terminated), if !shutting_down => {
+                    self.start_shutdown();
+                    continue;
+                }
+                _ = sleep_until(deadline), if shutting_down => continue,
+                _ = sleep_until(next_retry.unwrap_or_else(Instant::now)), if next_retry.is_some() => {
+                    self.run_due_retries().await;
+                    continue;
+                }
+                _ = sleep_until(next_request_deadline.unwrap_or_else(Instant::now)), if next_request_deadline.is_some() => {
+                    self.check_pending_requests().await;
+                    continue;
+                }
+            };
+
+            match read {
                 Ok(msg) => {
                     match msg {
+                        // Reply to commands without response data
+                        Message::Text(txt) if txt.is_empty() => (),
                         Message::Text(txt) => {
-                            let msg_type = message::get_type(&txt);
+                            if !self.resolve_request(&txt) {
+                                continue;
+                            }
+
+                            let response = match message::decode(&txt) {
+                                Ok(response) => response,
+                                Err(err) => {
+                                    self.decode_errors.record(&err);
+                                    log::error!(
+                                        "{} message ignored ({} so far). {} {}",
+                                        bot_str,
+                                        self.decode_errors.total(),
+                                        err,
+                                        txt
+                                    );
+                                    continue;
+                                }
+                            };
+
+                            if !matches!(response, Response::Error(_)) {
+                                self.consecutive_errors = 0;
+                            }
 
-                            match msg_type {
-                                MessageType::Connected(_res) => {
+                            match response {
+                                Response::Error(error) => {
+                                    self.handle_error(error).await;
+                                }
+                                Response::Connected => {
                                     log::info!("{} connected to server", bot_str);
+
+                                    if self.reconnect_attempts > 0 {
+                                        log::info!(
+                                            "Reconnected after {} attempts",
+                                            self.reconnect_attempts
+                                        );
+                                        self.reconnect_attempts = 0;
+                                    }
                                 }
-                                MessageType::Reconnect(_res) => {
-                                    log::info!("{} reconnect msg received!", bot_str);
-                                    self.reconnect().await;
+                                Response::Reconnect { clean_data } => {
+                                    log::info!(
+                                        "{} reconnect msg received! clean_data: {}",
+                                        bot_str,
+                                        clean_data
+                                    );
+                                    self.reconnect(ReconnectCause::Requested { clean_data })
+                                        .await;
                                 }
-                                MessageType::InitSession(res) => {
-                                    let env = environment::from_str(&env::var("ENV").unwrap());
-
+                                Response::InitSession(bot_data) => {
                                     log::info!("Getting {} previous session", bot_str);
 
+                                    let mut open_positions = false;
                                     let now = Local::now();
-                                    let bot_data = res.payload.unwrap();
+                                    self.last_reconciliation =
+                                        message::parse_payload_field(&txt, "last_reconciliation");
                                     let trades_in = bot_data.trades_in().len();
                                     let trades_out = bot_data.trades_out().len();
                                     let orders = bot_data.orders();
@@ -617,6 +1329,29 @@ impl Bot {
                                         }
                                     };
 
+                                    let mut strategy_params: Vec<ParamSet> =
+                                        message::parse_payload_field(&txt, "strategy_params")
+                                            .unwrap_or_default();
+                                    params::track_params(
+                                        &mut strategy_params,
+                                        self.strategy.params(),
+                                    );
+                                    self.strategy_params = strategy_params;
+                                    self.trade_risks =
+                                        message::parse_payload_field(&txt, "trade_risks")
+                                            .unwrap_or_default();
+                                    self.restore_risk_guard(&txt);
+
+                                    if let Some(position) =
+                                        message::parse_payload_field(&txt, "position")
+                                    {
+                                        self.position = position;
+                                    }
+
+                                    self.position
+                                        .apply(PositionEvent::Restored(open_positions))
+                                        .unwrap();
+
                                     if num_active_trades != num_active_stop_losses {
                                         log::error!(
                                             "Active trades {} do not match active stop losses {} !",
@@ -625,77 +1360,19 @@ impl Bot {
                                         );
                                     } else {
                                         self.restore_values(bot_data).await;
+                                        self.get_lot_limits().await;
                                         self.get_market_hours().await;
                                     }
-
-                                    //TODO RECONCILIATION
-                                    if env.is_prod() {
-                                        self.get_active_positions().await;
-                                    }
                                 }
-                                MessageType::ActivePositions(res) => {
-                                    let position_result = res.payload.unwrap();
-
-                                    match position_result {
-                                        PositionResult::MarketIn(
-                                            TradeResult::TradeIn(trade_in),
-                                            orders,
-                                        ) => {
-                                            if trade::trade_exists(&self.trades_in, trade_in.id) {
-                                                log::info!(
-                                                    "Active position {:?} {} found. Updating position...",
-                                                    trade_in.trade_type,
-                                                    trade_in.id
-                                                );
+                                Response::ActivePositions(position_result) => {
+                                    self.reconcile_positions(position_result).await;
 
-                                                trade::update_trade_by_id(
-                                                    &mut self.trades_in,
-                                                    trade_in,
-                                                );
-
-                                                match orders {
-                                                    Some(orders) => {
-                                                        order::update_orders(
-                                                            &mut self.orders,
-                                                            &orders,
-                                                        );
-                                                    }
-                                                    None => (),
-                                                }
-                                            } else {
-                                                log::info!(
-                                                    "Active position {:?} {} not found. Adding position...",
-                                                    trade_in,
-                                                    trade_in.id
-                                                );
-
-                                                match orders {
-                                                    Some(orders) => {
-                                                        self.add_trade_in(&trade_in);
-
-                                                        self.orders = order::add_pending(
-                                                            self.orders.clone(),
-                                                            orders,
-                                                        )
-                                                    }
-                                                    None => (),
-                                                }
-                                            }
-
-                                            open_positions = true;
-                                        }
-                                        _ => {
-                                            log::info!("No active positions found");
-                                            if open_positions {
-                                                log::error!("Divergence between broker open positions and db trades!");
-                                                open_positions = true;
-                                                sleep(Duration::from_secs(86400)).await;
-                                            }
-                                        }
+                                    // Also received on execution checks, once subscribed
+                                    if !self.subscribed {
+                                        self.subscribing_to_stream().await;
                                     }
                                 }
-                                MessageType::MarketHours(res) => {
-                                    let market_hours = res.payload.unwrap();
+                                Response::MarketHours(market_hours) => {
                                     let is_trading_hours = market_hours.is_trading_time();
 
                                     log::info!("Trading hours {}", &is_trading_hours);
@@ -715,15 +1392,24 @@ impl Bot {
                                                 will_open_at, wait_until, wait_until / 3600
                                             );
 
-                                            sleep(Duration::from_secs(wait_until)).await;
-                                            log::info!("{} Reconnecting", bot_str);
-                                            self.reconnect().await;
+                                            if self
+                                                .sleep_unless_terminated(Duration::from_secs(
+                                                    wait_until,
+                                                ))
+                                                .await
+                                            {
+                                                log::info!("{} Reconnecting", bot_str);
+                                                self.reconnect(ReconnectCause::Requested {
+                                                    clean_data: true,
+                                                })
+                                                .await;
+                                            }
                                         }
                                     };
 
                                     self.market_hours = market_hours;
                                 }
-                                MessageType::IsMarketOpen(_) => {
+                                Response::IsMarketOpen(_) => {
                                     let is_market_open = true;
                                     match is_market_open {
                                         true => {
@@ -732,10 +1418,7 @@ impl Bot {
                                             self.get_tick_data().await;
                                         }
                                         false => {
-                                            let secs_to_retry = env::var("MARKET_CLOSED_RETRY")
-                                                .unwrap()
-                                                .parse::<u64>()
-                                                .unwrap();
+                                            let secs_to_retry = self.config.market_closed_retry;
 
                                             log::warn!(
                                                 "{} Market closed!. Retrying after {} secs...",
@@ -743,18 +1426,22 @@ impl Bot {
                                                 secs_to_retry
                                             );
 
-                                            sleep(Duration::from_secs(secs_to_retry)).await;
-                                            self.get_market_hours().await;
+                                            if self
+                                                .sleep_unless_terminated(Duration::from_secs(
+                                                    secs_to_retry,
+                                                ))
+                                                .await
+                                            {
+                                                self.get_market_hours().await;
+                                            }
                                         }
                                     }
                                 }
-                                MessageType::InstrumentTick(res) => {
-                                    let tick = res.payload.unwrap();
+                                Response::InstrumentTick(tick) => {
+                                    self.pip_size = tick.pip_size();
                                     self.tick = tick;
                                 }
-                                MessageType::InstrumentData(res) => {
-                                    let payload = res.payload.unwrap();
-
+                                Response::InstrumentData(payload) => {
                                     let time_frame = payload.time_frame;
                                     let data = payload.data;
                                     let since_date = match &data.first() {
@@ -769,16 +1456,14 @@ impl Bot {
                                             &since_date,
                                         );
 
-                                        self.instrument.set_data(data).unwrap();
+                                        self.instrument = self.load_data(&self.instrument, data);
 
                                         if !is_mtf_strategy(&self.strategy_type) {
-                                            self.subscribing_to_stream().await;
+                                            self.instrument_data_loaded().await;
                                         }
                                     } else if is_mtf_strategy(&self.strategy_type) {
                                         match self.htf_instrument {
-                                            HTFInstrument::HTFInstrument(
-                                                ref mut htf_instrument,
-                                            ) => {
+                                            HTFInstrument::HTFInstrument(ref htf_instrument) => {
                                                 let since_date = match &htf_instrument.data.first()
                                                 {
                                                     Some(x) => x.date().to_string(),
@@ -791,42 +1476,47 @@ impl Bot {
                                                     &since_date
                                                 );
 
-                                                htf_instrument.set_data(data).unwrap();
+                                                let loaded = self.load_data(htf_instrument, data);
+                                                self.htf_instrument =
+                                                    HTFInstrument::HTFInstrument(loaded);
 
-                                                self.subscribing_to_stream().await;
+                                                self.instrument_data_loaded().await;
                                             }
                                             HTFInstrument::None => {}
                                         };
                                     }
                                     self.send_bot_status(&bot_str).await;
                                 }
-                                MessageType::StreamResponse(res) => {
-                                    let payload = res.payload.unwrap();
-                                    let data = payload.data;
+                                Response::Stream(data) => {
                                     let msg_date = data.0;
 
-                                    if self.last_stream_received != msg_date {
+                                    if self.data_sync != DataSync::Synced {
+                                        log::warn!("Stream data ignored while loading data");
+                                    } else if self.last_stream_received != msg_date {
                                         self.last_stream_received = msg_date;
+
+                                        if self.backfill_gap(msg_date).await {
+                                            continue;
+                                        }
+
                                         let index =
                                             self.instrument.data.len().checked_sub(1).unwrap();
-                                        let new_candle = self.instrument.next(data).unwrap();
+                                        let (new_candle, higher_candle) = candles::next_candle(
+                                            data,
+                                            &mut self.instrument,
+                                            &mut self.htf_instrument,
+                                            &self.strategy_type,
+                                        );
                                         let candle_date = data.0;
-                                        let mut higher_candle: Candle = new_candle.clone();
                                         let current_session = &self
                                             .market_hours
                                             .current_session(candle_date)
                                             .unwrap();
 
-                                        if is_mtf_strategy(&self.strategy_type) {
-                                            if let HTFInstrument::HTFInstrument(
-                                                ref mut htf_instrument,
-                                            ) = self.htf_instrument
-                                            {
-                                                higher_candle = htf_instrument.next(data).unwrap();
-                                            }
-                                        }
+                                        let allow_entries = self.check_risk_limits().await
+                                            && self.shutdown_deadline.is_none();
 
-                                        let (new_position, new_orders) = self
+                                        let (new_position, new_orders, modified_stop) = self
                                             .strategy
                                             .next(
                                                 &self.instrument,
@@ -836,13 +1526,14 @@ impl Bot {
                                                 &self.orders,
                                                 &self.tick,
                                                 false,
+                                                allow_entries,
                                             )
                                             .await;
 
                                         self.process_new_positions_and_orders(
                                             new_position,
                                             new_orders,
-                                            &mut open_positions,
+                                            modified_stop,
                                         )
                                         .await;
 
@@ -856,59 +1547,38 @@ impl Bot {
 
                                         if new_candle.is_closed() {
                                             log::info!(
-                                            "{} {:?} Session - Candle {:?} closed - Open pos: {} ",
+                                            "{} {:?} Session - Candle {:?} closed - Position: {:?} ",
                                             &self.env.value(),
                                             &current_session,
                                             close_date,
-                                            open_positions
+                                            self.position.state()
                                         );
 
-                                            self.instrument
-                                                .init_candle(data, &Some(self.time_frame.clone()));
-
-                                            self.instrument
-                                                .indicators
-                                                .init_indicators(&self.time_frame, true)
-                                                .unwrap();
+                                            candles::close_candle(
+                                                data,
+                                                &mut self.instrument,
+                                                &self.time_frame,
+                                            );
                                         }
 
                                         if higher_candle.is_closed()
                                             && is_mtf_strategy(&self.strategy_type)
                                         {
                                             log::info!(
-                                            "{:?} Session - HTF Candle {:?} closed - Open pos: {} ",
+                                            "{:?} Session - HTF Candle {:?} closed - Position: {:?} ",
                                             &current_session,
                                             higher_candle.date(),
-                                            open_positions
+                                            self.position.state()
                                         );
 
-                                            if let HTFInstrument::HTFInstrument(
-                                                ref mut htf_instrument,
-                                            ) = self.htf_instrument
-                                            {
-                                                let htf_data = (
-                                                    higher_candle.date(),
-                                                    higher_candle.open(),
-                                                    higher_candle.high(),
-                                                    higher_candle.low(),
-                                                    higher_candle.close(),
-                                                    higher_candle.volume(),
-                                                );
-
-                                                htf_instrument
-                                                    .init_candle(htf_data, &self.higher_time_frame);
-
-                                                htf_instrument
-                                                    .indicators
-                                                    .init_indicators(
-                                                        &self.higher_time_frame.as_ref().unwrap(),
-                                                        true,
-                                                    )
-                                                    .unwrap();
-                                            }
+                                            candles::close_htf_candle(
+                                                &higher_candle,
+                                                &mut self.htf_instrument,
+                                                &self.higher_time_frame,
+                                            );
                                         }
 
-                                        if !open_positions {
+                                        if self.position.is_flat() {
                                             self.orders = order::cancel_pending_expired_orders(
                                                 index,
                                                 &self.instrument,
@@ -916,19 +1586,14 @@ impl Bot {
                                             );
                                         }
 
-                                        if env::var("SEND_UPDATE_ON_STREAM")
-                                            .unwrap()
-                                            .parse::<bool>()
-                                            .unwrap()
-                                        {
+                                        if self.config.send_update_on_stream {
                                             self.send_bot_status(&bot_str).await;
                                         }
                                     } else {
                                         log::warn!("Duplicated stream data!");
                                     }
                                 }
-                                MessageType::StreamTickResponse(res) => {
-                                    let tick = res.payload.unwrap();
+                                Response::StreamTick(tick) => {
                                     //let now = Local::now();
                                     self.tick = InstrumentTick::new()
                                         .symbol(self.symbol.clone())
@@ -941,8 +1606,13 @@ impl Bot {
                                         .time(Local::now().timestamp())
                                         .build()
                                         .unwrap();
+                                    self.pip_size = tick.pip_size();
+
+                                    let allow_entries = self.check_risk_limits().await
+                                        && self.shutdown_deadline.is_none()
+                                        && self.data_sync == DataSync::Synced;
 
-                                    let (new_position, new_orders) = self
+                                    let (new_position, new_orders, modified_stop) = self
                                         .strategy
                                         .next(
                                             &self.instrument,
@@ -952,21 +1622,18 @@ impl Bot {
                                             &self.orders,
                                             &self.tick,
                                             true,
+                                            allow_entries,
                                         )
                                         .await;
 
                                     self.process_new_positions_and_orders(
                                         new_position,
                                         new_orders,
-                                        &mut open_positions,
+                                        modified_stop,
                                     )
                                     .await;
 
-                                    if env::var("UPDATE_INDICATORS_TICK")
-                                        .unwrap()
-                                        .parse::<bool>()
-                                        .unwrap()
-                                    {
+                                    if self.config.update_indicators_tick {
                                         // if now
                                         //     >= self.last_tick_received
                                         //         + date::Duration::milliseconds(1000)
@@ -979,10 +1646,24 @@ impl Bot {
                                     }
                                 }
 
-                                MessageType::TradeInFulfilled(res) => {
-                                    let payload = res.payload.unwrap();
+                                Response::TradeInFulfilled(payload) => {
                                     let accepted = &payload.accepted;
 
+                                    let event = match accepted {
+                                        true => PositionEvent::EntryFilled,
+                                        false => PositionEvent::EntryRejected,
+                                    };
+
+                                    if let Err(err) = self.position.apply(event) {
+                                        log::error!(
+                                            "{:?} {} response ignored. {}",
+                                            &payload.data.trade_type,
+                                            &payload.data.id,
+                                            err
+                                        );
+                                        continue;
+                                    }
+
                                     match accepted {
                                         true => {
                                             log::info!(
@@ -999,6 +1680,20 @@ impl Bot {
                                                 &trade_in,
                                             );
 
+                                            let trade_risk =
+                                                self.config.position_sizing.trade_risk(
+                                                    &trade_in,
+                                                    &self.orders,
+                                                    self.tick.pip_size(),
+                                                );
+                                            log::info!(
+                                                "Trade {} size {} risk {}",
+                                                trade_risk.trade_id,
+                                                trade_in.size,
+                                                trade_risk.risk
+                                            );
+                                            self.trade_risks.push(trade_risk);
+
                                             trade::update_last(&mut self.trades_in, trade_in);
 
                                             self.strategy_stats = self.strategy.update_stats(
@@ -1007,8 +1702,6 @@ impl Bot {
                                                 &self.trades_out,
                                             );
 
-                                            open_positions = true;
-
                                             self.send_bot_status(&bot_str).await;
                                         }
                                         false => {
@@ -1024,14 +1717,44 @@ impl Bot {
                                                 &payload.data,
                                                 &mut self.orders,
                                             );
-                                            open_positions = false;
                                         }
                                     }
                                 }
-                                MessageType::TradeOutFulfilled(res) => {
-                                    let payload = res.payload.unwrap();
+                                Response::LotLimits(limits) => self.apply_lot_limits(limits),
+                                Response::OrderModified(payload) => match payload.accepted {
+                                    true => {
+                                        log::info!(
+                                            "{:?} {} moved to {}",
+                                            payload.data.order_type,
+                                            payload.data.id,
+                                            payload.data.target_price
+                                        );
+                                        order::update_orders(&mut self.orders, &vec![payload.data]);
+                                        self.send_bot_status(&bot_str).await;
+                                    }
+                                    false => log::warn!(
+                                        "{} stop modification not accepted by the broker",
+                                        payload.symbol
+                                    ),
+                                },
+                                Response::TradeOutFulfilled(payload) => {
                                     let accepted = &payload.accepted;
 
+                                    let event = match accepted {
+                                        true => PositionEvent::ExitFilled,
+                                        false => PositionEvent::ExitRejected,
+                                    };
+
+                                    if let Err(err) = self.position.apply(event) {
+                                        log::error!(
+                                            "{:?} {} response ignored. {}",
+                                            &payload.data.trade_type,
+                                            &payload.data.id,
+                                            err
+                                        );
+                                        continue;
+                                    }
+
                                     match accepted {
                                         true => {
                                             log::info!(
@@ -1044,12 +1767,26 @@ impl Bot {
                                             );
 
                                             let trade_out: TradeOut = payload.data;
-                                            let updated_trade_out =
-                                                self.strategy.update_trade_stats(
-                                                    self.trades_in.last().unwrap(),
+                                            let updated_trade_out = match self
+                                                .trades_in
+                                                .iter()
+                                                .rev()
+                                                .find(|trade_in| trade_in.id == trade_out.id)
+                                            {
+                                                Some(trade_in) => self.strategy.update_trade_stats(
+                                                    trade_in,
                                                     &trade_out,
                                                     &self.instrument.data,
-                                                );
+                                                ),
+                                                None => {
+                                                    log::error!(
+                                                        "No entry for {:?} {}. Stats not updated",
+                                                        trade_out.trade_type,
+                                                        trade_out.id
+                                                    );
+                                                    trade_out
+                                                }
+                                            };
 
                                             log::info!(
                                                 "{:?} stats profit {} profit_per {} ",
@@ -1074,7 +1811,6 @@ impl Bot {
                                                 &self.trades_out,
                                             );
 
-                                            open_positions = false;
                                             self.send_bot_status(&bot_str).await;
                                         }
                                         false => {
@@ -1087,27 +1823,44 @@ impl Bot {
                                             );
 
                                             trade::delete_last(&mut self.trades_out);
-
-                                            open_positions = true;
                                         }
                                     };
+
+                                    self.close_next_orphan().await;
                                 }
-                                _ => (),
                             };
                         }
                         Message::Ping(_txt) => {
                             self.websocket.pong(b"").await;
                         }
-                        _ => panic!("Unexpected response type!"),
+                        msg => log::warn!("{} unexpected message ignored {:?}", bot_str, msg),
                     };
                 }
+                Err(err) if self.shutdown_deadline.is_some() => {
+                    log::warn!("Disconnected from server while shutting down: {:?}", err);
+                    break;
+                }
                 Err(err) => {
                     log::warn!("[ERROR] Disconnected from server: {:?}", err);
-                    self.reconnect().await;
+                    self.reconnect(ReconnectCause::Disconnected).await;
                     //Message::Ping(b"".to_vec())
                 }
             };
         }
+
+        self.send_bot_status(&bot_str).await;
+        self.websocket.close().await;
+        log::info!("{} shutdown completed", bot_str);
+    }
+}
+
+/// Resolves on SIGTERM or SIGINT
+async fn terminate_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Can't listen to SIGTERM");
+
+    tokio::select! {
+        _ = terminate.recv() => log::warn!("SIGTERM received"),
+        _ = tokio::signal::ctrl_c() => log::warn!("SIGINT received"),
     }
 }
 
@@ -1120,6 +1873,7 @@ pub struct BotBuilder {
     strategy_name: Option<String>,
     strategy_type: Option<StrategyType>,
     websocket: Option<WebSocket>,
+    config: Option<BotConfig>,
 }
 
 impl BotBuilder {
@@ -1133,6 +1887,7 @@ impl BotBuilder {
             strategy_name: None,
             strategy_type: None,
             websocket: None,
+            config: None,
         }
     }
     pub fn symbol(mut self, val: String) -> Self {
@@ -1171,9 +1926,13 @@ impl BotBuilder {
         self
     }
 
-    pub fn server_url(mut self, val: String) -> Self {
-        let ws_client = WebSocket::connect(&val);
-        self.websocket = Some(ws_client);
+    pub fn config(mut self, val: BotConfig) -> Self {
+        self.config = Some(val);
+        self
+    }
+
+    pub fn websocket(mut self, val: WebSocket) -> Self {
+        self.websocket = Some(val);
         self
     }
 
@@ -1187,6 +1946,7 @@ impl BotBuilder {
             Some(strategy_name),
             Some(strategy_type),
             Some(websocket),
+            Some(config),
         ) = (
             self.env,
             self.symbol,
@@ -1196,7 +1956,25 @@ impl BotBuilder {
             self.strategy_name,
             self.strategy_type.clone(),
             self.websocket,
+            self.config,
         ) {
+            let strategy = set_strategy(
+                &strategy_name,
+                &time_frame.to_string(),
+                Some(&self.higher_time_frame.as_ref().unwrap().to_string()),
+                strategy_type.clone(),
+                &config,
+            )
+            .map_err(|err| {
+                log::error!("{}", err);
+                RsAlgoError {
+                    err: RsAlgoErrorKind::WrongInstrumentConf,
+                }
+            })?;
+
+            let mut strategy_params = vec![];
+            params::track_params(&mut strategy_params, strategy.params());
+
             let instrument = Instrument::new()
                 .symbol(&symbol)
                 .market(market.to_owned())
@@ -1221,13 +1999,6 @@ impl BotBuilder {
                 None => HTFInstrument::None,
             };
 
-            let strategy = set_strategy(
-                &strategy_name,
-                &time_frame.to_string(),
-                Some(&self.higher_time_frame.as_ref().unwrap().to_string()),
-                strategy_type.clone(),
-            );
-
             Ok(Bot {
                 uuid: uuid::Uuid::new(),
                 env,
@@ -1251,6 +2022,25 @@ impl BotBuilder {
                 strategy_name: strategy_name.clone(),
                 strategy_type,
                 strategy_stats: StrategyStats::new(),
+                position: PositionTracker::new(),
+                strategy_params,
+                trade_risks: vec![],
+                risk_guard: RiskGuard::default(),
+                pip_value: config.position_sizing.pip_value,
+                pip_size: 0.,
+                last_reconciliation: None,
+                orphans_to_close: VecDeque::new(),
+                modifies_orders: true,
+                consecutive_errors: 0,
+                decode_errors: DecodeErrors::default(),
+                pending_requests: PendingRequests::default(),
+                subscribed: false,
+                reconnect_attempts: 0,
+                data_sync: DataSync::Full,
+                shutdown_deadline: None,
+                terminate: watch::channel(false).1,
+                scheduled_retries: vec![],
+                config,
             })
         } else {
             Err(RsAlgoError {
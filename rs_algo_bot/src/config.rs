@@ -0,0 +1,515 @@
+use crate::helpers::vars::*;
+use crate::pending::RequestTimeouts;
+use crate::reconciliation::{self, ReconciliationPolicy};
+use crate::reconnect::ReconnectPolicy;
+use crate::risk_guard::RiskLimits;
+use crate::strategies::registry::StrategyRegistry;
+use crate::strategies::sizing::{PositionSizing, SizingMode};
+use crate::strategies::stops::StopManagement;
+
+use rs_algo_shared::models::environment::{self, Environment};
+use rs_algo_shared::models::market::Market;
+use rs_algo_shared::models::strategy::{self, StrategyType};
+use rs_algo_shared::models::time_frame::{TimeFrame, TimeFrameType};
+
+use chrono_tz::Tz;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::{env, fs};
+use thiserror::Error;
+
+const TIME_FRAMES: [&str; 9] = ["M1", "M5", "M15", "M30", "H1", "H4", "D", "W", "M"];
+const MARKETS: [&str; 3] = ["Forex", "Crypto", "Stock"];
+const RECONCILIATION_POLICIES: [&str; 2] = ["Adopt", "Close"];
+const STRATEGY_TYPES: [&str; 6] = [
+    "OnlyLong",
+    "OnlyShort",
+    "LongShort",
+    "OnlyLongMTF",
+    "OnlyShortMTF",
+    "LongShortMTF",
+];
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Can't read config file {0}: {1}")]
+    File(String, String),
+    #[error("{key} is missing")]
+    Missing { key: String },
+    #[error("{key} has an invalid value {value:?}: {reason}")]
+    Invalid {
+        key: String,
+        value: String,
+        reason: String,
+    },
+}
+
+/// Bot settings, read once at startup from the optional TOML file set in
+/// `BOT_CONFIG`. Any key can be overridden with its upper case env var
+/// (`max_spread_pips` -> `MAX_SPREAD_PIPS`).
+#[derive(Debug, Clone)]
+pub struct BotConfig {
+    pub env: String,
+    pub symbol: String,
+    pub market: String,
+    pub strategy_name: String,
+    pub strategy_type: String,
+    pub time_frame: String,
+    pub higher_time_frame: String,
+    pub ws_server_url: String,
+    pub ws_server_port: u16,
+    pub ws_server_str: String,
+    /// Sent as an "Authorization: Bearer" header when set
+    pub ws_server_token: Option<String>,
+    /// PEM CA bundle trusted for wss:// connections, e.g. a self-signed one
+    pub ws_server_ca: String,
+    pub num_bars: i64,
+    pub max_historical_positions: usize,
+    pub max_buy_orders: usize,
+    pub max_pending_orders: usize,
+    pub non_profitable_outs: bool,
+    pub disconnected_retry: u64,
+    pub error_retry: u64,
+    pub max_error_retry: u64,
+    pub market_closed_retry: u64,
+    /// Secs to wait on SIGTERM for in flight executions before exiting
+    pub shutdown_timeout: u64,
+    pub send_update_on_stream: bool,
+    pub update_indicators_tick: bool,
+    pub positions_on_tick_stream: bool,
+    pub max_spread_pips: f64,
+    pub order_size: f64,
+    pub orders_overwrite: bool,
+    pub trading_direction: bool,
+    pub equity: f64,
+    pub commission: f64,
+    pub risk_reward_ratio: f64,
+    pub pips_profit_target: f64,
+    pub pips_margin: f64,
+    pub atr_stoploss: f64,
+    pub atr_profit_target: f64,
+    pub backtest_pip_size: f64,
+    pub backtest_spread_pips: f64,
+    pub reconciliation_policy: String,
+    /// Timezone of the broker market hours, e.g. CET for XTB
+    pub market_timezone: String,
+    pub strategy_params: Value,
+    pub stop_management: StopManagement,
+    pub position_sizing: PositionSizing,
+    pub risk_limits: RiskLimits,
+    pub request_timeouts: RequestTimeouts,
+    pub reconnect: ReconnectPolicy,
+}
+
+/// Backtests never connect to the server, so its settings are only required
+/// in bot mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunMode {
+    Bot,
+    Backtest,
+}
+
+/// Indicator settings and the strategy param they set
+const INDICATOR_PARAMS: [(&str, &str); 4] = [
+    ("bb_period", "bb_period"),
+    ("bb_multiplier", "bb_deviation"),
+    ("ema_a", "ema_a"),
+    ("ema_b", "ema_b"),
+];
+
+struct RawConfig {
+    values: HashMap<String, String>,
+    errors: Vec<ConfigError>,
+}
+
+impl RawConfig {
+    fn value(&mut self, key: &str) -> Option<String> {
+        match env::var(key.to_uppercase()) {
+            Ok(value) => Some(value),
+            Err(_) => self.values.get(key).cloned(),
+        }
+    }
+
+    fn get<T>(&mut self, key: &str) -> T
+    where
+        T: FromStr + Default,
+        T::Err: Display,
+    {
+        match self.value(key) {
+            Some(value) => self.parse(key, value),
+            None => {
+                self.errors.push(ConfigError::Missing {
+                    key: key.to_owned(),
+                });
+                T::default()
+            }
+        }
+    }
+
+    fn get_when<T>(&mut self, key: &str, required: bool) -> T
+    where
+        T: FromStr + Default,
+        T::Err: Display,
+    {
+        match required {
+            true => self.get(key),
+            false => self.get_or(key, T::default()),
+        }
+    }
+
+    fn get_or<T>(&mut self, key: &str, default: T) -> T
+    where
+        T: FromStr + Default,
+        T::Err: Display,
+    {
+        match self.value(key) {
+            Some(value) => self.parse(key, value),
+            None => default,
+        }
+    }
+
+    fn get_opt(&mut self, key: &str) -> Option<String> {
+        self.value(key).filter(|value| !value.is_empty())
+    }
+
+    fn parse<T>(&mut self, key: &str, value: String) -> T
+    where
+        T: FromStr + Default,
+        T::Err: Display,
+    {
+        match value.trim().parse::<T>() {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                self.invalid(key, &value, &err.to_string());
+                T::default()
+            }
+        }
+    }
+
+    // Tables are read as JSON so they can also be overridden from env vars
+    fn json_object(&mut self, key: &str) -> Value {
+        match self.value(key) {
+            Some(value) => match serde_json::from_str::<Value>(&value) {
+                Ok(parsed) if parsed.is_object() => parsed,
+                Ok(_) => {
+                    self.invalid(key, &value, "expected a table");
+                    Value::Object(Default::default())
+                }
+                Err(err) => {
+                    self.invalid(key, &value, &err.to_string());
+                    Value::Object(Default::default())
+                }
+            },
+            None => Value::Object(Default::default()),
+        }
+    }
+
+    fn table<T>(&mut self, key: &str) -> T
+    where
+        T: DeserializeOwned + Default,
+    {
+        let value = self.json_object(key);
+        match serde_json::from_value(value.clone()) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                self.invalid(key, &value.to_string(), &err.to_string());
+                T::default()
+            }
+        }
+    }
+
+    // The indicator settings of the environment, like BB_PERIOD, are used for
+    // the strategy params missing in the strategy_params table
+    fn strategy_params(&mut self) -> Value {
+        let mut params = self.json_object("strategy_params");
+
+        for (key, param) in INDICATOR_PARAMS {
+            if params.get(param).is_some() {
+                continue;
+            }
+
+            if let Some(value) = self.value(key) {
+                let value = match param {
+                    "bb_deviation" => json!(self.parse::<f64>(key, value)),
+                    _ => json!(self.parse::<usize>(key, value)),
+                };
+                params[param] = value;
+            }
+        }
+
+        params
+    }
+
+    fn invalid(&mut self, key: &str, value: &str, reason: &str) {
+        self.errors.push(ConfigError::Invalid {
+            key: key.to_owned(),
+            value: value.to_owned(),
+            reason: reason.to_owned(),
+        });
+    }
+
+    fn one_of(&mut self, key: &str, value: &str, allowed: &[&str]) {
+        if !allowed.contains(&value) {
+            self.invalid(key, value, &format!("expected one of {:?}", allowed));
+        }
+    }
+
+    fn positive(&mut self, key: &str, value: f64) {
+        if value <= 0. {
+            self.invalid(key, &value.to_string(), "must be greater than 0");
+        }
+    }
+}
+
+impl BotConfig {
+    pub fn load(mode: RunMode) -> Result<Self, Vec<ConfigError>> {
+        let values = match env::var("BOT_CONFIG") {
+            Ok(path) => read_file(&path).map_err(|err| vec![err])?,
+            Err(_) => HashMap::new(),
+        };
+
+        let mut raw = RawConfig {
+            values,
+            errors: vec![],
+        };
+
+        let connects = mode == RunMode::Bot;
+        let config = BotConfig {
+            env: raw.get("env"),
+            symbol: raw.get("symbol"),
+            market: raw.get("market"),
+            strategy_name: raw.get("strategy_name"),
+            strategy_type: raw.get("strategy_type"),
+            time_frame: raw.get("time_frame"),
+            higher_time_frame: raw.get("higher_time_frame"),
+            ws_server_url: raw.get_when("ws_server_url", connects),
+            ws_server_port: raw.get_when("ws_server_port", connects),
+            ws_server_str: raw.get_when("ws_server_str", connects),
+            ws_server_token: raw.get_opt("ws_server_token"),
+            ws_server_ca: raw.get_or("ws_server_ca", String::new()),
+            num_bars: raw.get("num_bars"),
+            max_historical_positions: raw.get("max_historical_positions"),
+            max_buy_orders: raw.get("max_buy_orders"),
+            max_pending_orders: raw.get("max_pending_orders"),
+            non_profitable_outs: raw.get("non_profitable_outs"),
+            disconnected_retry: raw.get("disconnected_retry"),
+            error_retry: raw.get_or("error_retry", 1),
+            max_error_retry: raw.get_or("max_error_retry", 60),
+            market_closed_retry: raw.get("market_closed_retry"),
+            shutdown_timeout: raw.get_or("shutdown_timeout", 20),
+            send_update_on_stream: raw.get("send_update_on_stream"),
+            update_indicators_tick: raw.get("update_indicators_tick"),
+            positions_on_tick_stream: raw.get("positions_on_tick_stream"),
+            max_spread_pips: raw.get("max_spread_pips"),
+            order_size: raw.get("order_size"),
+            orders_overwrite: raw.get("orders_overwrite"),
+            trading_direction: raw.get("trading_direction"),
+            equity: raw.get("equity"),
+            commission: raw.get("commission"),
+            risk_reward_ratio: raw.get("risk_reward_ratio"),
+            pips_profit_target: raw.get("pips_profit_target"),
+            pips_margin: raw.get("pips_margin"),
+            atr_stoploss: raw.get("atr_stoploss"),
+            atr_profit_target: raw.get("atr_profit_target"),
+            backtest_pip_size: raw.get_or("backtest_pip_size", 0.0001),
+            backtest_spread_pips: raw.get_or("backtest_spread_pips", 0.),
+            reconciliation_policy: raw.get_or("reconciliation_policy", "Adopt".to_owned()),
+            market_timezone: raw.get_or("market_timezone", "CET".to_owned()),
+            strategy_params: raw.strategy_params(),
+            stop_management: raw.table("stop_management"),
+            position_sizing: raw.table("position_sizing"),
+            risk_limits: raw.table("risk_limits"),
+            request_timeouts: raw.table("request_timeouts"),
+            reconnect: raw.table("reconnect"),
+        };
+
+        config.validate(&mut raw, mode);
+
+        match raw.errors.is_empty() {
+            true => Ok(config),
+            false => Err(raw.errors),
+        }
+    }
+
+    fn validate(&self, raw: &mut RawConfig, mode: RunMode) {
+        raw.one_of("market", &self.market, &MARKETS);
+        raw.one_of("strategy_type", &self.strategy_type, &STRATEGY_TYPES);
+        raw.one_of("time_frame", &self.time_frame, &TIME_FRAMES);
+        raw.one_of("higher_time_frame", &self.higher_time_frame, &TIME_FRAMES);
+        raw.one_of(
+            "reconciliation_policy",
+            &self.reconciliation_policy,
+            &RECONCILIATION_POLICIES,
+        );
+        if let Err(err) = self.market_timezone.parse::<Tz>() {
+            raw.invalid("market_timezone", &self.market_timezone, &err.to_string());
+        }
+        if mode == RunMode::Bot {
+            self.validate_connection(raw);
+        }
+        raw.positive("num_bars", self.num_bars as f64);
+        raw.positive("order_size", self.order_size);
+        raw.positive("equity", self.equity);
+        raw.positive("max_spread_pips", self.max_spread_pips);
+        raw.positive("backtest_pip_size", self.backtest_pip_size);
+        raw.positive("error_retry", self.error_retry as f64);
+        raw.positive("shutdown_timeout", self.shutdown_timeout as f64);
+
+        let sizing = &self.position_sizing;
+        raw.positive("position_sizing.pip_value", sizing.pip_value);
+        raw.positive("position_sizing.lot_step", sizing.lot_step);
+        raw.positive("position_sizing.min_size", sizing.min_size);
+        if sizing.max_size < sizing.min_size {
+            raw.invalid(
+                "position_sizing.max_size",
+                &sizing.max_size.to_string(),
+                "must be greater than min_size",
+            );
+        }
+        match sizing.mode {
+            SizingMode::FixedFractional(value) | SizingMode::Volatility(value) if value <= 0. => {
+                raw.invalid(
+                    "position_sizing.mode",
+                    &format!("{:?}", sizing.mode),
+                    "must be greater than 0",
+                )
+            }
+            _ => (),
+        }
+
+        let limits = &self.risk_limits;
+        for (key, value) in [
+            ("risk_limits.max_daily_loss", limits.max_daily_loss),
+            ("risk_limits.max_weekly_loss", limits.max_weekly_loss),
+            ("risk_limits.max_drawdown", limits.max_drawdown),
+        ] {
+            if let Some(value) = value {
+                raw.positive(key, value);
+            }
+        }
+        if limits.max_consecutive_losses == Some(0) {
+            raw.invalid(
+                "risk_limits.max_consecutive_losses",
+                "0",
+                "must be greater than 0",
+            );
+        }
+
+        let timeouts = &self.request_timeouts;
+        raw.positive("request_timeouts.execution", timeouts.execution as f64);
+        raw.positive("request_timeouts.data", timeouts.data as f64);
+        raw.positive("request_timeouts.default", timeouts.default as f64);
+
+        let reconnect = &self.reconnect;
+        raw.positive("reconnect.max_interval", reconnect.max_interval as f64);
+        if reconnect.multiplier < 1. {
+            raw.invalid(
+                "reconnect.multiplier",
+                &reconnect.multiplier.to_string(),
+                "must be at least 1",
+            );
+        }
+        if reconnect.alert_after == Some(0) {
+            raw.invalid("reconnect.alert_after", "0", "must be greater than 0");
+        }
+
+        if STRATEGY_TYPES.contains(&self.strategy_type.as_str()) {
+            if let Err(err) = StrategyRegistry::new().validate_params(
+                &self.strategy_name,
+                &self.strategy_type(),
+                self,
+            ) {
+                raw.invalid("strategy_name", &self.strategy_name, &err.to_string());
+            }
+        }
+
+        if self.max_buy_orders == 0 {
+            raw.invalid("max_buy_orders", "0", "must be greater than 0");
+        }
+    }
+
+    fn validate_connection(&self, raw: &mut RawConfig) {
+        if !self.ws_server_url.starts_with("ws://") && !self.ws_server_url.starts_with("wss://") {
+            raw.invalid(
+                "ws_server_url",
+                &self.ws_server_url,
+                "expected a ws:// or wss:// url",
+            );
+        }
+        if !self.ws_server_ca.is_empty() {
+            if !self.ws_server_url.starts_with("wss://") {
+                raw.invalid(
+                    "ws_server_ca",
+                    &self.ws_server_ca,
+                    "only used with a wss:// ws_server_url",
+                );
+            }
+            if fs::metadata(&self.ws_server_ca).is_err() {
+                raw.invalid("ws_server_ca", &self.ws_server_ca, "file not found");
+            }
+        }
+    }
+
+    pub fn environment(&self) -> Environment {
+        environment::from_str(&self.env)
+    }
+
+    pub fn market(&self) -> Market {
+        get_market(self.market.clone())
+    }
+
+    pub fn time_frame(&self) -> TimeFrameType {
+        TimeFrame::new(&self.time_frame)
+    }
+
+    pub fn higher_time_frame(&self) -> TimeFrameType {
+        TimeFrame::new(&self.higher_time_frame)
+    }
+
+    pub fn strategy_type(&self) -> StrategyType {
+        strategy::from_str(&self.strategy_type)
+    }
+
+    pub fn reconciliation_policy(&self) -> ReconciliationPolicy {
+        reconciliation::policy_from_str(&self.reconciliation_policy)
+    }
+
+    pub fn market_timezone(&self) -> Tz {
+        self.market_timezone.parse().unwrap_or(Tz::CET)
+    }
+
+    pub fn server_url(&self) -> String {
+        [
+            &self.ws_server_url,
+            ":",
+            &self.ws_server_port.to_string(),
+            "/?",
+            &self.ws_server_str,
+        ]
+        .concat()
+    }
+}
+
+fn read_file(path: &str) -> Result<HashMap<String, String>, ConfigError> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| ConfigError::File(path.to_owned(), err.to_string()))?;
+
+    let table = content
+        .parse::<toml::Table>()
+        .map_err(|err| ConfigError::File(path.to_owned(), err.to_string()))?;
+
+    Ok(table
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Table(table) => serde_json::to_string(&table).unwrap(),
+                value => value.to_string(),
+            };
+            (key, value)
+        })
+        .collect())
+}
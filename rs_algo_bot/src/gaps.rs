@@ -0,0 +1,180 @@
+use rs_algo_shared::helpers::date::{DateTime, Duration, Local};
+use rs_algo_shared::models::market::{MarketHour, MarketHours};
+
+use chrono::{Datelike, Timelike};
+use chrono_tz::Tz;
+
+/// Weekly trading hours of the symbol, in the broker timezone. Without hours
+/// every candle is expected, so a closed market only costs a backfill
+/// returning no bars.
+pub struct TradingWeek {
+    hours: Vec<MarketHour>,
+    timezone: Tz,
+}
+
+impl TradingWeek {
+    pub fn new(market_hours: &MarketHours, timezone: Tz) -> Self {
+        Self {
+            hours: market_hours.data.clone(),
+            timezone,
+        }
+    }
+
+    // Days are numbered from Monday and hours are millis from midnight
+    pub fn is_open(&self, date: DateTime<Local>) -> bool {
+        let date = date.with_timezone(&self.timezone);
+        let day = date.weekday().number_from_monday() as i64;
+        let millis = date.num_seconds_from_midnight() as i64 * 1000;
+
+        self.hours.is_empty()
+            || self.hours.iter().any(|hour| {
+                hour.day as i64 == day && hour.from as i64 <= millis && millis < hour.to as i64
+            })
+    }
+}
+
+/// Candles of `period_minutes` missing between the last candle and the one a
+/// new bar belongs to, not counting the ones the market was closed.
+pub fn missing_candles(
+    last_candle: DateTime<Local>,
+    bar_date: DateTime<Local>,
+    period_minutes: i64,
+    week: &TradingWeek,
+) -> usize {
+    let period = Duration::minutes(period_minutes.max(1));
+    let mut date = last_candle + period;
+    let mut missing = 0;
+
+    while date + period <= bar_date {
+        if week.is_open(date) {
+            missing += 1;
+        }
+        date = date + period;
+    }
+
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const HOUR: i64 = 3_600_000;
+
+    fn hour(day: i64, from: i64, to: i64) -> MarketHour {
+        MarketHour {
+            day: day as _,
+            from: (from * HOUR) as _,
+            to: (to * HOUR) as _,
+        }
+    }
+
+    // Forex hours in CET. Open from Sunday 23:00 to Friday 22:00
+    fn forex() -> TradingWeek {
+        let mut hours: Vec<MarketHour> = (1..=4).map(|day| hour(day, 0, 24)).collect();
+        hours.push(hour(5, 0, 22));
+        hours.push(hour(7, 23, 24));
+
+        TradingWeek {
+            hours,
+            timezone: Tz::CET,
+        }
+    }
+
+    // January 2024 starts on Monday
+    fn date(day: u32, hour: u32, min: u32) -> DateTime<Local> {
+        Tz::CET
+            .with_ymd_and_hms(2024, 1, day, hour, min, 0)
+            .unwrap()
+            .with_timezone(&Local)
+    }
+
+    #[test]
+    fn open_during_sessions() {
+        let week = forex();
+
+        assert!(week.is_open(date(3, 12, 0)));
+        assert!(week.is_open(date(5, 21, 59)));
+        assert!(week.is_open(date(7, 23, 0)));
+    }
+
+    #[test]
+    fn closed_on_weekends() {
+        let week = forex();
+
+        assert!(!week.is_open(date(5, 22, 0)));
+        assert!(!week.is_open(date(6, 12, 0)));
+        assert!(!week.is_open(date(7, 22, 59)));
+    }
+
+    #[test]
+    fn hours_are_in_the_broker_timezone() {
+        // Sunday 22:30 UTC is 23:30 CET
+        let sunday = Tz::UTC
+            .with_ymd_and_hms(2024, 1, 7, 22, 30, 0)
+            .unwrap()
+            .with_timezone(&Local);
+        let utc = TradingWeek {
+            timezone: Tz::UTC,
+            ..forex()
+        };
+
+        assert!(forex().is_open(sunday));
+        assert!(!utc.is_open(sunday));
+    }
+
+    #[test]
+    fn always_open_without_hours() {
+        let week = TradingWeek {
+            hours: vec![],
+            timezone: Tz::CET,
+        };
+
+        assert!(week.is_open(date(6, 12, 0)));
+        assert_eq!(
+            missing_candles(date(5, 21, 0), date(8, 2, 0), 60, &week),
+            52
+        );
+    }
+
+    #[test]
+    fn missing_candles_while_open() {
+        assert_eq!(
+            missing_candles(date(3, 10, 0), date(3, 14, 0), 60, &forex()),
+            3
+        );
+        assert_eq!(
+            missing_candles(date(3, 10, 0), date(3, 11, 0), 60, &forex()),
+            0
+        );
+        assert_eq!(
+            missing_candles(date(3, 10, 0), date(3, 11, 0), 15, &forex()),
+            3
+        );
+    }
+
+    #[test]
+    fn weekend_candles_are_not_missing() {
+        let week = forex();
+
+        assert_eq!(
+            missing_candles(date(5, 21, 0), date(7, 23, 0), 60, &week),
+            0
+        );
+        // Sunday 23:00 and Monday 00:00 and 01:00
+        assert_eq!(missing_candles(date(5, 21, 0), date(8, 2, 0), 60, &week), 3);
+    }
+
+    #[test]
+    fn session_breaks_are_not_missing() {
+        // Index with a daily break from 22:00 to 23:00
+        let week = TradingWeek {
+            hours: vec![hour(1, 0, 22), hour(1, 23, 24), hour(2, 0, 22)],
+            timezone: Tz::CET,
+        };
+
+        // 21:00, 23:00 and 00:00 but not 22:00
+        assert_eq!(missing_candles(date(1, 20, 0), date(2, 1, 0), 60, &week), 3);
+    }
+}
@@ -0,0 +1,198 @@
+use rs_algo_shared::broker::{DOHLC, VEC_DOHLC};
+use rs_algo_shared::models::market::Market;
+use rs_algo_shared::models::strategy::*;
+use rs_algo_shared::models::time_frame::*;
+use rs_algo_shared::scanner::candle::Candle;
+use rs_algo_shared::scanner::instrument::{HTFInstrument, Instrument};
+
+use chrono::Duration;
+
+pub fn next_candle(
+    data: DOHLC,
+    instrument: &mut Instrument,
+    htf_instrument: &mut HTFInstrument,
+    strategy_type: &StrategyType,
+) -> (Candle, Candle) {
+    let new_candle = instrument.next(data).unwrap();
+    let mut higher_candle: Candle = new_candle.clone();
+
+    if is_mtf_strategy(strategy_type) {
+        if let HTFInstrument::HTFInstrument(ref mut htf_instrument) = htf_instrument {
+            higher_candle = htf_instrument.next(data).unwrap();
+        }
+    }
+
+    (new_candle, higher_candle)
+}
+
+pub fn close_candle(data: DOHLC, instrument: &mut Instrument, time_frame: &TimeFrameType) {
+    instrument.init_candle(data, &Some(time_frame.clone()));
+
+    instrument
+        .indicators
+        .init_indicators(time_frame, true)
+        .unwrap();
+}
+
+pub fn close_htf_candle(
+    higher_candle: &Candle,
+    htf_instrument: &mut HTFInstrument,
+    higher_time_frame: &Option<TimeFrameType>,
+) {
+    if let HTFInstrument::HTFInstrument(ref mut htf_instrument) = htf_instrument {
+        let htf_data = (
+            higher_candle.date(),
+            higher_candle.open(),
+            higher_candle.high(),
+            higher_candle.low(),
+            higher_candle.close(),
+            higher_candle.volume(),
+        );
+
+        htf_instrument.init_candle(htf_data, higher_time_frame);
+
+        htf_instrument
+            .indicators
+            .init_indicators(higher_time_frame.as_ref().unwrap(), true)
+            .unwrap();
+    }
+}
+
+pub fn new_instrument(symbol: &str, market: &Market, time_frame: &TimeFrameType) -> Instrument {
+    Instrument::new()
+        .symbol(symbol)
+        .market(market.to_owned())
+        .time_frame(time_frame.to_owned())
+        .build()
+        .unwrap()
+}
+
+fn to_dohlc(candle: &Candle) -> DOHLC {
+    (
+        candle.date(),
+        candle.open(),
+        candle.high(),
+        candle.low(),
+        candle.close(),
+        candle.volume(),
+    )
+}
+
+/// Local bars older than the first fetched one followed by the fetched
+/// bars, so repeated dates and the bar in progress come from the broker.
+/// Only the last `max_bars` are kept.
+pub fn merge_data(
+    local: &[Candle],
+    fetched: VEC_DOHLC,
+    max_bars: usize,
+    time_frame: &TimeFrameType,
+) -> VEC_DOHLC {
+    let period = Duration::minutes(time_frame.to_number() as i64);
+    merge_bars(
+        local.iter().map(to_dohlc).collect(),
+        fetched,
+        max_bars,
+        period,
+    )
+}
+
+// Fetched bars starting more than a period after the last local one leave a
+// hole, like when the fetch was capped to max_bars, so the local bars are
+// dropped
+fn merge_bars(
+    local: VEC_DOHLC,
+    mut fetched: VEC_DOHLC,
+    max_bars: usize,
+    period: Duration,
+) -> VEC_DOHLC {
+    fetched.sort_by(|a, b| a.0.cmp(&b.0));
+    fetched.dedup_by(|a, b| a.0 == b.0);
+
+    let mut merged: VEC_DOHLC = match (fetched.first(), local.last()) {
+        (Some(first), Some(last)) if first.0 > last.0 + period => {
+            log::warn!(
+                "Fetched bars start at {} after the last local one at {}. Local bars dropped",
+                first.0,
+                last.0
+            );
+            vec![]
+        }
+        (Some(first), _) => local.into_iter().filter(|bar| bar.0 < first.0).collect(),
+        (None, _) => local,
+    };
+    merged.extend(fetched);
+
+    let excess = merged.len().saturating_sub(max_bars);
+    merged.drain(..excess);
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, TimeZone};
+
+    const MAX_BARS: usize = 100;
+
+    // Hourly bars with the close set to the hour, to tell them apart
+    fn bar(hour: i64, close: f64) -> DOHLC {
+        let date = Local.with_ymd_and_hms(2024, 1, 3, 0, 0, 0).unwrap() + Duration::hours(hour);
+        (date, 1., 1., 1., close, 0.)
+    }
+
+    fn bars(hours: std::ops::Range<i64>) -> VEC_DOHLC {
+        hours.map(|hour| bar(hour, hour as f64)).collect()
+    }
+
+    // Closes of the merged hourly bars
+    fn merge(local: VEC_DOHLC, fetched: VEC_DOHLC, max_bars: usize) -> Vec<f64> {
+        merge_bars(local, fetched, max_bars, Duration::hours(1))
+            .iter()
+            .map(|bar| bar.4)
+            .collect()
+    }
+
+    #[test]
+    fn overlapping_bars_come_from_the_broker() {
+        let fetched = vec![bar(3, 30.), bar(4, 40.), bar(5, 50.)];
+
+        assert_eq!(
+            merge(bars(0..5), fetched, MAX_BARS),
+            vec![0., 1., 2., 30., 40., 50.]
+        );
+    }
+
+    #[test]
+    fn fetched_bars_are_sorted_and_deduplicated() {
+        let fetched = vec![bar(5, 50.), bar(4, 40.), bar(5, 51.)];
+
+        assert_eq!(
+            merge(bars(0..5), fetched, MAX_BARS),
+            vec![0., 1., 2., 3., 40., 50.]
+        );
+    }
+
+    #[test]
+    fn only_the_last_max_bars_are_kept() {
+        assert_eq!(merge(bars(0..5), bars(4..8), 3), vec![5., 6., 7.]);
+    }
+
+    #[test]
+    fn local_bars_are_kept_without_fetched_ones() {
+        assert_eq!(merge(bars(0..3), vec![], MAX_BARS), vec![0., 1., 2.]);
+        assert_eq!(merge(vec![], bars(0..3), MAX_BARS), vec![0., 1., 2.]);
+    }
+
+    #[test]
+    fn fetched_bars_right_after_the_last_local_one_are_appended() {
+        assert_eq!(
+            merge(bars(0..3), bars(3..5), MAX_BARS),
+            vec![0., 1., 2., 3., 4.]
+        );
+    }
+
+    #[test]
+    fn local_bars_are_dropped_on_a_hole() {
+        assert_eq!(merge(bars(0..3), bars(4..6), MAX_BARS), vec![4., 5.]);
+    }
+}
@@ -1 +1,2 @@
+pub mod candles;
 pub mod vars;
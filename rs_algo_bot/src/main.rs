@@ -1,13 +1,23 @@
+mod backtest;
 mod bot;
+mod config;
 mod error;
+mod gaps;
 mod helpers;
 mod message;
+mod pending;
+mod position;
+mod reconciliation;
+mod reconnect;
+mod risk_guard;
 mod strategies;
+mod tls;
+mod ws_client;
 
 use bot::Bot;
-use helpers::vars::*;
-use rs_algo_shared::models::time_frame::TimeFrame;
-use rs_algo_shared::models::{environment, strategy};
+use config::{BotConfig, RunMode};
+use strategies::registry::StrategyRegistry;
+use ws_client::WebSocket;
 
 use dotenv::dotenv;
 use std::env;
@@ -17,34 +27,64 @@ async fn main() {
     dotenv().ok();
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
-    let env = env::var("ENV").unwrap();
-    let symbol = env::var("SYMBOL").unwrap();
-    let market = env::var("MARKET").unwrap();
-    let strategy_name = env::var("STRATEGY_NAME").unwrap();
-    let time_frame = env::var("TIME_FRAME").unwrap();
-    let higher_time_frame = env::var("HIGHER_TIME_FRAME").unwrap();
-    let strategy_type = env::var("STRATEGY_TYPE").unwrap();
-
-    let server_url = env::var("WS_SERVER_URL").expect("WS_SERVER_URL not found");
-    let port = env::var("WS_SERVER_PORT").expect("WS_SERVER_PORT not found");
-    let concection_str = env::var("WS_SERVER_STR").expect("WS_SERVER_STR not found");
-    let url = [&server_url, ":", &port, "/?", &concection_str].concat();
-
-    let market = get_market(market);
-    let time_frame = TimeFrame::new(&time_frame);
-    let higher_time_frame = TimeFrame::new(&higher_time_frame);
-    let strategy_type = strategy::from_str(&strategy_type);
-    let env = environment::from_str(&env);
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--list-strategies") {
+        let registry = StrategyRegistry::new();
+        println!(
+            "{}",
+            serde_json::to_string_pretty(registry.descriptors()).unwrap()
+        );
+        return;
+    }
+
+    let mode = match args.first().map(|arg| arg.as_str()) {
+        Some("backtest") => RunMode::Backtest,
+        _ => RunMode::Bot,
+    };
+
+    let config = match BotConfig::load(mode) {
+        Ok(config) => config,
+        Err(errors) => {
+            for err in errors {
+                log::error!("Config: {}", err);
+            }
+            std::process::exit(1);
+        }
+    };
+
+    if mode == RunMode::Backtest {
+        match backtest::run(&args[1..], &config).await {
+            Ok(stats) => println!("{}", serde_json::to_string_pretty(&stats).unwrap()),
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let tls = match tls::client_config(&config.ws_server_ca) {
+        Ok(tls) => tls,
+        Err(err) => {
+            log::error!("Config: Invalid ws_server_ca {}", err);
+            std::process::exit(1);
+        }
+    };
 
     Bot::new()
-        .env(env)
-        .symbol(symbol)
-        .market(market)
-        .server_url(url)
-        .time_frame(time_frame)
-        .strategy_name(strategy_name)
-        .strategy_type(strategy_type)
-        .higher_time_frame(higher_time_frame)
+        .env(config.environment())
+        .symbol(config.symbol.clone())
+        .market(config.market())
+        .websocket(WebSocket::new(
+            config.server_url(),
+            config.ws_server_token.clone(),
+            tls,
+        ))
+        .time_frame(config.time_frame())
+        .strategy_name(config.strategy_name.clone())
+        .strategy_type(config.strategy_type())
+        .higher_time_frame(config.higher_time_frame())
+        .config(config)
         .build()
         .unwrap()
         .run()
@@ -1,166 +1,317 @@
 use rs_algo_shared::broker::{DOHLC, VEC_DOHLC};
-use rs_algo_shared::helpers::date::DateTime;
 use rs_algo_shared::models::bot::BotData;
 use rs_algo_shared::models::market::MarketHours;
+use rs_algo_shared::models::order::Order;
 use rs_algo_shared::models::tick::InstrumentTick;
 use rs_algo_shared::models::time_frame::*;
 use rs_algo_shared::models::trade::*;
 use rs_algo_shared::ws::message::*;
 
+use crate::strategies::sizing::LotLimits;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::str::FromStr;
+use thiserror::Error;
 
-pub fn get_type(msg: &str) -> MessageType {
-    if !msg.is_empty() {
-        let parsed: Value = serde_json::from_str(msg).expect("Can't parse to JSON");
-        let response = parsed["response"].as_str();
-        let payload = &parsed["payload"];
-        let data = &parsed["payload"]["data"];
-        let symbol = parsed["payload"]["symbol"].as_str().unwrap_or("");
-        let accepted = parsed["payload"]["accepted"].as_bool().unwrap_or(false);
-
-        let time_frame = match parsed["payload"]["time_frame"].as_str() {
-            Some(tm) => TimeFrameType::from_str(tm),
-            None => TimeFrameType::ERR,
-        };
-
-        match response {
-            Some("Connected") => MessageType::Connected(ResponseBody {
-                response: ResponseType::Connected,
-                payload: None,
-            }),
-            Some("Reconnect") => MessageType::Reconnect(ResponseBody {
-                response: ResponseType::Reconnect,
-                payload: None,
-            }),
-            Some("InitSession") => MessageType::InitSession(ResponseBody {
-                response: ResponseType::InitSession,
-                payload: Some(parse_bot_data(payload)),
-            }),
-            Some("GetMarketHours") => MessageType::MarketHours(ResponseBody {
-                response: ResponseType::GetMarketHours,
-                payload: Some(parse_market_hours(payload)),
-            }),
-            Some("GetActivePositions") => MessageType::ActivePositions(ResponseBody {
-                response: ResponseType::GetActivePositions,
-                payload: Some(parse_active_positions(payload)),
-            }),
-            Some("IsMarketOpen") => MessageType::IsMarketOpen(ResponseBody {
-                response: ResponseType::GetMarketHours,
-                payload: Some(parse_market_open(payload)),
-            }),
-            Some("GetInstrumentTick") => MessageType::InstrumentTick(ResponseBody {
-                response: ResponseType::GetInstrumentTick,
-                payload: Some(parse_tick_data(payload)),
-            }),
-            Some("GetInstrumentData") => MessageType::InstrumentData(ResponseBody {
-                response: ResponseType::GetInstrumentData,
-                payload: Some(InstrumentData {
-                    symbol: symbol.to_owned(),
-                    time_frame,
-                    data: parse_vec_dohlc(data),
-                }),
-            }),
-            Some("SubscribeStream") => MessageType::StreamResponse(ResponseBody {
-                response: ResponseType::SubscribeStream,
-                payload: Some(InstrumentData {
-                    symbol: symbol.to_owned(),
-                    time_frame,
-                    data: parse_dohlc(payload),
-                }),
-            }),
-            Some("SubscribeTickPrices") => MessageType::StreamTickResponse(ResponseBody {
-                response: ResponseType::SubscribeTickPrices,
-                payload: Some(parse_tick_data(payload)),
-            }),
-            // Some("SubscribeTrades") => MessageType::StreamTradesResponse(ResponseBody {
-            //     response: ResponseType::SubscribeTrades,
-            //     payload: Some(parse_trades_data(payload)),
-            // }),
-            Some("TradeInFulfilled") => MessageType::TradeInFulfilled(ResponseBody {
-                response: ResponseType::TradeInFulfilled,
-                payload: Some(TradeResponse {
-                    symbol: symbol.to_owned(),
-                    accepted,
-                    data: parse_trade_in(data),
-                }),
-            }),
-            Some("TradeOutFulfilled") => MessageType::TradeOutFulfilled(ResponseBody {
-                response: ResponseType::TradeOutFulfilled,
-                payload: Some(TradeResponse {
-                    symbol: symbol.to_owned(),
-                    accepted,
-                    data: parse_trade_out(data),
-                }),
-            }),
-            _ => MessageType::Error(ResponseBody {
-                response: ResponseType::Error,
-                payload: None,
-            }),
-        }
-    } else {
-        MessageType::Error(ResponseBody {
-            response: ResponseType::Error,
-            payload: None,
-        })
-    }
+/// Commands not defined in rs_algo_shared CommandType
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExtendedCommandType {
+    ModifyOrder,
+    GetLotLimits,
+}
+
+#[derive(Serialize)]
+pub struct ExtendedCommand<T> {
+    pub command: ExtendedCommandType,
+    pub data: Option<T>,
+}
+
+#[derive(Serialize)]
+pub struct ModifyOrderPayload<'a> {
+    pub symbol: &'a str,
+    pub strategy_name: &'a str,
+    pub data: &'a Order,
+}
+
+#[derive(Serialize)]
+pub struct SymbolPayload<'a> {
+    pub symbol: &'a str,
+}
+
+// Names of the extended commands, as serialized, to match their responses
+pub const MODIFY_ORDER: &str = "ModifyOrder";
+pub const GET_LOT_LIMITS: &str = "GetLotLimits";
+pub const ERROR: &str = "Error";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum ErrorCode {
+    BrokerRejected,
+    Timeout,
+    Validation,
+    DbFailure,
+    Unauthorized,
+    /// Not supported by the broker
+    Unsupported,
 }
 
-pub fn parse_trade_in(data: &Value) -> TradeIn {
-    let trade_in: TradeIn = serde_json::from_value(data.clone()).unwrap();
-    trade_in
+/// Error reply to a command the server couldn't fulfill
+#[derive(Debug, Clone, Deserialize)]
+pub struct ErrorResponse {
+    pub command: String,
+    pub code: ErrorCode,
+    pub message: String,
 }
 
-pub fn parse_trade_out(data: &Value) -> TradeOut {
-    let trade_out: TradeOut = serde_json::from_value(data.clone()).unwrap();
-    trade_out
+/// Server responses the bot understands
+pub enum Response {
+    Connected,
+    Reconnect {
+        clean_data: bool,
+    },
+    InitSession(BotData),
+    MarketHours(MarketHours),
+    ActivePositions(PositionResult),
+    IsMarketOpen(bool),
+    InstrumentTick(InstrumentTick),
+    InstrumentData(InstrumentData<VEC_DOHLC>),
+    Stream(DOHLC),
+    StreamTick(InstrumentTick),
+    TradeInFulfilled(TradeResponse<TradeIn>),
+    TradeOutFulfilled(TradeResponse<TradeOut>),
+    OrderModified(TradeResponse<Order>),
+    /// None when the broker doesn't report them
+    LotLimits(Option<LotLimits>),
+    Error(ErrorResponse),
 }
 
-pub fn parse_bot_data(data: &Value) -> BotData {
-    let bot_data: BotData = serde_json::from_value(data.clone()).unwrap();
-    bot_data
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error("Malformed message. {0}")]
+    Malformed(#[from] serde_json::Error),
+    #[error("Unknown {0} response")]
+    Unknown(String),
+    #[error("Invalid {response} payload. {source}")]
+    InvalidPayload {
+        response: String,
+        source: serde_json::Error,
+    },
 }
 
-pub fn parse_tick_data(data: &Value) -> InstrumentTick {
-    let tick: InstrumentTick = serde_json::from_value(data.clone()).unwrap();
-    tick
+/// Messages ignored since the bot started
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DecodeErrors {
+    pub malformed: usize,
+    pub unknown: usize,
+    pub invalid_payload: usize,
 }
 
-pub fn parse_trades_data(data: &Value) -> TradeResult {
-    let trade_result: TradeResult = serde_json::from_value(data.clone()).unwrap();
-    trade_result
+impl DecodeErrors {
+    pub fn record(&mut self, err: &DecodeError) {
+        match err {
+            DecodeError::Malformed(_) => self.malformed += 1,
+            DecodeError::Unknown(_) => self.unknown += 1,
+            DecodeError::InvalidPayload { .. } => self.invalid_payload += 1,
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.malformed + self.unknown + self.invalid_payload
+    }
 }
 
-pub fn parse_market_hours(data: &Value) -> MarketHours {
-    let market_hours: MarketHours = serde_json::from_value(data.clone()).unwrap();
-    market_hours
+#[derive(Deserialize)]
+struct Frame {
+    response: String,
+    #[serde(default)]
+    payload: Value,
 }
 
-pub fn parse_market_open(data: &Value) -> bool {
-    let market_open: bool = serde_json::from_value(data.clone()).unwrap();
-    market_open
+impl Frame {
+    fn payload<T: DeserializeOwned>(&self) -> Result<T, DecodeError> {
+        T::deserialize(&self.payload).map_err(|source| DecodeError::InvalidPayload {
+            response: self.response.clone(),
+            source,
+        })
+    }
 }
 
-pub fn parse_active_positions(data: &Value) -> PositionResult {
-    let active_positions: PositionResult = serde_json::from_value(data.clone()).unwrap();
-    active_positions
+#[derive(Deserialize)]
+struct InstrumentDataPayload {
+    #[serde(default)]
+    symbol: String,
+    time_frame: Option<String>,
+    data: VEC_DOHLC,
 }
 
-pub fn parse_dohlc(data: &Value) -> DOHLC {
-    let obj = data.as_array().unwrap();
-    let date = DateTime::from_str(obj[0].as_str().unwrap()).unwrap();
-    let open = obj[1].as_f64().unwrap();
-    let high = obj[2].as_f64().unwrap();
-    let low = obj[3].as_f64().unwrap();
-    let close = obj[4].as_f64().unwrap();
-    let volume = obj[5].as_f64().unwrap();
-    (date, open, high, low, close, volume)
+#[derive(Deserialize)]
+struct ReconnectPayload {
+    clean_data: bool,
+}
+
+pub fn decode(msg: &str) -> Result<Response, DecodeError> {
+    let frame: Frame = serde_json::from_str(msg)?;
+
+    let response = match frame.response.as_str() {
+        "Connected" => Response::Connected,
+        "Reconnect" => {
+            // Without options the bot starts from scratch, as it always did
+            let payload: Option<ReconnectPayload> = frame.payload()?;
+            Response::Reconnect {
+                clean_data: payload.map_or(true, |payload| payload.clean_data),
+            }
+        }
+        "InitSession" => Response::InitSession(frame.payload()?),
+        "GetMarketHours" => Response::MarketHours(frame.payload()?),
+        "GetActivePositions" => Response::ActivePositions(frame.payload()?),
+        "IsMarketOpen" => Response::IsMarketOpen(frame.payload()?),
+        "GetInstrumentTick" => Response::InstrumentTick(frame.payload()?),
+        "GetInstrumentData" => {
+            let payload: InstrumentDataPayload = frame.payload()?;
+            let time_frame = match payload.time_frame {
+                Some(tm) => TimeFrameType::from_str(&tm),
+                None => TimeFrameType::ERR,
+            };
+
+            Response::InstrumentData(InstrumentData {
+                symbol: payload.symbol,
+                time_frame,
+                data: payload.data,
+            })
+        }
+        "SubscribeStream" => Response::Stream(frame.payload()?),
+        "SubscribeTickPrices" => Response::StreamTick(frame.payload()?),
+        "TradeInFulfilled" => Response::TradeInFulfilled(frame.payload()?),
+        "TradeOutFulfilled" => Response::TradeOutFulfilled(frame.payload()?),
+        MODIFY_ORDER => Response::OrderModified(frame.payload()?),
+        GET_LOT_LIMITS => Response::LotLimits(frame.payload()?),
+        ERROR => Response::Error(frame.payload()?),
+        _ => return Err(DecodeError::Unknown(frame.response)),
+    };
+
+    Ok(response)
 }
 
-pub fn parse_vec_dohlc(data: &Value) -> VEC_DOHLC {
-    let mut result: VEC_DOHLC = vec![];
-    for obj in data.as_array().unwrap() {
-        result.push(parse_dohlc(obj));
+// Echoed by the server from the command
+pub fn parse_request_id(msg: &str) -> Option<u64> {
+    let parsed: Value = serde_json::from_str(msg).ok()?;
+    parsed["request_id"].as_u64()
+}
+
+pub fn parse_payload_field<T>(msg: &str, key: &str) -> Option<T>
+where
+    T: DeserializeOwned,
+{
+    let parsed: Value = serde_json::from_str(msg).ok()?;
+    serde_json::from_value(parsed["payload"][key].clone()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extended_command_names() {
+        assert_eq!(
+            serde_json::to_value(ExtendedCommandType::ModifyOrder).unwrap(),
+            MODIFY_ORDER
+        );
+        assert_eq!(
+            serde_json::to_value(ExtendedCommandType::GetLotLimits).unwrap(),
+            GET_LOT_LIMITS
+        );
+    }
+
+    fn decode_error(msg: &str) -> DecodeError {
+        match decode(msg) {
+            Ok(_) => panic!("{} decoded", msg),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn malformed_frames() {
+        for msg in [
+            "",
+            "not json",
+            "[1, 2]",
+            r#"{"payload": {}}"#,
+            r#"{"response": 1}"#,
+        ] {
+            assert!(
+                matches!(decode_error(msg), DecodeError::Malformed(_)),
+                "{}",
+                msg
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_responses() {
+        let err = decode_error(r#"{"response": "Whatever", "payload": null}"#);
+        assert!(matches!(err, DecodeError::Unknown(ref response) if response == "Whatever"));
+    }
+
+    #[test]
+    fn invalid_payloads() {
+        for msg in [
+            r#"{"response": "IsMarketOpen", "payload": "yes"}"#,
+            r#"{"response": "Error", "payload": {"command": "InitSession"}}"#,
+            r#"{"response": "GetInstrumentData", "payload": {"symbol": "EURUSD"}}"#,
+            r#"{"response": "Reconnect", "payload": {"clean_data": 1}}"#,
+        ] {
+            assert!(
+                matches!(decode_error(msg), DecodeError::InvalidPayload { .. }),
+                "{}",
+                msg
+            );
+        }
+    }
+
+    #[test]
+    fn payloads_are_optional_where_expected() {
+        assert!(matches!(
+            decode(r#"{"response": "Connected"}"#),
+            Ok(Response::Connected)
+        ));
+        assert!(matches!(
+            decode(r#"{"response": "Reconnect"}"#),
+            Ok(Response::Reconnect { clean_data: true })
+        ));
+        assert!(matches!(
+            decode(r#"{"response": "GetLotLimits", "payload": null}"#),
+            Ok(Response::LotLimits(None))
+        ));
+    }
+
+    #[test]
+    fn error_replies() {
+        let msg = r#"{"response": "Error", "request_id": 3, "payload": {"command": "ModifyOrder", "code": "Unsupported", "message": "No"}}"#;
+
+        match decode(msg) {
+            Ok(Response::Error(error)) => {
+                assert_eq!(error.command, MODIFY_ORDER);
+                assert_eq!(error.code, ErrorCode::Unsupported);
+            }
+            _ => panic!("Error reply not decoded"),
+        }
+        assert_eq!(parse_request_id(msg), Some(3));
+    }
+
+    #[test]
+    fn errors_are_counted_by_kind() {
+        let mut errors = DecodeErrors::default();
+        errors.record(&decode_error("not json"));
+        errors.record(&decode_error(r#"{"response": "Whatever"}"#));
+        errors.record(&decode_error(
+            r#"{"response": "IsMarketOpen", "payload": "yes"}"#,
+        ));
+
+        assert_eq!(
+            (errors.malformed, errors.unknown, errors.invalid_payload),
+            (1, 1, 1)
+        );
+        assert_eq!(errors.total(), 3);
     }
-    result
 }
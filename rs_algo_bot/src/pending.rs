@@ -0,0 +1,180 @@
+use crate::message;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Seconds to wait for a command response
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestTimeouts {
+    /// ExecutePosition and ModifyOrder
+    pub execution: u64,
+    /// GetInstrumentData, which can take a while for long histories
+    pub data: u64,
+    pub default: u64,
+}
+
+impl Default for RequestTimeouts {
+    fn default() -> Self {
+        Self {
+            execution: 30,
+            data: 120,
+            default: 30,
+        }
+    }
+}
+
+impl RequestTimeouts {
+    /// None for commands the server doesn't reply to
+    pub fn timeout(&self, command: &str) -> Option<Duration> {
+        let secs = match command {
+            "UpdateBotData" | "SubscribeStream" => return None,
+            "ExecutePosition" | message::MODIFY_ORDER => self.execution,
+            "GetInstrumentData" => self.data,
+            _ => self.default,
+        };
+
+        Some(Duration::from_secs(secs))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingRequest {
+    pub id: u64,
+    pub command: String,
+    pub sent_at: Instant,
+    deadline: Instant,
+}
+
+/// Commands sent to the server still waiting for their response, by request
+/// id.
+#[derive(Debug, Default)]
+pub struct PendingRequests {
+    next_id: u64,
+    requests: HashMap<u64, PendingRequest>,
+}
+
+impl PendingRequests {
+    /// Id of the request, None for commands without timeout. Their responses
+    /// carry no id, so they are never taken for late ones.
+    pub fn register(&mut self, command: &str, timeout: Option<Duration>) -> Option<u64> {
+        let timeout = timeout?;
+        let now = Instant::now();
+        self.next_id += 1;
+
+        self.requests.insert(
+            self.next_id,
+            PendingRequest {
+                id: self.next_id,
+                command: command.to_owned(),
+                sent_at: now,
+                deadline: now + timeout,
+            },
+        );
+
+        Some(self.next_id)
+    }
+
+    pub fn resolve(&mut self, id: u64) -> Option<PendingRequest> {
+        self.requests.remove(&id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.requests.values().map(|request| request.deadline).min()
+    }
+
+    /// Removes and returns the requests past their deadline
+    pub fn expired(&mut self) -> Vec<PendingRequest> {
+        let now = Instant::now();
+        let ids: Vec<u64> = self
+            .requests
+            .values()
+            .filter(|request| request.deadline <= now)
+            .map(|request| request.id)
+            .collect();
+
+        let mut expired: Vec<PendingRequest> = ids
+            .iter()
+            .filter_map(|id| self.requests.remove(id))
+            .collect();
+        expired.sort_by_key(|request| request.id);
+        expired
+    }
+
+    /// Responses to requests sent on a previous connection never arrive
+    pub fn clear(&mut self) {
+        self.requests.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG: Option<Duration> = Some(Duration::from_secs(60));
+
+    #[test]
+    fn commands_without_timeout_are_not_tracked() {
+        let mut pending = PendingRequests::default();
+
+        assert_eq!(pending.register("UpdateBotData", None), None);
+        assert_eq!(pending.next_deadline(), None);
+    }
+
+    #[test]
+    fn responses_resolve_their_request_once() {
+        let mut pending = PendingRequests::default();
+        let first = pending.register("GetMarketHours", LONG).unwrap();
+        let second = pending.register("IsMarketOpen", LONG).unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(pending.resolve(first).unwrap().command, "GetMarketHours");
+        assert!(pending.resolve(first).is_none());
+        assert!(pending.resolve(second + 1).is_none());
+        assert!(!pending.is_empty());
+
+        pending.resolve(second);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn expired_requests_are_removed_in_order() {
+        let mut pending = PendingRequests::default();
+        let first = pending
+            .register("ExecutePosition", Some(Duration::ZERO))
+            .unwrap();
+        pending.register("GetInstrumentData", LONG);
+        let third = pending
+            .register("GetMarketHours", Some(Duration::ZERO))
+            .unwrap();
+
+        let expired: Vec<u64> = pending.expired().iter().map(|request| request.id).collect();
+        assert_eq!(expired, vec![first, third]);
+        assert!(pending.expired().is_empty());
+        assert!(pending.resolve(first).is_none());
+    }
+
+    #[test]
+    fn next_deadline_is_the_earliest() {
+        let mut pending = PendingRequests::default();
+        pending.register("GetInstrumentData", LONG);
+        let before = Instant::now();
+        pending.register("ExecutePosition", Some(Duration::from_secs(1)));
+
+        let next_deadline = pending.next_deadline().unwrap();
+        assert!(next_deadline >= before + Duration::from_secs(1));
+        assert!(next_deadline < before + Duration::from_secs(60));
+
+        pending.clear();
+        assert_eq!(pending.next_deadline(), None);
+    }
+}
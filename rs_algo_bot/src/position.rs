@@ -0,0 +1,202 @@
+use rs_algo_shared::helpers::date::Local;
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use thiserror::Error;
+
+const MAX_TRANSITIONS: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PositionState {
+    Flat,
+    PendingEntry,
+    Open,
+    PendingExit,
+    Rejected,
+    Reconciling,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PositionEvent {
+    EntrySent,
+    EntryFilled,
+    EntryRejected,
+    ExitSent,
+    ExitFilled,
+    ExitRejected,
+    Reconcile,
+    Reconciled(bool),
+    Restored(bool),
+}
+
+#[derive(Debug, Error)]
+#[error("Invalid position transition {event:?} from {from:?}")]
+pub struct TransitionError {
+    pub from: PositionState,
+    pub event: PositionEvent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transition {
+    pub date: String,
+    pub from: PositionState,
+    pub to: PositionState,
+    pub event: PositionEvent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PositionTracker {
+    state: PositionState,
+    transitions: VecDeque<Transition>,
+}
+
+impl Default for PositionTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PositionTracker {
+    pub fn new() -> Self {
+        Self {
+            state: PositionState::Flat,
+            transitions: VecDeque::new(),
+        }
+    }
+
+    pub fn state(&self) -> PositionState {
+        self.state
+    }
+
+    pub fn is_flat(&self) -> bool {
+        matches!(self.state, PositionState::Flat | PositionState::Rejected)
+    }
+
+    fn next_state(&self, event: PositionEvent) -> Option<PositionState> {
+        use PositionEvent::*;
+        use PositionState::*;
+
+        match (self.state, event) {
+            (_, Restored(open)) | (Reconciling, Reconciled(open)) => match open {
+                true => Some(Open),
+                false => Some(Flat),
+            },
+            (Reconciling, _) => None,
+            (_, Reconcile) => Some(Reconciling),
+            (Flat | Rejected, EntrySent) => Some(PendingEntry),
+            (PendingEntry, EntryFilled) => Some(Open),
+            (PendingEntry, EntryRejected) => Some(Rejected),
+            (Open, ExitSent) => Some(PendingExit),
+            (PendingExit, ExitFilled) => Some(Flat),
+            (PendingExit, ExitRejected) => Some(Open),
+            _ => None,
+        }
+    }
+
+    pub fn apply(&mut self, event: PositionEvent) -> Result<PositionState, TransitionError> {
+        let to = self.next_state(event).ok_or(TransitionError {
+            from: self.state,
+            event,
+        })?;
+
+        log::info!("Position {:?} -> {:?} on {:?}", self.state, to, event);
+
+        if self.transitions.len() == MAX_TRANSITIONS {
+            self.transitions.pop_front();
+        }
+
+        self.transitions.push_back(Transition {
+            date: Local::now().to_string(),
+            from: self.state,
+            to,
+            event,
+        });
+
+        self.state = to;
+        Ok(to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use PositionEvent::*;
+    use PositionState::*;
+
+    fn tracker(events: &[PositionEvent]) -> PositionTracker {
+        let mut tracker = PositionTracker::new();
+        for event in events {
+            tracker.apply(*event).unwrap();
+        }
+        tracker
+    }
+
+    #[test]
+    fn entry_and_exit_round_trip() {
+        let tracker = tracker(&[EntrySent, EntryFilled, ExitSent, ExitFilled]);
+        assert_eq!(tracker.state(), Flat);
+        assert_eq!(tracker.transitions.len(), 4);
+    }
+
+    #[test]
+    fn rejections_roll_back() {
+        assert_eq!(tracker(&[EntrySent, EntryRejected]).state(), Rejected);
+        assert_eq!(
+            tracker(&[EntrySent, EntryFilled, ExitSent, ExitRejected]).state(),
+            Open
+        );
+        assert_eq!(
+            tracker(&[EntrySent, EntryRejected, EntrySent]).state(),
+            PendingEntry
+        );
+    }
+
+    #[test]
+    fn invalid_transitions_are_refused() {
+        let mut flat = tracker(&[]);
+        let err = flat.apply(ExitSent).err().unwrap();
+        assert_eq!((err.from, err.event), (Flat, ExitSent));
+        assert_eq!(flat.state(), Flat);
+
+        let mut pending = tracker(&[EntrySent]);
+        assert!(pending.apply(EntrySent).is_err());
+        assert!(pending.apply(ExitFilled).is_err());
+
+        let mut open = tracker(&[EntrySent, EntryFilled]);
+        assert!(open.apply(EntrySent).is_err());
+    }
+
+    #[test]
+    fn reconciling_only_ends_on_reconciled() {
+        let mut reconciling = tracker(&[EntrySent, Reconcile]);
+        assert!(reconciling.apply(EntryFilled).is_err());
+        assert!(reconciling.apply(Reconcile).is_err());
+        assert_eq!(reconciling.apply(Reconciled(true)).unwrap(), Open);
+        assert_eq!(tracker(&[Reconcile, Reconciled(false)]).state(), Flat);
+        assert!(tracker(&[]).apply(Reconciled(true)).is_err());
+    }
+
+    #[test]
+    fn restored_from_any_state() {
+        assert_eq!(tracker(&[EntrySent, Restored(true)]).state(), Open);
+        assert_eq!(tracker(&[Reconcile, Restored(false)]).state(), Flat);
+    }
+
+    #[test]
+    fn flat_states() {
+        assert!(tracker(&[]).is_flat());
+        assert!(tracker(&[EntrySent, EntryRejected]).is_flat());
+        assert!(!tracker(&[EntrySent]).is_flat());
+    }
+
+    #[test]
+    fn transitions_are_capped() {
+        let events: Vec<PositionEvent> = (0..MAX_TRANSITIONS)
+            .flat_map(|_| [EntrySent, EntryRejected])
+            .collect();
+        let tracker = tracker(&events);
+
+        assert_eq!(tracker.transitions.len(), MAX_TRANSITIONS);
+        assert_eq!(tracker.transitions.back().unwrap().event, EntryRejected);
+    }
+}
@@ -0,0 +1,227 @@
+use rs_algo_shared::helpers::date::Local;
+use rs_algo_shared::models::order::{self, Order};
+use rs_algo_shared::models::tick::InstrumentTick;
+use rs_algo_shared::models::trade::{self, *};
+use rs_algo_shared::scanner::instrument::Instrument;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReconciliationPolicy {
+    /// Broker positions unknown to the bot are added to its trades
+    Adopt,
+    /// Broker positions unknown to the bot are closed
+    Close,
+}
+
+pub fn policy_from_str(policy: &str) -> ReconciliationPolicy {
+    match policy {
+        "Close" => ReconciliationPolicy::Close,
+        _ => ReconciliationPolicy::Adopt,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ReconciliationAction {
+    Updated(usize),
+    Adopted(usize),
+    Closed(usize),
+    ClosedExternally(usize),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReconciliationReport {
+    pub date: String,
+    pub policy: ReconciliationPolicy,
+    pub db_open_trades: usize,
+    pub broker_positions: usize,
+    pub actions: Vec<ReconciliationAction>,
+}
+
+impl ReconciliationReport {
+    pub fn in_sync(&self) -> bool {
+        self.actions
+            .iter()
+            .all(|action| matches!(action, ReconciliationAction::Updated(_)))
+    }
+}
+
+pub struct Reconciliation {
+    pub report: ReconciliationReport,
+    /// Adopted broker positions that have to be closed at the broker
+    pub to_close: Vec<TradeOut>,
+    /// DB trades no longer open at the broker
+    pub closed_externally: Vec<(TradeIn, TradeOut)>,
+}
+
+pub fn open_trades(trades_in: &[TradeIn], trades_out: &[TradeOut]) -> Vec<TradeIn> {
+    let num_open = trades_in.len().saturating_sub(trades_out.len());
+    trades_in[trades_in.len() - num_open..].to_vec()
+}
+
+/// Actions reconciling the broker position, if any, with the trades the bot
+/// knows and the ones it has open
+pub fn plan(
+    policy: ReconciliationPolicy,
+    broker_id: Option<usize>,
+    known_ids: &[usize],
+    db_open_ids: &[usize],
+) -> Vec<ReconciliationAction> {
+    let mut actions = vec![];
+
+    if let Some(id) = broker_id {
+        let action = match (known_ids.contains(&id), policy) {
+            (true, _) => ReconciliationAction::Updated(id),
+            (false, ReconciliationPolicy::Adopt) => ReconciliationAction::Adopted(id),
+            (false, ReconciliationPolicy::Close) => ReconciliationAction::Closed(id),
+        };
+        actions.push(action);
+    }
+
+    for id in db_open_ids.iter().filter(|id| Some(**id) != broker_id) {
+        actions.push(ReconciliationAction::ClosedExternally(*id));
+    }
+
+    actions
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn reconcile(
+    policy: ReconciliationPolicy,
+    broker_position: PositionResult,
+    index: usize,
+    instrument: &Instrument,
+    tick: &InstrumentTick,
+    trades_in: &mut Vec<TradeIn>,
+    trades_out: &[TradeOut],
+    orders: &mut Vec<Order>,
+) -> Reconciliation {
+    let db_open_trades = open_trades(trades_in, trades_out);
+    let db_open_ids: Vec<usize> = db_open_trades.iter().map(|trade_in| trade_in.id).collect();
+    let known_ids: Vec<usize> = trades_in.iter().map(|trade_in| trade_in.id).collect();
+
+    let (broker_trade, broker_orders) = match broker_position {
+        PositionResult::MarketIn(TradeResult::TradeIn(trade_in), broker_orders) => {
+            (Some(trade_in), broker_orders)
+        }
+        _ => (None, None),
+    };
+
+    let broker_id = broker_trade.as_ref().map(|trade_in| trade_in.id);
+    let actions = plan(policy, broker_id, &known_ids, &db_open_ids);
+    let mut to_close = vec![];
+    let mut closed_externally = vec![];
+
+    for action in actions.iter() {
+        match (action, &broker_trade) {
+            (ReconciliationAction::Updated(_), Some(trade_in)) => {
+                trade::update_trade_by_id(trades_in, trade_in.clone());
+
+                if let Some(broker_orders) = &broker_orders {
+                    order::update_orders(orders, broker_orders);
+                }
+            }
+            (
+                ReconciliationAction::Adopted(_) | ReconciliationAction::Closed(_),
+                Some(trade_in),
+            ) => {
+                trades_in.push(trade_in.clone());
+
+                if let Some(broker_orders) = &broker_orders {
+                    *orders = order::add_pending(orders.clone(), broker_orders.clone());
+                }
+
+                if let (ReconciliationAction::Closed(_), TradeResult::TradeOut(trade_out)) =
+                    (action, close_trade(index, instrument, trade_in, tick))
+                {
+                    to_close.push(trade_out);
+                }
+            }
+            (ReconciliationAction::ClosedExternally(id), _) => {
+                if let Some(trade_in) = db_open_trades.iter().find(|trade_in| trade_in.id == *id) {
+                    if let TradeResult::TradeOut(trade_out) =
+                        close_trade(index, instrument, trade_in, tick)
+                    {
+                        closed_externally
+                            .push((trade_in.clone(), closed_at_entry(trade_in, trade_out)));
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    Reconciliation {
+        report: ReconciliationReport {
+            date: Local::now().to_string(),
+            policy,
+            db_open_trades: db_open_trades.len(),
+            broker_positions: broker_id.iter().count(),
+            actions,
+        },
+        to_close,
+        closed_externally,
+    }
+}
+
+// The broker doesn't send the close of positions no longer open, so they are
+// recorded at their entry price instead of pricing them at the current tick
+fn closed_at_entry(trade_in: &TradeIn, mut trade_out: TradeOut) -> TradeOut {
+    trade_out.price_out = trade_in.price_in;
+    trade_out.profit = 0.;
+    trade_out.profit_per = 0.;
+    trade_out
+}
+
+pub fn close_trade(
+    index: usize,
+    instrument: &Instrument,
+    trade_in: &TradeIn,
+    tick: &InstrumentTick,
+) -> TradeResult {
+    let trade_type = match trade_in.trade_type.is_long_entry() {
+        true => TradeType::MarketOutLong,
+        false => TradeType::MarketOutShort,
+    };
+
+    trade::resolve_trade_out(index, instrument, trade_in, &trade_type, None, tick)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ReconciliationAction::*;
+
+    #[test]
+    fn known_broker_position_is_updated() {
+        let actions = plan(ReconciliationPolicy::Close, Some(2), &[1, 2], &[2]);
+        assert_eq!(actions, vec![Updated(2)]);
+    }
+
+    #[test]
+    fn unknown_broker_position_follows_policy() {
+        let adopt = plan(ReconciliationPolicy::Adopt, Some(3), &[1], &[]);
+        let close = plan(ReconciliationPolicy::Close, Some(3), &[1], &[]);
+
+        assert_eq!(adopt, vec![Adopted(3)]);
+        assert_eq!(close, vec![Closed(3)]);
+    }
+
+    #[test]
+    fn db_trades_missing_at_the_broker_are_closed_externally() {
+        let actions = plan(ReconciliationPolicy::Adopt, None, &[1, 2], &[1, 2]);
+        assert_eq!(actions, vec![ClosedExternally(1), ClosedExternally(2)]);
+    }
+
+    #[test]
+    fn orphan_and_closed_externally_together() {
+        let actions = plan(ReconciliationPolicy::Close, Some(5), &[1, 4], &[4]);
+        assert_eq!(actions, vec![Closed(5), ClosedExternally(4)]);
+    }
+
+    #[test]
+    fn nothing_open_is_in_sync() {
+        let actions = plan(ReconciliationPolicy::Adopt, None, &[1], &[]);
+        assert!(actions.is_empty());
+    }
+}
@@ -0,0 +1,51 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Why the bot connects to the server again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReconnectCause {
+    /// The connection was lost. Retried with backoff until it succeeds
+    Disconnected,
+    /// Asked by the server, or by the bot once the market opens again
+    Requested { clean_data: bool },
+}
+
+/// Backoff between reconnect attempts, starting at `disconnected_retry` secs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectPolicy {
+    pub multiplier: f64,
+    /// Secs
+    pub max_interval: u64,
+    /// Failed attempts after which an alert is logged, once per outage
+    pub alert_after: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            multiplier: 2.,
+            max_interval: 300,
+            alert_after: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Between half and the whole capped exponential interval of the
+    /// attempt, so bots disconnected together don't retry in lockstep.
+    pub fn delay(&self, initial: u64, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(32) as i32;
+        let interval = (initial as f64 * self.multiplier.powi(exponent))
+            .min(self.max_interval as f64)
+            .max(0.);
+
+        let half = interval / 2.;
+        Duration::from_secs_f64(half + rand::thread_rng().gen_range(0.0..=half))
+    }
+
+    pub fn alert(&self, failed_attempts: u32) -> bool {
+        self.alert_after == Some(failed_attempts)
+    }
+}
@@ -0,0 +1,448 @@
+use rs_algo_shared::helpers::date::{from_dbtime, DateTime, Local};
+use rs_algo_shared::models::tick::InstrumentTick;
+use rs_algo_shared::models::trade::{TradeIn, TradeOut};
+
+use chrono::Datelike;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RiskLimits {
+    /// Max realized + unrealized loss of the trading day, in account currency
+    pub max_daily_loss: Option<f64>,
+    /// Same as max_daily_loss for the ISO week
+    pub max_weekly_loss: Option<f64>,
+    /// Max drop from the equity peak, in percentage
+    pub max_drawdown: Option<f64>,
+    pub max_consecutive_losses: Option<usize>,
+    /// Close the open trade when a limit is breached
+    pub flatten: bool,
+    /// Clear the halt persisted by a previous run
+    pub reset: bool,
+}
+
+impl RiskLimits {
+    pub fn is_enabled(&self) -> bool {
+        self.max_daily_loss.is_some()
+            || self.max_weekly_loss.is_some()
+            || self.max_drawdown.is_some()
+            || self.max_consecutive_losses.is_some()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum HaltReason {
+    DailyLoss(f64),
+    WeeklyLoss(f64),
+    Drawdown(f64),
+    ConsecutiveLosses(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Halt {
+    pub since: DateTime<Local>,
+    pub reason: HaltReason,
+}
+
+impl Halt {
+    /// Daily and weekly halts are lifted once the period is over. The rest
+    /// need a reset.
+    fn expired(&self, now: &DateTime<Local>) -> bool {
+        match self.reason {
+            HaltReason::DailyLoss(_) => self.since.date_naive() != now.date_naive(),
+            HaltReason::WeeklyLoss(_) => self.since.iso_week() != now.iso_week(),
+            _ => false,
+        }
+    }
+}
+
+pub enum GuardStatus {
+    Trading,
+    Halted,
+    /// A limit has just been breached
+    NewHalt {
+        flatten: bool,
+    },
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RiskGuard {
+    peak_equity: f64,
+    /// Realized profit of every exit so far. The bot only keeps its last
+    /// trades, so equity can't be summed from them.
+    realized_profit: f64,
+    /// Exit date of the last trade added to realized_profit
+    counted_until: Option<DateTime<Local>>,
+    halted: Option<Halt>,
+}
+
+impl RiskGuard {
+    pub fn halted(&self) -> Option<&Halt> {
+        self.halted.as_ref()
+    }
+
+    pub fn reset(&mut self) {
+        if let Some(halt) = self.halted.take() {
+            log::warn!("Risk guard halt {:?} cleared", halt.reason);
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &mut self,
+        limits: &RiskLimits,
+        equity: f64,
+        pip_value: f64,
+        open_trade: Option<&TradeIn>,
+        trades_in: &[TradeIn],
+        trades_out: &[TradeOut],
+        tick: &InstrumentTick,
+        now: DateTime<Local>,
+    ) -> GuardStatus {
+        let profits = Profits {
+            closed: closed_profits(trades_in, trades_out, pip_value, tick.pip_size()),
+            unrealized: open_trade
+                .map(|trade_in| unrealized_profit(trade_in, pip_value, tick))
+                .unwrap_or(0.),
+        };
+
+        self.check(limits, equity, &profits, open_trade.is_some(), now)
+    }
+
+    fn check(
+        &mut self,
+        limits: &RiskLimits,
+        equity: f64,
+        profits: &Profits,
+        is_open: bool,
+        now: DateTime<Local>,
+    ) -> GuardStatus {
+        self.count_realized(profits);
+
+        if let Some(halt) = &self.halted {
+            match halt.expired(&now) {
+                true => self.reset(),
+                false => return GuardStatus::Halted,
+            }
+        }
+
+        if !limits.is_enabled() {
+            return GuardStatus::Trading;
+        }
+
+        let daily = profits.in_period(|date| date.date_naive() == now.date_naive());
+        let weekly = profits.in_period(|date| date.iso_week() == now.iso_week());
+
+        let current_equity = equity + self.realized_profit + profits.unrealized;
+        self.peak_equity = self.peak_equity.max(current_equity).max(equity);
+        let drawdown = (self.peak_equity - current_equity) / self.peak_equity * 100.;
+
+        let consecutive_losses = profits
+            .closed
+            .iter()
+            .rev()
+            .take_while(|(_, profit)| *profit < 0.)
+            .count();
+
+        let reason = match limits {
+            RiskLimits {
+                max_daily_loss: Some(max),
+                ..
+            } if -daily >= *max => Some(HaltReason::DailyLoss(daily)),
+            RiskLimits {
+                max_weekly_loss: Some(max),
+                ..
+            } if -weekly >= *max => Some(HaltReason::WeeklyLoss(weekly)),
+            RiskLimits {
+                max_drawdown: Some(max),
+                ..
+            } if drawdown >= *max => Some(HaltReason::Drawdown(drawdown)),
+            RiskLimits {
+                max_consecutive_losses: Some(max),
+                ..
+            } if consecutive_losses >= *max => {
+                Some(HaltReason::ConsecutiveLosses(consecutive_losses))
+            }
+            _ => None,
+        };
+
+        match reason {
+            Some(reason) => {
+                log::error!("Risk limit breached: {:?}. New entries halted", reason);
+                self.halted = Some(Halt { since: now, reason });
+                GuardStatus::NewHalt {
+                    flatten: limits.flatten && is_open,
+                }
+            }
+            None => GuardStatus::Trading,
+        }
+    }
+}
+
+impl RiskGuard {
+    // Exits are added once, so trimmed trades keep counting
+    fn count_realized(&mut self, profits: &Profits) {
+        for (date, profit) in &profits.closed {
+            let counted = matches!(self.counted_until, Some(until) if *date <= until);
+            if !counted {
+                self.realized_profit += profit;
+                self.counted_until = Some(*date);
+            }
+        }
+    }
+}
+
+/// Profits in account currency
+#[derive(Debug, Default)]
+struct Profits {
+    /// Closed trades by exit date, oldest first
+    closed: Vec<(DateTime<Local>, f64)>,
+    unrealized: f64,
+}
+
+impl Profits {
+    // The open trade counts in every period
+    fn in_period(&self, in_period: impl Fn(&DateTime<Local>) -> bool) -> f64 {
+        self.closed
+            .iter()
+            .filter(|(date, _)| in_period(date))
+            .map(|(_, profit)| profit)
+            .sum::<f64>()
+            + self.unrealized
+    }
+}
+
+// TradeOut profits are price differences, sized with their TradeIn. Exits
+// whose entry is no longer kept are left out.
+fn closed_profits(
+    trades_in: &[TradeIn],
+    trades_out: &[TradeOut],
+    pip_value: f64,
+    pip_size: f64,
+) -> Vec<(DateTime<Local>, f64)> {
+    trades_out
+        .iter()
+        .filter_map(|trade_out| {
+            let trade_in = trades_in
+                .iter()
+                .find(|trade_in| trade_in.id == trade_out.id)?;
+
+            Some((
+                from_dbtime(&trade_out.date_out),
+                to_currency(trade_out.profit, trade_in.size, pip_value, pip_size),
+            ))
+        })
+        .collect()
+}
+
+fn unrealized_profit(trade_in: &TradeIn, pip_value: f64, tick: &InstrumentTick) -> f64 {
+    let diff = match trade_in.trade_type.is_long_entry() {
+        true => tick.bid() - trade_in.price_in,
+        false => trade_in.price_in - tick.ask(),
+    };
+
+    to_currency(diff, trade_in.size, pip_value, tick.pip_size())
+}
+
+fn to_currency(price_diff: f64, size: f64, pip_value: f64, pip_size: f64) -> f64 {
+    match pip_size.is_finite() && pip_size > 0. {
+        true => price_diff / pip_size * pip_value * size,
+        false => 0.,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const PIP: f64 = 0.0001;
+
+    // January 2024 starts on Monday
+    fn date(day: u32, hour: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 1, day, hour, 0, 0).unwrap()
+    }
+
+    fn limits() -> RiskLimits {
+        RiskLimits {
+            max_daily_loss: Some(100.),
+            ..RiskLimits::default()
+        }
+    }
+
+    fn profits(closed: &[(DateTime<Local>, f64)], unrealized: f64) -> Profits {
+        Profits {
+            closed: closed.to_vec(),
+            unrealized,
+        }
+    }
+
+    #[test]
+    fn price_differences_are_converted_to_currency() {
+        // 20 pips of half a lot at 10 per pip and lot
+        assert!((to_currency(20. * PIP, 0.5, 10., PIP) - 100.).abs() < 1e-9);
+        assert_eq!(to_currency(20. * PIP, 0.5, 10., 0.), 0.);
+    }
+
+    #[test]
+    fn daily_loss_counts_the_open_trade() {
+        let mut guard = RiskGuard::default();
+        let now = date(3, 12);
+
+        let status = guard.check(
+            &limits(),
+            1000.,
+            &profits(&[(date(3, 9), -60.)], -30.),
+            true,
+            now,
+        );
+        assert!(matches!(status, GuardStatus::Trading));
+
+        let status = guard.check(
+            &limits(),
+            1000.,
+            &profits(&[(date(3, 9), -60.)], -40.),
+            true,
+            now,
+        );
+        assert!(matches!(status, GuardStatus::NewHalt { flatten: false }));
+        assert_eq!(guard.halted().unwrap().reason, HaltReason::DailyLoss(-100.));
+    }
+
+    #[test]
+    fn losses_of_other_days_are_not_daily() {
+        let mut guard = RiskGuard::default();
+        let closed = [(date(2, 9), -150.)];
+
+        let status = guard.check(&limits(), 1000., &profits(&closed, 0.), false, date(3, 12));
+        assert!(matches!(status, GuardStatus::Trading));
+    }
+
+    #[test]
+    fn daily_halt_is_lifted_the_next_day() {
+        let mut guard = RiskGuard::default();
+        let closed = [(date(3, 9), -150.)];
+
+        guard.check(&limits(), 1000., &profits(&closed, 0.), false, date(3, 12));
+        let status = guard.check(&limits(), 1000., &profits(&closed, 0.), false, date(3, 13));
+        assert!(matches!(status, GuardStatus::Halted));
+
+        let status = guard.check(&limits(), 1000., &profits(&closed, 0.), false, date(4, 9));
+        assert!(matches!(status, GuardStatus::Trading));
+        assert!(guard.halted().is_none());
+    }
+
+    #[test]
+    fn drawdown_from_the_peak_needs_a_reset() {
+        let mut guard = RiskGuard::default();
+        let limits = RiskLimits {
+            max_drawdown: Some(10.),
+            flatten: true,
+            ..RiskLimits::default()
+        };
+
+        guard.check(
+            &limits,
+            1000.,
+            &profits(&[(date(1, 9), 200.)], 0.),
+            true,
+            date(1, 12),
+        );
+        // 1200 peak, 1080 is a 10% drawdown
+        let status = guard.check(
+            &limits,
+            1000.,
+            &profits(&[(date(1, 9), 200.)], -120.),
+            true,
+            date(1, 13),
+        );
+        assert!(matches!(status, GuardStatus::NewHalt { flatten: true }));
+
+        let status = guard.check(&limits, 1000., &profits(&[], 0.), false, date(15, 9));
+        assert!(matches!(status, GuardStatus::Halted));
+
+        guard.reset();
+        assert!(guard.halted().is_none());
+    }
+
+    #[test]
+    fn exits_are_realized_once() {
+        let mut guard = RiskGuard::default();
+        let closed = [(date(1, 9), 200.), (date(1, 10), -50.)];
+
+        guard.check(
+            &limits(),
+            1000.,
+            &profits(&closed[..1], 0.),
+            false,
+            date(1, 9),
+        );
+        guard.check(&limits(), 1000., &profits(&closed, 0.), false, date(1, 10));
+        guard.check(&limits(), 1000., &profits(&closed, 0.), false, date(1, 11));
+
+        assert_eq!(guard.realized_profit, 150.);
+        assert_eq!(guard.peak_equity, 1200.);
+    }
+
+    #[test]
+    fn trimmed_trades_are_not_a_drawdown() {
+        let mut guard = RiskGuard::default();
+        let limits = RiskLimits {
+            max_drawdown: Some(10.),
+            ..RiskLimits::default()
+        };
+
+        guard.check(
+            &limits,
+            1000.,
+            &profits(&[(date(1, 9), 500.)], 0.),
+            false,
+            date(1, 12),
+        );
+
+        // The profitable exit is no longer kept by the bot
+        let status = guard.check(&limits, 1000., &profits(&[], 0.), false, date(2, 9));
+        assert!(matches!(status, GuardStatus::Trading));
+
+        let restored: RiskGuard =
+            serde_json::from_value(serde_json::to_value(&guard).unwrap()).unwrap();
+        assert_eq!(restored.realized_profit, 500.);
+    }
+
+    #[test]
+    fn consecutive_losses_are_the_last_ones() {
+        let mut guard = RiskGuard::default();
+        let limits = RiskLimits {
+            max_consecutive_losses: Some(3),
+            ..RiskLimits::default()
+        };
+        let mut closed = vec![
+            (date(1, 9), -1.),
+            (date(1, 10), 5.),
+            (date(1, 11), -1.),
+            (date(1, 12), -1.),
+        ];
+
+        let status = guard.check(&limits, 1000., &profits(&closed, 0.), false, date(1, 13));
+        assert!(matches!(status, GuardStatus::Trading));
+
+        closed.push((date(1, 13), -1.));
+        let status = guard.check(&limits, 1000., &profits(&closed, 0.), false, date(1, 14));
+        assert!(matches!(status, GuardStatus::NewHalt { flatten: false }));
+    }
+
+    #[test]
+    fn disabled_limits_never_halt() {
+        let mut guard = RiskGuard::default();
+        let status = guard.check(
+            &RiskLimits::default(),
+            1000.,
+            &profits(&[], -5000.),
+            true,
+            date(3, 12),
+        );
+
+        assert!(matches!(status, GuardStatus::Trading));
+    }
+}
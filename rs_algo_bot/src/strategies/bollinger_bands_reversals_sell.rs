@@ -1,8 +1,12 @@
+use super::indicators;
+use super::params::*;
+use super::registry::*;
+use super::sizing::LotLimits;
 use super::strategy::*;
+use crate::config::BotConfig;
 
 use rs_algo_shared::error::Result;
 use rs_algo_shared::helpers::calc::{self, *};
-use rs_algo_shared::indicators::Indicator;
 use rs_algo_shared::models::order::OrderType;
 use rs_algo_shared::models::stop_loss::*;
 use rs_algo_shared::models::strategy::StrategyType;
@@ -12,6 +16,27 @@ use rs_algo_shared::models::time_frame::{TimeFrame, TimeFrameType};
 use rs_algo_shared::models::trade::{Position, TradeDirection, TradeIn};
 use rs_algo_shared::scanner::instrument::*;
 
+use serde_json::Value;
+
+pub fn descriptor() -> StrategyDescriptor {
+    StrategyDescriptor {
+        name: "BB_Reversals_Sell",
+        strategy_types: &ALL_STRATEGY_TYPES,
+        requires_htf: false,
+        params: &BOLLINGER_PARAMS,
+        factory: |name, time_frame, higher_time_frame, strategy_type, config| {
+            Ok(Box::new(BollingerBandsReversals::new(
+                Some(name),
+                Some(time_frame),
+                higher_time_frame,
+                Some(strategy_type),
+                config,
+            )?))
+        },
+        check_params: |config| BollingerParams::parse(config, ExitMode::PipsTarget).map(|_| ()),
+    }
+}
+
 #[derive(Clone)]
 pub struct BollingerBandsReversals<'a> {
     name: &'a str,
@@ -19,9 +44,8 @@ pub struct BollingerBandsReversals<'a> {
     higher_time_frame: Option<TimeFrameType>,
     strategy_type: StrategyType,
     trading_direction: TradeDirection,
-    order_size: f64,
-    risk_reward_ratio: f64,
-    profit_target: f64,
+    params: BollingerParams,
+    config: BotConfig,
 }
 
 impl<'a> Strategy for BollingerBandsReversals<'a> {
@@ -30,23 +54,10 @@ impl<'a> Strategy for BollingerBandsReversals<'a> {
         time_frame: Option<&str>,
         higher_time_frame: Option<&str>,
         strategy_type: Option<StrategyType>,
+        config: &BotConfig,
     ) -> Result<Self> {
-        let risk_reward_ratio = std::env::var("RISK_REWARD_RATIO")
-            .unwrap()
-            .parse::<f64>()
-            .unwrap();
-
-        let profit_target = std::env::var("PIPS_PROFIT_TARGET")
-            .unwrap()
-            .parse::<f64>()
-            .unwrap();
-
-        let base_time_frame = &std::env::var("TIME_FRAME")
-            .unwrap()
-            .parse::<String>()
-            .unwrap();
-
-        let order_size = std::env::var("ORDER_SIZE").unwrap().parse::<f64>().unwrap();
+        let params = BollingerParams::load(config, ExitMode::PipsTarget)?;
+        let base_time_frame = &config.time_frame;
 
         let name = name.unwrap_or("Bollinger_Bands_Reversals");
 
@@ -63,12 +74,7 @@ impl<'a> Strategy for BollingerBandsReversals<'a> {
         let higher_time_frame = match higher_time_frame {
             Some(htf) => Some(TimeFrame::new(htf)),
             None => match strategy_type.is_multi_timeframe() {
-                true => Some(TimeFrame::new(
-                    &std::env::var("HIGHER_TIME_FRAME")
-                        .unwrap()
-                        .parse::<String>()
-                        .unwrap(),
-                )),
+                true => Some(TimeFrame::new(&config.higher_time_frame)),
                 false => None,
             },
         };
@@ -81,9 +87,8 @@ impl<'a> Strategy for BollingerBandsReversals<'a> {
             higher_time_frame,
             strategy_type,
             trading_direction,
-            order_size,
-            risk_reward_ratio,
-            profit_target,
+            params,
+            config: config.clone(),
         })
     }
 
@@ -91,6 +96,29 @@ impl<'a> Strategy for BollingerBandsReversals<'a> {
         self.name
     }
 
+    fn config(&self) -> &BotConfig {
+        &self.config
+    }
+
+    fn set_lot_limits(&mut self, limits: &LotLimits) {
+        self.config.position_sizing.apply_limits(limits);
+    }
+
+    fn params(&self) -> Value {
+        self.params.to_value()
+    }
+
+    fn take_profit_price(
+        &self,
+        is_long: bool,
+        entry_price: f64,
+        stop_price: Option<f64>,
+        tick: &InstrumentTick,
+    ) -> Option<f64> {
+        self.params
+            .take_profit_price(is_long, entry_price, stop_price, tick)
+    }
+
     fn strategy_type(&self) -> &StrategyType {
         &self.strategy_type
     }
@@ -113,27 +141,15 @@ impl<'a> Strategy for BollingerBandsReversals<'a> {
         instrument: &Instrument,
         htf_instrument: &HTFInstrument,
     ) -> &TradeDirection {
+        let (ema_a_len, ema_b_len) = (self.params.ema_a, self.params.ema_b);
+
         self.trading_direction = time_frame::get_htf_trading_direction(
             index,
             instrument,
             htf_instrument,
             |(idx, _prev_idx, htf_inst)| {
-                let htf_ema_a = htf_inst
-                    .indicators
-                    .ema_a
-                    .as_ref()
-                    .unwrap()
-                    .get_data_a()
-                    .get(idx)
-                    .unwrap();
-                let htf_ema_b = htf_inst
-                    .indicators
-                    .ema_b
-                    .as_ref()
-                    .unwrap()
-                    .get_data_a()
-                    .get(idx)
-                    .unwrap();
+                let htf_ema_a = &indicators::ema(htf_inst.data(), idx, ema_a_len);
+                let htf_ema_b = &indicators::ema(htf_inst.data(), idx, ema_b_len);
 
                 let is_long = htf_ema_a > htf_ema_b;
                 let is_short = htf_ema_a < htf_ema_b;
@@ -165,53 +181,33 @@ impl<'a> Strategy for BollingerBandsReversals<'a> {
         let close_price = &candle.close();
         let prev_close_price = &prev_candle.close();
 
-        let low_band = instrument
-            .indicators
-            .bb
-            .as_ref()
-            .unwrap()
-            .get_data_b()
-            .get(index)
-            .unwrap();
-
-        let atr_value = instrument
-            .indicators
-            .atr
-            .as_ref()
-            .unwrap()
-            .get_data_a()
-            .get(index)
-            .unwrap();
-
-        let prev_low_band = instrument
-            .indicators
-            .bb
-            .as_ref()
-            .unwrap()
-            .get_data_b()
-            .get(prev_index)
-            .unwrap();
+        let low_band = &self.params.bands(instrument, index).low;
+
+        let atr_value = match indicators::atr(index, instrument) {
+            Some(atr_value) => atr_value,
+            None => return Position::None,
+        };
+
+        let prev_low_band = &self.params.bands(instrument, prev_index).low;
 
         let entry_condition =
             candle.is_closed() && close_price < low_band && (prev_close_price > prev_low_band);
 
-        let atr_stoploss = std::env::var("ATR_STOPLOSS")
-            .unwrap()
-            .parse::<f64>()
-            .unwrap();
-
-        let pips_margin = std::env::var("PIPS_MARGIN")
-            .unwrap()
-            .parse::<f64>()
-            .unwrap();
+        let atr_stoploss = self.params.atr_stoploss;
+        let pips_margin = self.params.pips_margin;
 
         let buy_price = close_price + to_pips(pips_margin, tick);
 
+        let order_size =
+            self.position_size(index, instrument, Some(atr_value * atr_stoploss), tick);
+
+        let orders = vec![
+            OrderType::BuyOrderLong(order_size, buy_price),
+            OrderType::StopLossLong(StopLossType::Atr(atr_stoploss), buy_price),
+        ];
+
         match entry_condition {
-            true => Position::Order(vec![
-                OrderType::BuyOrderLong(self.order_size, buy_price),
-                OrderType::StopLossLong(StopLossType::Atr(atr_stoploss), buy_price),
-            ]),
+            true => Position::Order(orders),
 
             false => Position::None,
         }
@@ -225,10 +221,26 @@ impl<'a> Strategy for BollingerBandsReversals<'a> {
         trade_in: &TradeIn,
         tick: &InstrumentTick,
     ) -> Position {
-        let price_in = trade_in.price_in;
-        let pips_profit = 5.;
-        let sell_price = price_in + to_pips(pips_profit, tick);
-        let exit_condition = tick.bid() > sell_price;
+        let data = &instrument.data();
+        let prev_index = get_prev_index(index);
+        let candle = data.get(index).unwrap();
+        let prev_candle = &data.get(prev_index).unwrap();
+        let price = &candle.close();
+        let prev_close_price = &prev_candle.close();
+
+        let top_band = &self.params.bands(instrument, index).top;
+        let prev_top_band = &self.params.bands(instrument, prev_index).top;
+
+        let band_cross = price < top_band && (prev_close_price > prev_top_band);
+
+        let exit_condition = match self.params.exit_mode {
+            ExitMode::BandCross => band_cross,
+            ExitMode::BandCrossOnClose => candle.is_closed() && band_cross,
+            ExitMode::PipsTarget => {
+                tick.bid() > trade_in.price_in + to_pips(self.params.pips_profit_target, tick)
+            }
+        };
+
         match exit_condition {
             true => Position::MarketOut(None),
             false => Position::None,
@@ -242,7 +254,6 @@ impl<'a> Strategy for BollingerBandsReversals<'a> {
         _htf_instrument: &HTFInstrument,
         tick: &InstrumentTick,
     ) -> Position {
-        log::info!("222222 {:?}", tick);
         let data = &instrument.data();
         let prev_index = get_prev_index(index);
         let candle = data.get(index).unwrap();
@@ -250,57 +261,33 @@ impl<'a> Strategy for BollingerBandsReversals<'a> {
         let close_price = &candle.close();
         let prev_close_price = &prev_candle.close();
 
-        let top_band = instrument
-            .indicators
-            .bb
-            .as_ref()
-            .unwrap()
-            .get_data_a()
-            .get(index)
-            .unwrap();
-
-        let prev_top_band = instrument
-            .indicators
-            .bb
-            .as_ref()
-            .unwrap()
-            .get_data_a()
-            .get(prev_index)
-            .unwrap();
-
-        let atr_value = instrument
-            .indicators
-            .atr
-            .as_ref()
-            .unwrap()
-            .get_data_a()
-            .get(index)
-            .unwrap();
+        let top_band = &self.params.bands(instrument, index).top;
+
+        let prev_top_band = &self.params.bands(instrument, prev_index).top;
+
+        let atr_value = match indicators::atr(index, instrument) {
+            Some(atr_value) => atr_value,
+            None => return Position::None,
+        };
 
         let entry_condition =
             candle.is_closed() && close_price > top_band && (prev_close_price < prev_top_band);
 
-        let pips_margin = std::env::var("PIPS_MARGIN")
-            .unwrap()
-            .parse::<f64>()
-            .unwrap();
+        let pips_margin = self.params.pips_margin;
+        let atr_stoploss = self.params.atr_stoploss;
+        let atr_profit_target = self.config.atr_profit_target;
 
-        let atr_stoploss = std::env::var("ATR_STOPLOSS")
-            .unwrap()
-            .parse::<f64>()
-            .unwrap();
+        let buy_price = close_price - to_pips(pips_margin, tick);
+        let order_size =
+            self.position_size(index, instrument, Some(atr_value * atr_stoploss), tick);
 
-        let atr_profit_target = std::env::var("ATR_PROFIT_TARGET")
-            .unwrap()
-            .parse::<f64>()
-            .unwrap();
+        let orders = vec![
+            OrderType::BuyOrderShort(order_size, buy_price),
+            OrderType::StopLossShort(StopLossType::Atr(atr_stoploss), buy_price),
+        ];
 
-        let buy_price = close_price - to_pips(pips_margin, tick);
         match entry_condition {
-            true => Position::Order(vec![
-                OrderType::BuyOrderShort(self.order_size, buy_price),
-                OrderType::StopLossShort(StopLossType::Atr(atr_stoploss), buy_price),
-            ]),
+            true => Position::Order(orders),
 
             false => Position::None,
         }
@@ -314,10 +301,26 @@ impl<'a> Strategy for BollingerBandsReversals<'a> {
         trade_in: &TradeIn,
         tick: &InstrumentTick,
     ) -> Position {
-        let price_in = trade_in.price_in;
-        let pips_profit = 5.;
-        let sell_price = price_in - to_pips(pips_profit, tick);
-        let exit_condition = tick.bid() < sell_price;
+        let data = &instrument.data();
+        let prev_index = get_prev_index(index);
+        let candle = data.get(index).unwrap();
+        let prev_candle = &data.get(prev_index).unwrap();
+        let price = &candle.close();
+        let prev_close_price = &prev_candle.close();
+
+        let low_band = &self.params.bands(instrument, index).low;
+        let prev_low_band = &self.params.bands(instrument, prev_index).low;
+
+        let band_cross = (price > low_band) && (prev_close_price < prev_low_band);
+
+        let exit_condition = match self.params.exit_mode {
+            ExitMode::BandCross => band_cross,
+            ExitMode::BandCrossOnClose => candle.is_closed() && band_cross,
+            ExitMode::PipsTarget => {
+                tick.bid() < trade_in.price_in - to_pips(self.params.pips_profit_target, tick)
+            }
+        };
+
         match exit_condition {
             true => Position::MarketOut(None),
             false => Position::None,
@@ -0,0 +1,127 @@
+use rs_algo_shared::indicators::Indicator;
+use rs_algo_shared::scanner::candle::Candle;
+use rs_algo_shared::scanner::instrument::Instrument;
+
+/// Bollinger Bands of a candle. NaN until there are enough candles, so no
+/// price crosses them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bands {
+    pub top: f64,
+    pub mid: f64,
+    pub low: f64,
+}
+
+// Instrument indicators are configured by rs_algo_shared from env vars, the
+// ones the strategy params set are calculated here instead
+pub fn bollinger_bands(data: &[Candle], index: usize, period: usize, deviation: f64) -> Bands {
+    let closes = match window(data, index, period) {
+        Some(candles) => candles
+            .iter()
+            .map(|candle| candle.close())
+            .collect::<Vec<f64>>(),
+        None => {
+            return Bands {
+                top: f64::NAN,
+                mid: f64::NAN,
+                low: f64::NAN,
+            }
+        }
+    };
+
+    bands(&closes, deviation)
+}
+
+fn bands(closes: &[f64], deviation: f64) -> Bands {
+    let len = closes.len() as f64;
+    let mid = closes.iter().sum::<f64>() / len;
+    let variance = closes
+        .iter()
+        .map(|close| (close - mid).powi(2))
+        .sum::<f64>()
+        / len;
+    let width = variance.sqrt() * deviation;
+
+    Bands {
+        top: mid + width,
+        mid,
+        low: mid - width,
+    }
+}
+
+/// EMA of the closes up to `index`, seeded with the SMA of the first `length`
+/// ones. NaN until there are enough candles.
+pub fn ema(data: &[Candle], index: usize, length: usize) -> f64 {
+    match window(data, index, index + 1) {
+        Some(candles) if length > 0 && candles.len() >= length => {
+            let closes: Vec<f64> = candles.iter().map(|candle| candle.close()).collect();
+            ema_of(&closes, length)
+        }
+        _ => f64::NAN,
+    }
+}
+
+fn ema_of(closes: &[f64], length: usize) -> f64 {
+    let alpha = 2. / (length as f64 + 1.);
+    let seed = closes[..length].iter().sum::<f64>() / length as f64;
+
+    closes[length..]
+        .iter()
+        .fold(seed, |ema, close| alpha * close + (1. - alpha) * ema)
+}
+
+/// ATR of the instrument at `index`, None until it is calculated
+pub fn atr(index: usize, instrument: &Instrument) -> Option<f64> {
+    instrument
+        .indicators
+        .atr
+        .as_ref()?
+        .get_data_a()
+        .get(index)
+        .copied()
+}
+
+fn window(data: &[Candle], index: usize, period: usize) -> Option<&[Candle]> {
+    match period > 0 && index < data.len() && index + 1 >= period {
+        true => Some(&data[index + 1 - period..=index]),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flat_closes_have_no_width() {
+        let bands = bands(&[1.5, 1.5, 1.5, 1.5], 2.);
+        assert_eq!(
+            bands,
+            Bands {
+                top: 1.5,
+                mid: 1.5,
+                low: 1.5
+            }
+        );
+    }
+
+    #[test]
+    fn band_width_is_the_population_deviation() {
+        // mean 5, population deviation 2
+        let bands = bands(&[2., 4., 4., 4., 5., 5., 7., 9.], 2.);
+        assert_eq!((bands.low, bands.mid, bands.top), (1., 5., 9.));
+    }
+
+    #[test]
+    fn ema_is_seeded_with_the_sma() {
+        assert_eq!(ema_of(&[1., 2., 3.], 3), 2.);
+        // alpha 0.5: 0.5 * 6 + 0.5 * 2
+        assert_eq!(ema_of(&[1., 2., 3., 6.], 3), 4.);
+    }
+
+    #[test]
+    fn not_enough_candles() {
+        let bands = bollinger_bands(&[], 0, 20, 2.);
+        assert!(bands.top.is_nan() && bands.low.is_nan());
+        assert!(ema(&[], 0, 8).is_nan());
+    }
+}
@@ -1,4 +1,18 @@
 pub mod bollinger_bands_reversals;
 pub mod bollinger_bands_reversals_close;
 pub mod bollinger_bands_reversals_sell;
+pub mod indicators;
+pub mod params;
+pub mod registry;
+pub mod sizing;
+pub mod stops;
 pub mod strategy;
+
+use registry::StrategyDescriptor;
+
+/// Strategies available to bots. New strategies are added here
+pub const STRATEGIES: [fn() -> StrategyDescriptor; 3] = [
+    bollinger_bands_reversals::descriptor,
+    bollinger_bands_reversals_close::descriptor,
+    bollinger_bands_reversals_sell::descriptor,
+];
@@ -0,0 +1,239 @@
+use super::indicators::{self, Bands};
+use super::registry::{ParamDescriptor, ParamKind};
+use crate::config::BotConfig;
+
+use rs_algo_shared::error::{Result, RsAlgoError, RsAlgoErrorKind};
+use rs_algo_shared::helpers::calc::to_pips;
+use rs_algo_shared::helpers::date::Local;
+use rs_algo_shared::models::tick::InstrumentTick;
+use rs_algo_shared::scanner::instrument::Instrument;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExitMode {
+    /// Exit as soon as the price crosses back the opposite band
+    BandCross,
+    /// Same as BandCross but waiting for the candle to close
+    BandCrossOnClose,
+    /// Exit once the price moves pips_profit_target pips in favour
+    PipsTarget,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TakeProfitMode {
+    Disabled,
+    /// Stop distance times risk_reward_ratio
+    RiskReward,
+    /// Fixed pips_profit_target pips
+    Pips,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BollingerParams {
+    pub bb_period: usize,
+    pub bb_deviation: f64,
+    pub ema_a: usize,
+    pub ema_b: usize,
+    pub atr_stoploss: f64,
+    pub pips_margin: f64,
+    pub pips_profit_target: f64,
+    pub exit_mode: ExitMode,
+    pub take_profit: TakeProfitMode,
+    pub risk_reward_ratio: f64,
+}
+
+pub static BOLLINGER_PARAMS: [ParamDescriptor; 10] = [
+    ParamDescriptor {
+        key: "bb_period",
+        kind: ParamKind::Integer,
+        description: "Bollinger Bands period",
+    },
+    ParamDescriptor {
+        key: "bb_deviation",
+        kind: ParamKind::Float,
+        description: "Bollinger Bands standard deviation multiplier",
+    },
+    ParamDescriptor {
+        key: "ema_a",
+        kind: ParamKind::Integer,
+        description: "Fast EMA length of the HTF filter",
+    },
+    ParamDescriptor {
+        key: "ema_b",
+        kind: ParamKind::Integer,
+        description: "Slow EMA length of the HTF filter",
+    },
+    ParamDescriptor {
+        key: "atr_stoploss",
+        kind: ParamKind::Float,
+        description: "ATR multiplier for the stop loss",
+    },
+    ParamDescriptor {
+        key: "pips_margin",
+        kind: ParamKind::Float,
+        description: "Pips between the close price and the entry order",
+    },
+    ParamDescriptor {
+        key: "pips_profit_target",
+        kind: ParamKind::Float,
+        description: "Profit target in pips, used by the PipsTarget exit mode",
+    },
+    ParamDescriptor {
+        key: "exit_mode",
+        kind: ParamKind::Text,
+        description: "BandCross, BandCrossOnClose or PipsTarget",
+    },
+    ParamDescriptor {
+        key: "take_profit",
+        kind: ParamKind::Text,
+        description: "Take profit order attached to entries: Disabled, RiskReward or Pips",
+    },
+    ParamDescriptor {
+        key: "risk_reward_ratio",
+        kind: ParamKind::Float,
+        description: "Take profit distance as a multiple of the stop distance",
+    },
+];
+
+impl BollingerParams {
+    /// Params not set in the strategy_params table fall back to the bot
+    /// config. BB_PERIOD, BB_MULTIPLIER, EMA_A and EMA_B are merged into the
+    /// table by the config.
+    pub fn load(config: &BotConfig, exit_mode: ExitMode) -> Result<Self> {
+        Self::parse(config, exit_mode).map_err(|err| {
+            log::error!("Invalid strategy params: {}", err);
+            RsAlgoError {
+                err: RsAlgoErrorKind::WrongInstrumentConf,
+            }
+        })
+    }
+
+    pub fn parse(config: &BotConfig, exit_mode: ExitMode) -> std::result::Result<Self, String> {
+        let defaults = Self {
+            bb_period: 20,
+            bb_deviation: 2.,
+            ema_a: 8,
+            ema_b: 13,
+            atr_stoploss: config.atr_stoploss,
+            pips_margin: config.pips_margin,
+            pips_profit_target: config.pips_profit_target,
+            exit_mode,
+            take_profit: TakeProfitMode::Disabled,
+            risk_reward_ratio: config.risk_reward_ratio,
+        };
+
+        let mut params = serde_json::to_value(defaults).unwrap();
+        if let (Some(params), Some(overrides)) =
+            (params.as_object_mut(), config.strategy_params.as_object())
+        {
+            for (key, value) in overrides {
+                params.insert(key.to_owned(), value.clone());
+            }
+        }
+
+        let params: Self = serde_json::from_value(params).map_err(|err| err.to_string())?;
+
+        match (params.bb_period, params.ema_a, params.ema_b) {
+            (0, _, _) | (_, 0, _) | (_, _, 0) => {
+                Err("bb_period, ema_a and ema_b must be greater than 0".to_owned())
+            }
+            _ if params.bb_deviation <= 0. => Err("bb_deviation must be positive".to_owned()),
+            _ => Ok(params),
+        }
+    }
+
+    pub fn bands(&self, instrument: &Instrument, index: usize) -> Bands {
+        indicators::bollinger_bands(instrument.data(), index, self.bb_period, self.bb_deviation)
+    }
+
+    /// Take profit price of an entry at `entry_price`. Risk reward targets
+    /// are a multiple of the distance to its stop loss.
+    pub fn take_profit_price(
+        &self,
+        is_long: bool,
+        entry_price: f64,
+        stop_price: Option<f64>,
+        tick: &InstrumentTick,
+    ) -> Option<f64> {
+        let distance = match (self.take_profit, stop_price) {
+            (TakeProfitMode::Disabled, _) | (TakeProfitMode::RiskReward, None) => return None,
+            (TakeProfitMode::RiskReward, Some(stop_price)) => {
+                (entry_price - stop_price).abs() * self.risk_reward_ratio
+            }
+            (TakeProfitMode::Pips, _) => to_pips(self.pips_profit_target, tick),
+        };
+
+        match is_long {
+            true => Some(entry_price + distance),
+            false => Some(entry_price - distance),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+/// Params a bot has run with, so trades can be matched with the set that
+/// produced them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParamSet {
+    pub since: String,
+    pub params: Value,
+}
+
+pub fn track_params(history: &mut Vec<ParamSet>, params: Value) {
+    if history.last().map(|set| &set.params) != Some(&params) {
+        history.push(ParamSet {
+            since: Local::now().to_string(),
+            params,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(take_profit: TakeProfitMode) -> BollingerParams {
+        BollingerParams {
+            bb_period: 20,
+            bb_deviation: 2.,
+            ema_a: 8,
+            ema_b: 13,
+            atr_stoploss: 2.,
+            pips_margin: 1.,
+            pips_profit_target: 10.,
+            exit_mode: ExitMode::BandCross,
+            take_profit,
+            risk_reward_ratio: 1.5,
+        }
+    }
+
+    #[test]
+    fn risk_reward_take_profit_from_the_stop() {
+        let params = params(TakeProfitMode::RiskReward);
+        let tick = InstrumentTick::default();
+
+        assert_eq!(
+            params.take_profit_price(true, 1.1, Some(1.09), &tick),
+            Some(1.1 + (1.1f64 - 1.09).abs() * 1.5)
+        );
+        assert_eq!(
+            params.take_profit_price(false, 1.1, Some(1.12), &tick),
+            Some(1.1 - (1.12f64 - 1.1).abs() * 1.5)
+        );
+        assert_eq!(params.take_profit_price(true, 1.1, None, &tick), None);
+    }
+
+    #[test]
+    fn disabled_take_profit() {
+        let params = params(TakeProfitMode::Disabled);
+        let tick = InstrumentTick::default();
+
+        assert_eq!(params.take_profit_price(true, 1.1, Some(1.09), &tick), None);
+    }
+}
@@ -0,0 +1,199 @@
+use super::strategy::Strategy;
+use crate::config::BotConfig;
+use crate::strategies;
+
+use rs_algo_shared::error::Result;
+use rs_algo_shared::models::strategy::StrategyType;
+
+use serde::Serialize;
+use thiserror::Error;
+
+pub const ALL_STRATEGY_TYPES: [StrategyType; 6] = [
+    StrategyType::OnlyLong,
+    StrategyType::OnlyShort,
+    StrategyType::LongShort,
+    StrategyType::OnlyLongMTF,
+    StrategyType::OnlyShortMTF,
+    StrategyType::LongShortMTF,
+];
+
+pub type StrategyFactory =
+    fn(&'static str, &str, Option<&str>, StrategyType, &BotConfig) -> Result<Box<dyn Strategy>>;
+
+pub type ParamsCheck = fn(&BotConfig) -> std::result::Result<(), String>;
+
+#[derive(Debug, Error)]
+pub enum StrategyError {
+    #[error("Strategy {0} not found. Available strategies: {1:?}")]
+    NotFound(String, Vec<&'static str>),
+    #[error("Strategy {0} doesn't support {1}")]
+    UnsupportedType(String, String),
+    #[error("Strategy {0} requires a MTF strategy type")]
+    MissingHTF(String),
+    #[error("Strategy {0} can't be built: {1}")]
+    Build(String, String),
+    #[error("Strategy {0} params are invalid: {1}")]
+    InvalidParams(String, String),
+    #[error("Strategy {0} registered twice")]
+    Duplicated(String),
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub enum ParamKind {
+    Float,
+    Integer,
+    Text,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ParamDescriptor {
+    pub key: &'static str,
+    pub kind: ParamKind,
+    pub description: &'static str,
+}
+
+#[derive(Clone, Serialize)]
+pub struct StrategyDescriptor {
+    pub name: &'static str,
+    pub strategy_types: &'static [StrategyType],
+    pub requires_htf: bool,
+    pub params: &'static [ParamDescriptor],
+    #[serde(skip_serializing)]
+    pub factory: StrategyFactory,
+    #[serde(skip_serializing)]
+    pub check_params: ParamsCheck,
+}
+
+#[derive(Default)]
+pub struct StrategyRegistry {
+    descriptors: Vec<StrategyDescriptor>,
+}
+
+impl StrategyRegistry {
+    pub fn new() -> Self {
+        let mut registry = Self::default();
+
+        // The first one registered is kept
+        for descriptor in strategies::STRATEGIES {
+            if let Err(err) = registry.register(descriptor()) {
+                log::error!("{}", err);
+            }
+        }
+
+        registry
+    }
+
+    pub fn register(
+        &mut self,
+        descriptor: StrategyDescriptor,
+    ) -> std::result::Result<(), StrategyError> {
+        match self.find(descriptor.name) {
+            Some(_) => Err(StrategyError::Duplicated(descriptor.name.to_owned())),
+            None => {
+                self.descriptors.push(descriptor);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn descriptors(&self) -> &[StrategyDescriptor] {
+        &self.descriptors
+    }
+
+    pub fn find(&self, name: &str) -> Option<&StrategyDescriptor> {
+        self.descriptors
+            .iter()
+            .find(|descriptor| descriptor.name == name)
+    }
+
+    pub fn validate(
+        &self,
+        name: &str,
+        strategy_type: &StrategyType,
+    ) -> std::result::Result<&StrategyDescriptor, StrategyError> {
+        let descriptor = self.find(name).ok_or_else(|| {
+            StrategyError::NotFound(
+                name.to_owned(),
+                self.descriptors
+                    .iter()
+                    .map(|descriptor| descriptor.name)
+                    .collect(),
+            )
+        })?;
+
+        if !descriptor.strategy_types.contains(strategy_type) {
+            return Err(StrategyError::UnsupportedType(
+                name.to_owned(),
+                strategy_type.to_string(),
+            ));
+        }
+
+        if descriptor.requires_htf && !strategy_type.is_multi_timeframe() {
+            return Err(StrategyError::MissingHTF(name.to_owned()));
+        }
+
+        Ok(descriptor)
+    }
+
+    /// Validates the strategy and its params without building it
+    pub fn validate_params(
+        &self,
+        name: &str,
+        strategy_type: &StrategyType,
+        config: &BotConfig,
+    ) -> std::result::Result<(), StrategyError> {
+        let descriptor = self.validate(name, strategy_type)?;
+
+        (descriptor.check_params)(config)
+            .map_err(|err| StrategyError::InvalidParams(name.to_owned(), err))
+    }
+
+    pub fn build(
+        &self,
+        name: &str,
+        time_frame: &str,
+        higher_time_frame: Option<&str>,
+        strategy_type: StrategyType,
+        config: &BotConfig,
+    ) -> std::result::Result<Box<dyn Strategy>, StrategyError> {
+        let descriptor = self.validate(name, &strategy_type)?;
+
+        (descriptor.factory)(
+            descriptor.name,
+            time_frame,
+            higher_time_frame,
+            strategy_type,
+            config,
+        )
+        .map_err(|err| StrategyError::Build(name.to_owned(), err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strategies_are_registered_once() {
+        let mut registry = StrategyRegistry::default();
+
+        for descriptor in strategies::STRATEGIES {
+            registry.register(descriptor()).unwrap();
+        }
+
+        assert_eq!(registry.descriptors().len(), strategies::STRATEGIES.len());
+    }
+
+    #[test]
+    fn duplicated_strategy_is_refused() {
+        let mut registry = StrategyRegistry::new();
+        let descriptor = strategies::STRATEGIES[0]();
+        let name = descriptor.name;
+
+        match registry.register(descriptor) {
+            Err(StrategyError::Duplicated(duplicated)) => assert_eq!(duplicated, name),
+            _ => panic!("{} registered twice", name),
+        }
+        assert_eq!(registry.descriptors().len(), strategies::STRATEGIES.len());
+    }
+}
@@ -0,0 +1,223 @@
+use rs_algo_shared::models::order::{Order, OrderStatus};
+use rs_algo_shared::models::trade::TradeIn;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SizingMode {
+    /// Always order_size lots
+    FixedLot,
+    /// Risk N% of the equity between the entry and the stop loss
+    FixedFractional(f64),
+    /// Scale order_size so a move of N pips equals one ATR
+    Volatility(f64),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PositionSizing {
+    pub mode: SizingMode,
+    /// Account currency value of one pip for one lot
+    pub pip_value: f64,
+    pub lot_step: f64,
+    pub min_size: f64,
+    pub max_size: f64,
+}
+
+impl Default for PositionSizing {
+    fn default() -> Self {
+        Self {
+            mode: SizingMode::FixedLot,
+            pip_value: 10.,
+            lot_step: 0.01,
+            min_size: 0.01,
+            max_size: 100.,
+        }
+    }
+}
+
+/// Lot limits of the symbol at the broker, which replace the configured ones
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LotLimits {
+    pub lot_step: f64,
+    pub min_size: f64,
+    pub max_size: f64,
+}
+
+/// Risk of an entry, stored by trade id so stats can be expressed in units of
+/// risk. Its size is the one of the TradeIn.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TradeRisk {
+    pub trade_id: usize,
+    pub stop_distance: f64,
+    pub risk: f64,
+}
+
+impl PositionSizing {
+    pub fn size(
+        &self,
+        order_size: f64,
+        equity: f64,
+        stop_distance: Option<f64>,
+        atr_value: Option<f64>,
+        pip_size: f64,
+    ) -> f64 {
+        if !valid_pip_size(pip_size) {
+            log::warn!("Invalid pip size {}. Using order_size", pip_size);
+            return self.round(order_size);
+        }
+
+        let size = match (self.mode, stop_distance, atr_value) {
+            (SizingMode::FixedFractional(risk_per), Some(distance), _) if distance > 0. => {
+                let stop_pips = distance / pip_size;
+                equity * risk_per / 100. / (stop_pips * self.pip_value)
+            }
+            (SizingMode::Volatility(target_pips), _, Some(atr)) if atr > 0. => {
+                order_size * target_pips / (atr / pip_size)
+            }
+            (SizingMode::FixedLot, _, _) => order_size,
+            (mode, _, _) => {
+                log::warn!("{:?} sizing not possible. Using order_size", mode);
+                order_size
+            }
+        };
+
+        self.round(size)
+    }
+
+    /// Account currency lost if the stop is hit
+    pub fn risk(&self, size: f64, stop_distance: f64, pip_size: f64) -> f64 {
+        match valid_pip_size(pip_size) {
+            true => size * stop_distance / pip_size * self.pip_value,
+            false => 0.,
+        }
+    }
+
+    pub fn trade_risk(&self, trade_in: &TradeIn, orders: &[Order], pip_size: f64) -> TradeRisk {
+        let stop_distance = orders
+            .iter()
+            .filter(|order| order.trade_id == trade_in.id)
+            .find(|order| order.order_type.is_stop() && order.status == OrderStatus::Pending)
+            .map(|stop| (trade_in.price_in - stop.target_price).abs())
+            .unwrap_or(0.);
+
+        TradeRisk {
+            trade_id: trade_in.id,
+            stop_distance,
+            risk: self.risk(trade_in.size, stop_distance, pip_size),
+        }
+    }
+
+    pub fn apply_limits(&mut self, limits: &LotLimits) {
+        self.lot_step = limits.lot_step;
+        self.min_size = limits.min_size;
+        self.max_size = limits.max_size;
+    }
+
+    fn round(&self, size: f64) -> f64 {
+        // Epsilon avoids 0.3 / 0.01 flooring to 29 steps
+        let steps = (size / self.lot_step + 1e-9).floor();
+        (steps * self.lot_step).clamp(self.min_size, self.max_size)
+    }
+}
+
+fn valid_pip_size(pip_size: f64) -> bool {
+    pip_size.is_finite() && pip_size > 0.
+}
+
+/// Risks of trades no longer kept, dropped with them
+pub fn retain_trades(trade_risks: &mut Vec<TradeRisk>, trades_in: &[TradeIn]) {
+    trade_risks.retain(|risk| {
+        trades_in
+            .iter()
+            .any(|trade_in| trade_in.id == risk.trade_id)
+    });
+}
+
+pub fn average_risk(trade_risks: &[TradeRisk]) -> f64 {
+    match trade_risks.len() {
+        0 => 0.,
+        len => trade_risks.iter().map(|trade| trade.risk).sum::<f64>() / len as f64,
+    }
+}
+
+/// Distance from `price` to the stop among the orders of a new entry
+pub fn stop_distance(price: f64, orders: &[Order]) -> Option<f64> {
+    orders
+        .iter()
+        .find(|order| order.order_type.is_stop() && order.status == OrderStatus::Pending)
+        .map(|stop| (price - stop.target_price).abs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIP: f64 = 0.0001;
+
+    fn sizing(mode: SizingMode) -> PositionSizing {
+        PositionSizing {
+            mode,
+            ..PositionSizing::default()
+        }
+    }
+
+    #[test]
+    fn sizes_are_floored_to_the_lot_step() {
+        let sizing = sizing(SizingMode::FixedLot);
+
+        assert_eq!(sizing.size(0.3, 1000., None, None, PIP), 0.3);
+        assert_eq!(sizing.size(0.299, 1000., None, None, PIP), 0.29);
+        assert_eq!(sizing.size(0.001, 1000., None, None, PIP), 0.01);
+        assert_eq!(sizing.size(500., 1000., None, None, PIP), 100.);
+    }
+
+    #[test]
+    fn fixed_fractional_risks_the_equity_percent() {
+        let sizing = sizing(SizingMode::FixedFractional(1.));
+
+        // 1% of 10000 over a 20 pips stop at 10 per pip and lot
+        assert_eq!(sizing.size(1., 10000., Some(20. * PIP), None, PIP), 0.5);
+        // Without stop, order_size
+        assert_eq!(sizing.size(0.2, 10000., None, None, PIP), 0.2);
+    }
+
+    #[test]
+    fn volatility_scales_the_order_size() {
+        let sizing = sizing(SizingMode::Volatility(10.));
+        assert_eq!(sizing.size(1., 10000., None, Some(20. * PIP), PIP), 0.5);
+    }
+
+    #[test]
+    fn invalid_pip_size_uses_the_order_size() {
+        let sizing = sizing(SizingMode::FixedFractional(1.));
+
+        for pip_size in [0., -PIP, f64::NAN] {
+            assert_eq!(
+                sizing.size(0.2, 10000., Some(20. * PIP), None, pip_size),
+                0.2
+            );
+            assert_eq!(sizing.risk(0.2, 20. * PIP, pip_size), 0.);
+        }
+    }
+
+    #[test]
+    fn risk_in_account_currency() {
+        let sizing = sizing(SizingMode::FixedLot);
+        assert!((sizing.risk(0.5, 20. * PIP, PIP) - 100.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn broker_limits_replace_the_configured_ones() {
+        let mut sizing = sizing(SizingMode::FixedLot);
+        sizing.apply_limits(&LotLimits {
+            lot_step: 0.1,
+            min_size: 0.1,
+            max_size: 5.,
+        });
+
+        assert_eq!(sizing.size(0.25, 1000., None, None, PIP), 0.2);
+        assert_eq!(sizing.size(0.05, 1000., None, None, PIP), 0.1);
+        assert_eq!(sizing.size(7., 1000., None, None, PIP), 5.);
+    }
+}
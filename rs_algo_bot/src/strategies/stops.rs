@@ -0,0 +1,248 @@
+use super::indicators;
+
+use rs_algo_shared::helpers::calc::to_pips;
+use rs_algo_shared::models::order::{Order, OrderStatus};
+use rs_algo_shared::models::tick::InstrumentTick;
+use rs_algo_shared::models::trade::TradeIn;
+use rs_algo_shared::scanner::instrument::Instrument;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum StopTrigger {
+    Pips(f64),
+    Atr(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TrailingStop {
+    /// Trails the price by N x ATR
+    Atr(f64),
+    /// Trails the lowest low / highest high of the last N candles
+    Swing(usize),
+}
+
+/// Moves the stop of the open trade. Stops are only moved in favour of the
+/// trade and never past the current price.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StopManagement {
+    pub break_even: Option<StopTrigger>,
+    pub trailing: Option<TrailingStop>,
+    pub on_tick: bool,
+}
+
+impl StopManagement {
+    pub fn is_enabled(&self) -> bool {
+        self.break_even.is_some() || self.trailing.is_some()
+    }
+
+    pub fn update_stop(
+        &self,
+        index: usize,
+        instrument: &Instrument,
+        trade_in: &TradeIn,
+        orders: &[Order],
+        tick: &InstrumentTick,
+    ) -> Option<Order> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let stop = orders
+            .iter()
+            .find(|order| order.order_type.is_stop() && order.status == OrderStatus::Pending)?;
+
+        let atr_value = indicators::atr(index, instrument)?;
+        let is_long = trade_in.trade_type.is_long_entry();
+
+        let swing = match self.trailing {
+            Some(TrailingStop::Swing(lookback)) => {
+                let candles = &instrument.data[index.saturating_sub(lookback)..index];
+                match is_long {
+                    true => candles.iter().map(|candle| candle.low()).reduce(f64::min),
+                    false => candles.iter().map(|candle| candle.high()).reduce(f64::max),
+                }
+            }
+            _ => None,
+        };
+
+        let position = StopPosition {
+            is_long,
+            price_in: trade_in.price_in,
+            price: match is_long {
+                true => tick.bid(),
+                false => tick.ask(),
+            },
+            stop: stop.target_price,
+            atr_value,
+            swing,
+        };
+
+        self.next_stop(&position, |pips| to_pips(pips, tick))
+            .map(|new_stop| {
+                let mut stop = stop.clone();
+                stop.target_price = new_stop;
+                stop
+            })
+    }
+
+    /// New stop price of the position, if it has to be moved
+    pub fn next_stop<F>(&self, position: &StopPosition, to_pips: F) -> Option<f64>
+    where
+        F: Fn(f64) -> f64,
+    {
+        let StopPosition {
+            is_long,
+            price_in,
+            price,
+            stop,
+            atr_value,
+            swing,
+        } = *position;
+
+        let profit = match is_long {
+            true => price - price_in,
+            false => price_in - price,
+        };
+
+        let mut candidates = vec![];
+
+        if let Some(trigger) = self.break_even {
+            let distance = match trigger {
+                StopTrigger::Pips(pips) => to_pips(pips),
+                StopTrigger::Atr(multiplier) => atr_value * multiplier,
+            };
+
+            if profit >= distance {
+                candidates.push(price_in);
+            }
+        }
+
+        match self.trailing {
+            Some(TrailingStop::Atr(multiplier)) => candidates.push(match is_long {
+                true => price - atr_value * multiplier,
+                false => price + atr_value * multiplier,
+            }),
+            Some(TrailingStop::Swing(_)) => candidates.extend(swing),
+            None => (),
+        }
+
+        let new_stop = match is_long {
+            true => candidates.into_iter().reduce(f64::max)?,
+            false => candidates.into_iter().reduce(f64::min)?,
+        };
+
+        let should_move = match is_long {
+            true => new_stop > stop && new_stop < price,
+            false => new_stop < stop && new_stop > price,
+        };
+
+        match should_move {
+            true => Some(new_stop),
+            false => None,
+        }
+    }
+}
+
+/// Open trade whose stop is managed, at the current price
+#[derive(Debug, Clone, Copy)]
+pub struct StopPosition {
+    pub is_long: bool,
+    pub price_in: f64,
+    /// Bid for longs, ask for shorts
+    pub price: f64,
+    pub stop: f64,
+    pub atr_value: f64,
+    /// Lowest low, or highest high for shorts, of the swing lookback candles
+    pub swing: Option<f64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIP: f64 = 0.0001;
+
+    fn long(price: f64, stop: f64) -> StopPosition {
+        StopPosition {
+            is_long: true,
+            price_in: 1.1,
+            price,
+            stop,
+            atr_value: 0.002,
+            swing: None,
+        }
+    }
+
+    fn short(price: f64, stop: f64) -> StopPosition {
+        StopPosition {
+            is_long: false,
+            price_in: 1.1,
+            price,
+            stop,
+            atr_value: 0.002,
+            swing: None,
+        }
+    }
+
+    fn stops(break_even: Option<StopTrigger>, trailing: Option<TrailingStop>) -> StopManagement {
+        StopManagement {
+            break_even,
+            trailing,
+            on_tick: false,
+        }
+    }
+
+    #[test]
+    fn break_even_once_the_trigger_is_reached() {
+        let stops = stops(Some(StopTrigger::Pips(10.)), None);
+        let pips = |pips| pips * PIP;
+
+        assert_eq!(stops.next_stop(&long(1.1005, 1.098), pips), None);
+        assert_eq!(stops.next_stop(&long(1.1011, 1.098), pips), Some(1.1));
+        assert_eq!(stops.next_stop(&short(1.0989, 1.102), pips), Some(1.1));
+    }
+
+    #[test]
+    fn stops_only_move_in_favour() {
+        let stops = stops(None, Some(TrailingStop::Atr(1.)));
+        let pips = |pips| pips * PIP;
+
+        // Trailing level below the current stop
+        assert_eq!(stops.next_stop(&long(1.1, 1.099), pips), None);
+        assert_eq!(stops.next_stop(&short(1.1, 1.101), pips), None);
+
+        let moved = stops.next_stop(&long(1.105, 1.099), pips).unwrap();
+        assert!(moved > 1.099 && moved < 1.105);
+    }
+
+    #[test]
+    fn stops_never_pass_the_price() {
+        let stops = stops(Some(StopTrigger::Pips(0.)), None);
+        let pips = |pips| pips * PIP;
+
+        // In profit, but break even would be at the current price
+        assert_eq!(stops.next_stop(&long(1.1, 1.098), pips), None);
+    }
+
+    #[test]
+    fn the_most_favourable_candidate_wins() {
+        let stops = stops(Some(StopTrigger::Atr(1.)), Some(TrailingStop::Swing(5)));
+        let pips = |pips| pips * PIP;
+        let mut position = long(1.106, 1.098);
+
+        position.swing = Some(1.103);
+        assert_eq!(stops.next_stop(&position, pips), Some(1.103));
+
+        position.swing = Some(1.097);
+        assert_eq!(stops.next_stop(&position, pips), Some(1.1));
+    }
+
+    #[test]
+    fn disabled_without_rules() {
+        let stops = stops(None, None);
+        assert!(!stops.is_enabled());
+        assert_eq!(stops.next_stop(&long(1.2, 1.098), |pips| pips * PIP), None);
+    }
+}
@@ -1,4 +1,7 @@
-use crate::strategies;
+use crate::config::BotConfig;
+use crate::strategies::registry::{StrategyError, StrategyRegistry};
+use crate::strategies::sizing::LotLimits;
+use crate::strategies::{indicators, sizing};
 
 use rs_algo_shared::error::Result;
 use rs_algo_shared::helpers::calc;
@@ -13,8 +16,8 @@ use rs_algo_shared::scanner::instrument::*;
 
 use async_trait::async_trait;
 use dyn_clone::DynClone;
+use serde_json::Value;
 use std::cmp::Ordering;
-use std::env;
 
 #[async_trait(?Send)]
 pub trait Strategy: DynClone + Send {
@@ -23,10 +26,24 @@ pub trait Strategy: DynClone + Send {
         time_frame: Option<&str>,
         higher_time_frame: Option<&str>,
         strategy_type: Option<StrategyType>,
+        config: &BotConfig,
     ) -> Result<Self>
     where
         Self: Sized;
     fn name(&self) -> &str;
+    fn config(&self) -> &BotConfig;
+    fn set_lot_limits(&mut self, limits: &LotLimits);
+    fn params(&self) -> Value;
+    /// Take profit price of an entry, once its stop loss is prepared
+    fn take_profit_price(
+        &self,
+        _is_long: bool,
+        _entry_price: f64,
+        _stop_price: Option<f64>,
+        _tick: &InstrumentTick,
+    ) -> Option<f64> {
+        None
+    }
     fn strategy_type(&self) -> &StrategyType;
     fn time_frame(&self) -> &TimeFrameType;
     fn higher_time_frame(&self) -> &Option<TimeFrameType>;
@@ -94,12 +111,10 @@ pub trait Strategy: DynClone + Send {
         orders: &Vec<Order>,
         tick: &InstrumentTick,
         use_tick_price: bool,
-    ) -> (PositionResult, PositionResult) {
-        let max_spread = env::var("MAX_SPREAD_PIPS").unwrap().parse::<f64>().unwrap();
-        let positions_on_tick_stream = env::var("POSITIONS_ON_TICK_STREAM")
-            .unwrap()
-            .parse::<bool>()
-            .unwrap();
+        allow_entries: bool,
+    ) -> (PositionResult, PositionResult, Option<Order>) {
+        let max_spread = self.config().max_spread_pips;
+        let positions_on_tick_stream = self.config().positions_on_tick_stream;
 
         let spread_pips = calc::get_spread_pips(&instrument.symbol, tick);
         let is_max_spread = spread_pips > max_spread;
@@ -110,7 +125,7 @@ pub trait Strategy: DynClone + Send {
                 max_spread,
                 spread_pips
             );
-            return (PositionResult::None, PositionResult::None);
+            return (PositionResult::None, PositionResult::None, None);
         }
 
         let index = instrument.data.len().saturating_sub(1);
@@ -121,14 +136,20 @@ pub trait Strategy: DynClone + Send {
             _ => false,
         };
 
-        let order_position_result = self.pending_orders_activated(
+        let order_position_result = match self.pending_orders_activated(
             index,
             instrument,
             &pending_orders,
             trades_in,
             tick,
             use_tick_price,
-        );
+        ) {
+            PositionResult::MarketInOrder(_, order) if !allow_entries => {
+                log::warn!("Entries halted. {:?} not activated", order.order_type);
+                PositionResult::None
+            }
+            result => result,
+        };
 
         if !use_tick_price || (use_tick_price && positions_on_tick_stream) {
             let trade_direction = match use_tick_price {
@@ -161,7 +182,7 @@ pub trait Strategy: DynClone + Send {
                 }
             }
 
-            if !open_positions {
+            if !open_positions && allow_entries {
                 let current_trade_fulfilled = match trades_out.last() {
                     Some(trade) => trade.is_fulfilled(),
                     None => true,
@@ -185,7 +206,26 @@ pub trait Strategy: DynClone + Send {
             }
         }
 
-        (position_result, order_position_result)
+        let stop_management = &self.config().stop_management;
+        let is_candle_close = !use_tick_price
+            && instrument
+                .data
+                .get(index)
+                .map(|candle| candle.is_closed())
+                .unwrap_or(false);
+
+        let modified_stop = match trades_in.last() {
+            Some(trade_in)
+                if open_positions
+                    && trade_in.is_fulfilled()
+                    && (is_candle_close || (use_tick_price && stop_management.on_tick)) =>
+            {
+                stop_management.update_stop(index, instrument, trade_in, &pending_orders, tick)
+            }
+            _ => None,
+        };
+
+        (position_result, order_position_result, modified_stop)
     }
 
     fn should_open_position(
@@ -198,22 +238,8 @@ pub trait Strategy: DynClone + Send {
         trade_direction: &TradeDirection,
         tick: &InstrumentTick,
     ) -> PositionResult {
-        let trade_size = env::var("ORDER_SIZE").unwrap().parse::<f64>().unwrap();
-
-        let max_pending_orders = env::var("MAX_PENDING_ORDERS")
-            .unwrap()
-            .parse::<usize>()
-            .unwrap();
-
-        let overwrite_orders = env::var("ORDERS_OVERWRITE")
-            .unwrap()
-            .parse::<bool>()
-            .unwrap();
-
-        let trading_direction = env::var("TRADING_DIRECTION")
-            .unwrap()
-            .parse::<bool>()
-            .unwrap();
+        let overwrite_orders = self.config().orders_overwrite;
+        let trading_direction = self.config().trading_direction;
 
         let pending_orders = order::get_pending(orders);
         let no_pending_orders = pending_orders.is_empty();
@@ -225,6 +251,29 @@ pub trait Strategy: DynClone + Send {
                     match self.entry_long(index, instrument, htf_instrument, tick) {
                         Position::MarketIn(order_types) => {
                             let trade_type = TradeType::MarketInLong;
+
+                            let prepared_orders = order_types.map(|orders| {
+                                order::prepare_orders(index, instrument, &trade_type, &orders, tick)
+                            });
+
+                            let stop_distance = prepared_orders
+                                .as_ref()
+                                .and_then(|orders| sizing::stop_distance(tick.ask(), orders));
+                            let trade_size =
+                                self.position_size(index, instrument, stop_distance, tick);
+
+                            let prepared_orders = prepared_orders.map(|orders| {
+                                self.with_take_profit(
+                                    index,
+                                    instrument,
+                                    &trade_type,
+                                    orders,
+                                    tick.ask(),
+                                    trade_size,
+                                    tick,
+                                )
+                            });
+
                             let trade_in_result = trade::resolve_trade_in(
                                 index,
                                 trade_size,
@@ -234,10 +283,6 @@ pub trait Strategy: DynClone + Send {
                                 tick,
                             );
 
-                            let prepared_orders = order_types.map(|orders| {
-                                order::prepare_orders(index, instrument, &trade_type, &orders, tick)
-                            });
-
                             let new_orders = match overwrite_orders {
                                 true => prepared_orders,
                                 false => match pending_orders.len().cmp(&0) {
@@ -261,6 +306,19 @@ pub trait Strategy: DynClone + Send {
                                 tick,
                             );
 
+                            let prepared_orders = match entry_order(&order_types) {
+                                Some((size, price)) => self.with_take_profit(
+                                    index,
+                                    instrument,
+                                    &trade_type,
+                                    prepared_orders,
+                                    price,
+                                    size,
+                                    tick,
+                                ),
+                                None => prepared_orders,
+                            };
+
                             let new_orders = match overwrite_orders {
                                 true => prepared_orders,
                                 false => match pending_orders.len().cmp(&0) {
@@ -282,6 +340,28 @@ pub trait Strategy: DynClone + Send {
                         Position::MarketIn(order_types) => {
                             let trade_type = TradeType::MarketInShort;
 
+                            let prepared_orders = order_types.map(|orders| {
+                                order::prepare_orders(index, instrument, &trade_type, &orders, tick)
+                            });
+
+                            let stop_distance = prepared_orders
+                                .as_ref()
+                                .and_then(|orders| sizing::stop_distance(tick.bid(), orders));
+                            let trade_size =
+                                self.position_size(index, instrument, stop_distance, tick);
+
+                            let prepared_orders = prepared_orders.map(|orders| {
+                                self.with_take_profit(
+                                    index,
+                                    instrument,
+                                    &trade_type,
+                                    orders,
+                                    tick.bid(),
+                                    trade_size,
+                                    tick,
+                                )
+                            });
+
                             let trade_in_result = trade::resolve_trade_in(
                                 index,
                                 trade_size,
@@ -291,10 +371,6 @@ pub trait Strategy: DynClone + Send {
                                 tick,
                             );
 
-                            let prepared_orders = order_types.map(|orders| {
-                                order::prepare_orders(index, instrument, &trade_type, &orders, tick)
-                            });
-
                             let new_orders = match overwrite_orders {
                                 true => prepared_orders,
                                 false => match pending_orders.len().cmp(&0) {
@@ -318,6 +394,19 @@ pub trait Strategy: DynClone + Send {
                                 tick,
                             );
 
+                            let prepared_orders = match entry_order(&order_types) {
+                                Some((size, price)) => self.with_take_profit(
+                                    index,
+                                    instrument,
+                                    &trade_type,
+                                    prepared_orders,
+                                    price,
+                                    size,
+                                    tick,
+                                ),
+                                None => prepared_orders,
+                            };
+
                             let new_orders = match overwrite_orders {
                                 true => prepared_orders,
                                 false => match pending_orders.len().cmp(&0) {
@@ -340,6 +429,42 @@ pub trait Strategy: DynClone + Send {
         }
     }
 
+    // Take profits are prepared after the stop they are priced from
+    #[allow(clippy::too_many_arguments)]
+    fn with_take_profit(
+        &self,
+        index: usize,
+        instrument: &Instrument,
+        trade_type: &TradeType,
+        mut orders: Vec<Order>,
+        entry_price: f64,
+        size: f64,
+        tick: &InstrumentTick,
+    ) -> Vec<Order> {
+        let is_long = trade_type.is_long_entry();
+        let stop_price = orders
+            .iter()
+            .find(|order| order.order_type.is_stop())
+            .map(|stop| stop.target_price);
+
+        if let Some(price) = self.take_profit_price(is_long, entry_price, stop_price, tick) {
+            let take_profit = match is_long {
+                true => OrderType::TakeProfitLong(size, price),
+                false => OrderType::TakeProfitShort(size, price),
+            };
+
+            orders.extend(order::prepare_orders(
+                index,
+                instrument,
+                trade_type,
+                &vec![take_profit],
+                tick,
+            ));
+        }
+
+        orders
+    }
+
     fn should_exit_position(
         &mut self,
         index: usize,
@@ -457,7 +582,12 @@ pub trait Strategy: DynClone + Send {
                     None => TradeResult::None,
                 };
 
-                log::info!("Order activated: {:?} ", order.order_type);
+                match order.order_type {
+                    OrderType::TakeProfitLong(_, _) | OrderType::TakeProfitShort(_, _) => {
+                        log::info!("Take profit activated: {:?} ", order.order_type)
+                    }
+                    _ => log::info!("Order activated: {:?} ", order.order_type),
+                };
 
                 PositionResult::MarketOutOrder(trade_out_result, order)
             }
@@ -465,9 +595,21 @@ pub trait Strategy: DynClone + Send {
         }
     }
 
-    fn there_are_funds(&mut self, trades_out: &Vec<TradeOut>) -> bool {
-        let profit: f64 = trades_out.iter().map(|trade| trade.profit_per).sum();
-        profit > -90.
+    fn position_size(
+        &self,
+        index: usize,
+        instrument: &Instrument,
+        stop_distance: Option<f64>,
+        tick: &InstrumentTick,
+    ) -> f64 {
+        let config = self.config();
+        config.position_sizing.size(
+            config.order_size,
+            config.equity,
+            stop_distance,
+            indicators::atr(index, instrument),
+            tick.pip_size(),
+        )
     }
 
     fn update_stats(
@@ -476,8 +618,8 @@ pub trait Strategy: DynClone + Send {
         trades_in: &Vec<TradeIn>,
         trades_out: &Vec<TradeOut>,
     ) -> StrategyStats {
-        let equity = env::var("EQUITY").unwrap().parse::<f64>().unwrap();
-        let commission = env::var("COMMISSION").unwrap().parse::<f64>().unwrap();
+        let equity = self.config().equity;
+        let commission = self.config().commission;
         calculate_strategy_stats(instrument, trades_in, trades_out, equity, commission)
     }
 
@@ -496,52 +638,29 @@ pub fn set_strategy(
     time_frame: &str,
     higher_time_frame: Option<&str>,
     strategy_type: StrategyType,
-) -> Box<dyn Strategy> {
-    let strategies: Vec<Box<dyn Strategy>> = vec![
-        Box::new(
-            strategies::bollinger_bands_reversals::BollingerBandsReversals::new(
-                Some("BB_Reversals"),
-                Some(time_frame),
-                higher_time_frame,
-                Some(strategy_type.clone()),
-            )
-            .unwrap(),
-        ),
-        Box::new(
-            strategies::bollinger_bands_reversals_close::BollingerBandsReversals::new(
-                Some("BB_Reversals_Close"),
-                Some(time_frame),
-                higher_time_frame,
-                Some(strategy_type.clone()),
-            )
-            .unwrap(),
-        ),
-        // Box::new(
-        //     strategies::bollinger_bands_reversals_sell::BollingerBandsReversals::new(
-        //         Some("BB_Reversals_Sell2"),
-        //         Some(time_frame),
-        //         higher_time_frame,
-        //         Some(strategy_type.clone()),
-        //     )
-        //     .unwrap(),
-        // ),
-    ];
-
-    let mut strategy = strategies[0].clone();
-    let mut found = false;
-    for stra in strategies.iter() {
-        if strategy_name == stra.name() {
-            strategy = stra.clone();
-            found = true
-        }
-    }
-    if found {
-        log::info!("Using strategy {}", strategy.name());
-    } else {
-        panic!("Strategy {} not found!", strategy_name);
-    }
+    config: &BotConfig,
+) -> std::result::Result<Box<dyn Strategy>, StrategyError> {
+    let strategy = StrategyRegistry::new().build(
+        strategy_name,
+        time_frame,
+        higher_time_frame,
+        strategy_type,
+        config,
+    )?;
+
+    log::info!("Using strategy {}", strategy.name());
+
+    Ok(strategy)
+}
 
-    strategy
+// Size and price of the entry order
+fn entry_order(order_types: &[OrderType]) -> Option<(f64, f64)> {
+    order_types.iter().find_map(|order_type| match order_type {
+        OrderType::BuyOrderLong(size, price) | OrderType::BuyOrderShort(size, price) => {
+            Some((*size, *price))
+        }
+        _ => None,
+    })
 }
 
 fn log_created_orders(orders: &[Order]) {
@@ -0,0 +1,50 @@
+use rustls::{ClientConfig, RootCertStore};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+
+/// Trusts the system roots plus the PEM CA bundle in ws_server_ca, e.g. a
+/// self-signed one. Without it the default connector is used.
+pub fn client_config(ca_path: &str) -> io::Result<Option<Arc<ClientConfig>>> {
+    if ca_path.is_empty() {
+        return Ok(None);
+    }
+
+    let mut roots = RootCertStore::empty();
+
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            let certs: Vec<Vec<u8>> = certs.into_iter().map(|cert| cert.0).collect();
+            roots.add_parsable_certificates(&certs);
+        }
+        Err(err) => log::warn!("Can't load system certificates {}", err),
+    }
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(ca_path)?))?;
+
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificates found in {}", ca_path),
+        ));
+    }
+
+    let (added, ignored) = roots.add_parsable_certificates(&certs);
+    if added == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid certificates in {}", ca_path),
+        ));
+    }
+
+    if ignored > 0 {
+        log::warn!("{} invalid certificates ignored in {}", ignored, ca_path);
+    }
+
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(Some(Arc::new(config)))
+}
@@ -0,0 +1,87 @@
+use futures_util::{SinkExt, StreamExt};
+use rustls::ClientConfig;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{header::AUTHORIZATION, HeaderValue};
+use tokio_tungstenite::tungstenite::{Error, Message, Result};
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Websocket client that authenticates with an "Authorization: Bearer" header
+pub struct WebSocket {
+    url: String,
+    token: Option<String>,
+    tls: Option<Arc<ClientConfig>>,
+    socket: Option<Socket>,
+}
+
+impl WebSocket {
+    pub fn new(url: String, token: Option<String>, tls: Option<Arc<ClientConfig>>) -> Self {
+        Self {
+            url,
+            token,
+            tls,
+            socket: None,
+        }
+    }
+
+    // A failed connection is logged and surfaces on the next read
+    pub async fn connect(&mut self) {
+        self.close().await;
+
+        match self.open().await {
+            Ok(socket) => self.socket = Some(socket),
+            Err(err) => log::error!("Can't connect to {} {}", self.url, err),
+        }
+    }
+
+    async fn open(&self) -> Result<Socket> {
+        let mut request = self.url.as_str().into_client_request()?;
+
+        if let Some(token) = &self.token {
+            let bearer = HeaderValue::from_str(&["Bearer ", token].concat())
+                .map_err(|err| Error::HttpFormat(err.into()))?;
+            request.headers_mut().insert(AUTHORIZATION, bearer);
+        }
+
+        let connector = self
+            .tls
+            .as_ref()
+            .map(|tls| Connector::Rustls(Arc::clone(tls)));
+        let (socket, _) =
+            tokio_tungstenite::connect_async_tls_with_config(request, None, connector).await?;
+        Ok(socket)
+    }
+
+    pub async fn read(&mut self) -> Result<Message> {
+        match &mut self.socket {
+            Some(socket) => socket.next().await.unwrap_or(Err(Error::ConnectionClosed)),
+            None => Err(Error::AlreadyClosed),
+        }
+    }
+
+    pub async fn send(&mut self, msg: &str) -> Result<()> {
+        match &mut self.socket {
+            Some(socket) => socket.send(Message::Text(msg.to_owned())).await,
+            None => Err(Error::AlreadyClosed),
+        }
+    }
+
+    pub async fn pong(&mut self, payload: &[u8]) {
+        if let Some(socket) = &mut self.socket {
+            if let Err(err) = socket.send(Message::Pong(payload.to_vec())).await {
+                log::warn!("Can't send pong {}", err);
+            }
+        }
+    }
+
+    pub async fn close(&mut self) {
+        if let Some(mut socket) = self.socket.take() {
+            if let Err(err) = socket.close(None).await {
+                log::warn!("Can't close websocket {}", err);
+            }
+        }
+    }
+}
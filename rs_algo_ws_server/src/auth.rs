@@ -0,0 +1,179 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use tungstenite::handshake::server::{ErrorResponse, Request};
+use tungstenite::http::{header::AUTHORIZATION, StatusCode};
+
+/// Symbols and strategies a token may trade. "*" allows any.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Grant {
+    pub symbols: Vec<String>,
+    pub strategies: Vec<String>,
+}
+
+impl Grant {
+    fn any() -> Self {
+        Self {
+            symbols: vec!["*".to_owned()],
+            strategies: vec!["*".to_owned()],
+        }
+    }
+
+    // Commands without strategy, like GetMarketHours, only check the symbol
+    pub fn allows(&self, symbol: &str, strategy: &str) -> bool {
+        is_allowed(&self.symbols, symbol)
+            && (strategy.is_empty() || is_allowed(&self.strategies, strategy))
+    }
+
+    // Trading always names a strategy, so an empty one is never allowed
+    pub fn allows_trading(&self, symbol: &str, strategy: &str) -> bool {
+        !strategy.is_empty() && self.allows(symbol, strategy)
+    }
+}
+
+fn is_allowed(allowed: &[String], value: &str) -> bool {
+    allowed.iter().any(|item| item == "*" || item == value)
+}
+
+pub struct Auth {
+    // None when WS_AUTH_TOKENS is unset and authentication is disabled
+    tokens: Option<HashMap<String, Grant>>,
+}
+
+impl Auth {
+    /// Tokens are read from WS_AUTH_TOKENS as JSON, e.g.
+    /// {"token": {"symbols": ["EURUSD"], "strategies": ["*"]}}
+    pub fn from_env() -> Self {
+        let tokens = match env::var("WS_AUTH_TOKENS") {
+            Ok(tokens) => Some(serde_json::from_str(&tokens).unwrap_or_else(|err| {
+                log::error!(
+                    "Invalid WS_AUTH_TOKENS {}. Every connection will be rejected",
+                    err
+                );
+                HashMap::new()
+            })),
+            Err(_) => {
+                log::warn!("No WS_AUTH_TOKENS set. Authentication is disabled");
+                None
+            }
+        };
+
+        Self { tokens }
+    }
+
+    pub fn authenticate(&self, request: &Request) -> Option<Grant> {
+        match &self.tokens {
+            Some(tokens) => tokens.get(token(request)?).cloned(),
+            None => Some(Grant::any()),
+        }
+    }
+}
+
+// From the "Authorization: Bearer" header
+fn token(request: &Request) -> Option<&str> {
+    request
+        .headers()
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+pub fn unauthorized() -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some("Unauthorized".to_owned()));
+    *response.status_mut() = StatusCode::UNAUTHORIZED;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grant(symbols: &[&str], strategies: &[&str]) -> Grant {
+        Grant {
+            symbols: symbols.iter().map(|s| s.to_string()).collect(),
+            strategies: strategies.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn request(authorization: Option<&str>) -> Request {
+        let mut builder = Request::builder().uri("ws://localhost:9000/?ws_bot&token=query");
+        if let Some(value) = authorization {
+            builder = builder.header(AUTHORIZATION, value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn grants_listed_symbols_and_strategies() {
+        let grant = grant(&["EURUSD"], &["BB_Reversals"]);
+
+        assert!(grant.allows("EURUSD", "BB_Reversals"));
+        assert!(!grant.allows("GBPUSD", "BB_Reversals"));
+        assert!(!grant.allows("EURUSD", "BB_Reversals_Sell"));
+    }
+
+    #[test]
+    fn grants_wildcards() {
+        assert!(grant(&["*"], &["BB_Reversals"]).allows("USDJPY", "BB_Reversals"));
+        assert!(grant(&["EURUSD"], &["*"]).allows("EURUSD", "Any"));
+        assert!(Grant::any().allows("USDCHF", "Any"));
+    }
+
+    #[test]
+    fn commands_without_strategy_only_check_symbol() {
+        let grant = grant(&["EURUSD"], &[]);
+
+        assert!(grant.allows("EURUSD", ""));
+        assert!(!grant.allows("EURUSD", "BB_Reversals"));
+        assert!(!grant.allows("GBPUSD", ""));
+    }
+
+    #[test]
+    fn trading_requires_an_allowed_strategy() {
+        let grant = grant(&["EURUSD"], &["BB_Reversals"]);
+
+        assert!(grant.allows_trading("EURUSD", "BB_Reversals"));
+        assert!(!grant.allows_trading("EURUSD", ""));
+        assert!(!grant.allows_trading("EURUSD", "BB_Reversals_Sell"));
+        assert!(!Grant::any().allows_trading("EURUSD", ""));
+    }
+
+    #[test]
+    fn empty_grant_allows_nothing() {
+        assert!(!Grant::default().allows("EURUSD", ""));
+    }
+
+    #[test]
+    fn token_from_bearer_header() {
+        assert_eq!(token(&request(Some("Bearer secret"))), Some("secret"));
+    }
+
+    #[test]
+    fn token_ignores_query_and_other_schemes() {
+        assert_eq!(token(&request(None)), None);
+        assert_eq!(token(&request(Some("Basic secret"))), None);
+        assert_eq!(token(&request(Some("secret"))), None);
+    }
+
+    #[test]
+    fn disabled_auth_grants_everything() {
+        let auth = Auth { tokens: None };
+        let grant = auth.authenticate(&request(None)).unwrap();
+
+        assert!(grant.allows("EURUSD", "BB_Reversals"));
+    }
+
+    #[test]
+    fn enabled_auth_checks_token() {
+        let tokens = HashMap::from([("secret".to_owned(), grant(&["EURUSD"], &["*"]))]);
+        let auth = Auth {
+            tokens: Some(tokens),
+        };
+
+        assert!(auth.authenticate(&request(Some("Bearer secret"))).is_some());
+        assert!(auth.authenticate(&request(Some("Bearer other"))).is_none());
+        assert!(auth.authenticate(&request(None)).is_none());
+    }
+}
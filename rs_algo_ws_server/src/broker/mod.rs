@@ -0,0 +1,77 @@
+pub mod paper;
+pub mod pool;
+
+use rs_algo_shared::broker::xtb_stream::Xtb;
+use rs_algo_shared::error::Result;
+use rs_algo_shared::models::order::Order;
+use rs_algo_shared::ws::message::TradeResponse;
+
+use async_trait::async_trait;
+use serde::Serialize;
+use std::env;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrokerKind {
+    Xtb,
+    Paper,
+}
+
+pub fn kind() -> BrokerKind {
+    match env::var("BROKER").unwrap_or_default().as_ref() {
+        "Paper" => BrokerKind::Paper,
+        _ => BrokerKind::Xtb,
+    }
+}
+
+/// Lot size constraints of a symbol
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct LotLimits {
+    pub lot_step: f64,
+    pub min_size: f64,
+    pub max_size: f64,
+}
+
+/// Order operations not covered by `BrokerStream`.
+#[async_trait]
+pub trait OrderModifier {
+    /// Brokers that can't move stops reject ModifyOrder before calling
+    /// `modify_order`, so bots enforce their moved stops themselves
+    fn modifies_orders(&self) -> bool {
+        true
+    }
+
+    async fn modify_order(
+        &mut self,
+        symbol: &str,
+        strategy_name: &str,
+        order: Order,
+    ) -> Result<TradeResponse<Order>>;
+
+    /// None when the broker doesn't report them, so bots keep their
+    /// configured limits
+    async fn lot_limits(&mut self, _symbol: &str) -> Result<Option<LotLimits>> {
+        Ok(None)
+    }
+}
+
+// The rs_algo_shared Xtb client has no trade modification nor symbol lot
+// limits. Bots close the trade once price reaches the moved stop instead.
+#[async_trait]
+impl OrderModifier for Xtb {
+    fn modifies_orders(&self) -> bool {
+        false
+    }
+
+    async fn modify_order(
+        &mut self,
+        symbol: &str,
+        _strategy_name: &str,
+        order: Order,
+    ) -> Result<TradeResponse<Order>> {
+        Ok(TradeResponse {
+            symbol: symbol.to_owned(),
+            accepted: false,
+            data: order,
+        })
+    }
+}
@@ -0,0 +1,853 @@
+use super::{LotLimits, OrderModifier};
+use crate::error::RsAlgoErrorKind;
+use crate::handlers::session::Session;
+use crate::message;
+
+use rs_algo_shared::broker::{BrokerStream, DOHLC, VEC_DOHLC};
+use rs_algo_shared::error::Result;
+use rs_algo_shared::models::market::{MarketHour, MarketHours};
+use rs_algo_shared::models::order::Order;
+use rs_algo_shared::models::tick::InstrumentTick;
+use rs_algo_shared::models::time_frame::TimeFrameType;
+use rs_algo_shared::models::trade::*;
+use rs_algo_shared::ws::message::*;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration as Dur, Local, TimeZone, Timelike};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::time;
+use tungstenite::Message;
+
+/// Paper broker settings, read once from the PAPER_BROKER_* env vars.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaperConfig {
+    history_bars: usize,
+    data_dir: Option<String>,
+    start_price: f64,
+    spread_pips: f64,
+    stream_interval: u64,
+    lot_limits: LotLimits,
+}
+
+impl Default for PaperConfig {
+    fn default() -> Self {
+        Self {
+            history_bars: 500,
+            data_dir: None,
+            start_price: 1.0,
+            spread_pips: 1.,
+            stream_interval: 1000,
+            lot_limits: LotLimits {
+                lot_step: 0.01,
+                min_size: 0.01,
+                max_size: 100.,
+            },
+        }
+    }
+}
+
+impl PaperConfig {
+    pub fn from_env() -> std::result::Result<Self, RsAlgoErrorKind> {
+        Self::from_lookup(|key| env::var(key).ok())
+    }
+
+    fn from_lookup<F>(lookup: F) -> std::result::Result<Self, RsAlgoErrorKind>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let default = Self::default();
+
+        Ok(Self {
+            history_bars: parse(&lookup, "PAPER_BROKER_HISTORY_BARS", default.history_bars)?,
+            data_dir: lookup("PAPER_BROKER_DATA_DIR"),
+            start_price: parse(&lookup, "PAPER_BROKER_START_PRICE", default.start_price)?,
+            spread_pips: parse(&lookup, "PAPER_BROKER_SPREAD_PIPS", default.spread_pips)?,
+            stream_interval: parse(
+                &lookup,
+                "PAPER_BROKER_STREAM_INTERVAL",
+                default.stream_interval,
+            )?,
+            lot_limits: LotLimits {
+                lot_step: parse(
+                    &lookup,
+                    "PAPER_BROKER_LOT_STEP",
+                    default.lot_limits.lot_step,
+                )?,
+                min_size: parse(
+                    &lookup,
+                    "PAPER_BROKER_MIN_SIZE",
+                    default.lot_limits.min_size,
+                )?,
+                max_size: parse(
+                    &lookup,
+                    "PAPER_BROKER_MAX_SIZE",
+                    default.lot_limits.max_size,
+                )?,
+            },
+        })
+    }
+}
+
+fn parse<F, T>(lookup: &F, key: &str, default: T) -> std::result::Result<T, RsAlgoErrorKind>
+where
+    F: Fn(&str) -> Option<String>,
+    T: FromStr,
+{
+    match lookup(key) {
+        Some(value) => value.parse::<T>().map_err(|_| {
+            log::error!("Invalid {} {:?}", key, value);
+            RsAlgoErrorKind::InvalidEnvVar
+        }),
+        None => Ok(default),
+    }
+}
+
+struct PaperPosition {
+    symbol: String,
+    strategy_name: String,
+    trade_in: TradeIn,
+    orders: Option<Vec<Order>>,
+}
+
+enum PriceSource {
+    Replay { bars: VEC_DOHLC, cursor: usize },
+    Synthetic { seed: u64 },
+}
+
+struct PriceFeed {
+    source: PriceSource,
+    price: f64,
+    pip_size: f64,
+    spread: f64,
+}
+
+impl PriceFeed {
+    fn new(symbol: &str, config: &PaperConfig) -> Self {
+        let pip_size = match symbol.contains("JPY") {
+            true => 0.01,
+            false => 0.0001,
+        };
+        let spread = config.spread_pips * pip_size;
+
+        let replay = config
+            .data_dir
+            .as_ref()
+            .map(|dir| [dir, "/", symbol, ".csv"].concat())
+            .and_then(|path| read_csv(&path));
+
+        match replay {
+            Some(bars) if !bars.is_empty() => {
+                log::info!("Paper broker replaying {} {} bars", bars.len(), symbol);
+                let cursor = config.history_bars.min(bars.len());
+                let price = bars[cursor.saturating_sub(1)].4;
+                Self {
+                    source: PriceSource::Replay { bars, cursor },
+                    price,
+                    pip_size,
+                    spread,
+                }
+            }
+            _ => {
+                log::info!("Paper broker generating synthetic {} prices", symbol);
+                let price = config.start_price;
+                let seed = symbol
+                    .bytes()
+                    .fold(0x9E37_79B9_7F4A_7C15, |acc: u64, byte| {
+                        (acc ^ byte as u64).wrapping_mul(0x0100_0000_01B3)
+                    });
+                Self {
+                    source: PriceSource::Synthetic { seed },
+                    price,
+                    pip_size,
+                    spread,
+                }
+            }
+        }
+    }
+
+    fn history(&mut self, period: usize, from: i64) -> VEC_DOHLC {
+        let period = period.max(1);
+        match self.source {
+            PriceSource::Replay { ref bars, cursor } => {
+                let played: VEC_DOHLC = bars[..cursor]
+                    .iter()
+                    .filter(|bar| bar.0.timestamp() >= from)
+                    .cloned()
+                    .collect();
+                aggregate(&played, period)
+            }
+            PriceSource::Synthetic { .. } => {
+                let now = Local::now().timestamp();
+                let step = (period * 60) as i64;
+                let mut result: VEC_DOHLC = vec![];
+                let mut timestamp = from - from.rem_euclid(step);
+
+                while timestamp <= now {
+                    let date = Local.timestamp_opt(timestamp, 0).unwrap();
+                    result.push(self.synthetic_bar(date, period));
+                    timestamp += step;
+                }
+                result
+            }
+        }
+    }
+
+    fn next_bar(&mut self) -> Option<DOHLC> {
+        match self.source {
+            PriceSource::Replay {
+                ref bars,
+                ref mut cursor,
+            } => {
+                let bar = bars.get(*cursor).cloned();
+                if let Some(bar) = bar {
+                    *cursor += 1;
+                    self.price = bar.4;
+                }
+                bar
+            }
+            PriceSource::Synthetic { .. } => {
+                let now = Local::now();
+                let date = now - Dur::seconds(now.second() as i64);
+                Some(self.synthetic_bar(date, 1))
+            }
+        }
+    }
+
+    fn synthetic_bar(&mut self, date: DateTime<Local>, minutes: usize) -> DOHLC {
+        let volatility = self.price * 0.0002 * (minutes as f64).sqrt();
+        let open = self.price;
+        let close = open + volatility * self.random();
+        let high = open.max(close) + volatility * self.random().abs() / 2.;
+        let low = open.min(close) - volatility * self.random().abs() / 2.;
+        self.price = close;
+        (date, open, high, low, close, 0.)
+    }
+
+    // xorshift64, returns a value in [-1, 1)
+    fn random(&mut self) -> f64 {
+        match self.source {
+            PriceSource::Synthetic { ref mut seed } => {
+                *seed ^= *seed << 13;
+                *seed ^= *seed >> 7;
+                *seed ^= *seed << 17;
+                (*seed as f64 / u64::MAX as f64) * 2. - 1.
+            }
+            PriceSource::Replay { .. } => 0.,
+        }
+    }
+
+    fn tick(&self, symbol: &str) -> InstrumentTick {
+        let spread = self.spread;
+
+        InstrumentTick::new()
+            .symbol(symbol.to_owned())
+            .ask(self.price + spread)
+            .bid(self.price)
+            .high(self.price + spread)
+            .low(self.price)
+            .spread(spread)
+            .pip_size(self.pip_size)
+            .time(Local::now().timestamp())
+            .build()
+            .unwrap()
+    }
+}
+
+struct PaperAccount {
+    config: PaperConfig,
+    feeds: HashMap<String, PriceFeed>,
+    positions: Vec<PaperPosition>,
+}
+
+impl PaperAccount {
+    fn new(config: PaperConfig) -> Self {
+        Self {
+            config,
+            feeds: HashMap::new(),
+            positions: vec![],
+        }
+    }
+
+    fn feed(&mut self, symbol: &str) -> &mut PriceFeed {
+        let config = &self.config;
+        self.feeds
+            .entry(symbol.to_owned())
+            .or_insert_with(|| PriceFeed::new(symbol, config))
+    }
+
+    fn fill_trade_in(
+        &mut self,
+        symbol: &str,
+        strategy_name: &str,
+        mut trade_in: TradeIn,
+        orders: Option<Vec<Order>>,
+    ) -> TradeResponse<TradeIn> {
+        let tick = self.feed(symbol).tick(symbol);
+        let already_open = self
+            .positions
+            .iter()
+            .any(|position| position.symbol == symbol && position.strategy_name == strategy_name);
+
+        trade_in.ask = tick.ask();
+        trade_in.price_in =
+            entry_price(trade_in.trade_type.is_long_entry(), tick.ask(), tick.bid());
+
+        if !already_open {
+            log::info!(
+                "Paper {} {:?} {} filled at {}",
+                symbol,
+                trade_in.trade_type,
+                trade_in.id,
+                trade_in.price_in
+            );
+
+            self.positions.push(PaperPosition {
+                symbol: symbol.to_owned(),
+                strategy_name: strategy_name.to_owned(),
+                trade_in: trade_in.clone(),
+                orders,
+            });
+        }
+
+        TradeResponse {
+            symbol: symbol.to_owned(),
+            accepted: !already_open,
+            data: trade_in,
+        }
+    }
+
+    fn fill_trade_out(
+        &mut self,
+        symbol: &str,
+        strategy_name: &str,
+        mut trade_out: TradeOut,
+    ) -> TradeResponse<TradeOut> {
+        let tick = self.feed(symbol).tick(symbol);
+        let position = self.positions.iter().position(|position| {
+            position.symbol == symbol
+                && position.strategy_name == strategy_name
+                && position.trade_in.id == trade_out.id
+        });
+
+        trade_out.ask = tick.ask();
+        trade_out.bid = tick.bid();
+
+        let accepted = match position {
+            Some(idx) => {
+                let position = self.positions.remove(idx);
+                let trade_in = position.trade_in;
+                let exit = exit_result(
+                    trade_in.trade_type.is_long_entry(),
+                    trade_in.price_in,
+                    tick.ask(),
+                    tick.bid(),
+                );
+                let price_out = exit.price_out;
+
+                trade_out.price_out = exit.price_out;
+                trade_out.profit = exit.profit;
+                trade_out.profit_per = exit.profit_per;
+
+                log::info!(
+                    "Paper {} {:?} {} closed at {} profit {}",
+                    symbol,
+                    trade_out.trade_type,
+                    trade_out.id,
+                    price_out,
+                    trade_out.profit
+                );
+                true
+            }
+            None => {
+                log::error!("Paper {} position {} not found", symbol, trade_out.id);
+                false
+            }
+        };
+
+        TradeResponse {
+            symbol: symbol.to_owned(),
+            accepted,
+            data: trade_out,
+        }
+    }
+}
+
+impl PaperAccount {
+    fn modify_order(
+        &mut self,
+        symbol: &str,
+        strategy_name: &str,
+        order: Order,
+    ) -> TradeResponse<Order> {
+        let pending_order = self
+            .positions
+            .iter_mut()
+            .filter(|position| position.symbol == symbol && position.strategy_name == strategy_name)
+            .filter_map(|position| position.orders.as_mut())
+            .flat_map(|orders| orders.iter_mut())
+            .find(|pending_order| pending_order.id == order.id);
+
+        let accepted = match pending_order {
+            Some(pending_order) => {
+                log::info!(
+                    "Paper {} order {} modified to {:?}",
+                    symbol,
+                    order.id,
+                    order.order_type
+                );
+                *pending_order = order.clone();
+                true
+            }
+            None => {
+                log::error!("Paper {} order {} not found", symbol, order.id);
+                false
+            }
+        };
+
+        TradeResponse {
+            symbol: symbol.to_owned(),
+            accepted,
+            data: order,
+        }
+    }
+}
+
+// Long positions open at the ask and close at the bid, shorts the other way
+fn entry_price(is_long: bool, ask: f64, bid: f64) -> f64 {
+    match is_long {
+        true => ask,
+        false => bid,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct ExitResult {
+    price_out: f64,
+    profit: f64,
+    profit_per: f64,
+}
+
+// Profit is the price difference, like XTB reports it. The bot converts it
+// to currency with the trade size and pip value.
+fn exit_result(is_long: bool, price_in: f64, ask: f64, bid: f64) -> ExitResult {
+    let (price_out, diff) = match is_long {
+        true => (bid, bid - price_in),
+        false => (ask, price_in - ask),
+    };
+
+    ExitResult {
+        price_out,
+        profit: diff,
+        profit_per: diff / price_in * 100.,
+    }
+}
+
+static ACCOUNT: OnceLock<Mutex<PaperAccount>> = OnceLock::new();
+
+/// Sets the paper broker settings. Called once at startup, before any
+/// session uses the broker.
+pub fn init(config: PaperConfig) {
+    if ACCOUNT.set(Mutex::new(PaperAccount::new(config))).is_err() {
+        log::warn!("Paper broker already initialized");
+    }
+}
+
+fn account() -> &'static Mutex<PaperAccount> {
+    ACCOUNT.get_or_init(|| Mutex::new(PaperAccount::new(PaperConfig::default())))
+}
+
+fn read_csv(path: &str) -> Option<VEC_DOHLC> {
+    let content = fs::read_to_string(path).ok()?;
+    let mut result: VEC_DOHLC = vec![];
+
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+        if fields.len() != 6 {
+            continue;
+        }
+
+        let date = match DateTime::<Local>::from_str(fields[0]) {
+            Ok(date) => date,
+            Err(_) => continue,
+        };
+
+        let values: Vec<f64> = fields[1..]
+            .iter()
+            .filter_map(|value| value.parse::<f64>().ok())
+            .collect();
+
+        if values.len() == 5 {
+            result.push((date, values[0], values[1], values[2], values[3], values[4]));
+        }
+    }
+
+    Some(result)
+}
+
+fn aggregate(bars: &VEC_DOHLC, period: usize) -> VEC_DOHLC {
+    let step = (period * 60) as i64;
+    let mut result: VEC_DOHLC = vec![];
+
+    for bar in bars {
+        let bucket = bar.0.timestamp() - bar.0.timestamp().rem_euclid(step);
+        match result.last_mut() {
+            Some(last) if last.0.timestamp() == bucket => {
+                last.2 = last.2.max(bar.2);
+                last.3 = last.3.min(bar.3);
+                last.4 = bar.4;
+                last.5 += bar.5;
+            }
+            _ => {
+                let date = Local.timestamp_opt(bucket, 0).unwrap();
+                result.push((date, bar.1, bar.2, bar.3, bar.4, bar.5));
+            }
+        }
+    }
+
+    result
+}
+
+fn time_frame(period: usize) -> TimeFrameType {
+    match period {
+        1 => TimeFrameType::M1,
+        5 => TimeFrameType::M5,
+        15 => TimeFrameType::M15,
+        30 => TimeFrameType::M30,
+        60 => TimeFrameType::H1,
+        240 => TimeFrameType::H4,
+        1440 => TimeFrameType::D,
+        10080 => TimeFrameType::W,
+        _ => TimeFrameType::ERR,
+    }
+}
+
+fn response<T>(response: ResponseType, payload: T) -> Result<ResponseBody<T>> {
+    Ok(ResponseBody {
+        response,
+        payload: Some(payload),
+    })
+}
+
+/// Local broker that fills against replayed or synthetic prices and keeps
+/// its own positions. Selected with `BROKER=Paper`.
+pub struct PaperBroker {}
+
+#[async_trait]
+impl BrokerStream for PaperBroker {
+    async fn new() -> Self {
+        PaperBroker {}
+    }
+
+    async fn login(&mut self, _username: &str, _password: &str) -> Result<&mut Self> {
+        log::info!("Paper broker session started");
+        Ok(self)
+    }
+
+    async fn get_instrument_data(
+        &mut self,
+        symbol: &str,
+        period: usize,
+        start: i64,
+    ) -> Result<ResponseBody<InstrumentData<VEC_DOHLC>>> {
+        let data = account()
+            .lock()
+            .unwrap()
+            .feed(symbol)
+            .history(period, start);
+
+        response(
+            ResponseType::GetInstrumentData,
+            InstrumentData {
+                symbol: symbol.to_owned(),
+                time_frame: time_frame(period),
+                data,
+            },
+        )
+    }
+
+    async fn get_instrument_tick(&mut self, symbol: &str) -> Result<ResponseBody<InstrumentTick>> {
+        let tick = account().lock().unwrap().feed(symbol).tick(symbol);
+        response(ResponseType::GetInstrumentTick, tick)
+    }
+
+    async fn get_market_hours(&mut self, symbol: &str) -> Result<ResponseBody<MarketHours>> {
+        let always_open = (1..=7)
+            .map(|day| MarketHour {
+                day,
+                from: 0,
+                to: 86_400_000,
+            })
+            .collect();
+
+        response(
+            ResponseType::GetMarketHours,
+            MarketHours::new(symbol.to_owned(), always_open),
+        )
+    }
+
+    async fn is_market_open(&mut self, _symbol: &str) -> Result<ResponseBody<bool>> {
+        response(ResponseType::IsMarketOpen, true)
+    }
+
+    async fn get_active_positions(
+        &mut self,
+        symbol: &str,
+        strategy_name: &str,
+    ) -> Result<ResponseBody<PositionResult>> {
+        let account = account().lock().unwrap();
+        let position_result =
+            match account.positions.iter().find(|position| {
+                position.symbol == symbol && position.strategy_name == strategy_name
+            }) {
+                Some(position) => PositionResult::MarketIn(
+                    TradeResult::TradeIn(position.trade_in.clone()),
+                    position.orders.clone(),
+                ),
+                None => PositionResult::None,
+            };
+
+        response(ResponseType::GetActivePositions, position_result)
+    }
+
+    async fn open_trade(
+        &mut self,
+        trade: TradeData<TradeIn>,
+        orders: Option<Vec<Order>>,
+    ) -> Result<ResponseBody<TradeResponse<TradeIn>>> {
+        let trade_response = account().lock().unwrap().fill_trade_in(
+            &trade.symbol,
+            &trade.strategy_name,
+            trade.data,
+            orders,
+        );
+        response(ResponseType::TradeInFulfilled, trade_response)
+    }
+
+    async fn open_order(
+        &mut self,
+        trade: TradeData<TradeIn>,
+        order: TradeData<Order>,
+    ) -> Result<ResponseBody<TradeResponse<TradeIn>>> {
+        let trade_response = account().lock().unwrap().fill_trade_in(
+            &trade.symbol,
+            &trade.strategy_name,
+            trade.data,
+            Some(vec![order.data]),
+        );
+        response(ResponseType::TradeInFulfilled, trade_response)
+    }
+
+    async fn close_trade(
+        &mut self,
+        trade: TradeData<TradeOut>,
+    ) -> Result<ResponseBody<TradeResponse<TradeOut>>> {
+        let trade_response = account().lock().unwrap().fill_trade_out(
+            &trade.symbol,
+            &trade.strategy_name,
+            trade.data,
+        );
+        response(ResponseType::TradeOutFulfilled, trade_response)
+    }
+
+    async fn close_order(
+        &mut self,
+        trade: TradeData<TradeOut>,
+        _order: TradeData<Order>,
+    ) -> Result<ResponseBody<TradeResponse<TradeOut>>> {
+        let trade_response = account().lock().unwrap().fill_trade_out(
+            &trade.symbol,
+            &trade.strategy_name,
+            trade.data,
+        );
+        response(ResponseType::TradeOutFulfilled, trade_response)
+    }
+
+    async fn subscribe_stream(&mut self, _symbol: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn subscribe_tick_prices(&mut self, _symbol: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn subscribe_trades(&mut self, _symbol: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn keepalive_ping(&mut self) -> Result<String> {
+        Ok("".to_string())
+    }
+
+    async fn parse_stream_data(msg: Message, _symbol: &str, _strategy: &str) -> Option<String> {
+        msg.into_text().ok()
+    }
+}
+
+#[async_trait]
+impl OrderModifier for PaperBroker {
+    async fn modify_order(
+        &mut self,
+        symbol: &str,
+        strategy_name: &str,
+        order: Order,
+    ) -> Result<TradeResponse<Order>> {
+        Ok(account()
+            .lock()
+            .unwrap()
+            .modify_order(symbol, strategy_name, order))
+    }
+
+    async fn lot_limits(&mut self, _symbol: &str) -> Result<Option<LotLimits>> {
+        Ok(Some(account().lock().unwrap().config.lot_limits))
+    }
+}
+
+/// Paper counterpart of `stream::listen`: pushes a new bar and tick to the
+/// session every `PAPER_BROKER_STREAM_INTERVAL` millis.
+pub fn listen(session: Session) {
+    tokio::spawn(async move {
+        let stream_interval = account().lock().unwrap().config.stream_interval;
+        let symbol = session.symbol.clone();
+        let mut interval = time::interval(Duration::from_millis(stream_interval));
+
+        loop {
+            interval.tick().await;
+
+            let (bar, tick) = {
+                let mut account = account().lock().unwrap();
+                let feed = account.feed(&symbol);
+                (feed.next_bar(), feed.tick(&symbol))
+            };
+
+            let bar = match bar {
+                Some(bar) => bar,
+                None => {
+                    log::warn!("Paper {} replay finished", symbol);
+                    break;
+                }
+            };
+
+            let stream_msg = ResponseBody {
+                response: ResponseType::SubscribeStream,
+                payload: Some(bar),
+            };
+
+            let tick_msg = ResponseBody {
+                response: ResponseType::SubscribeTickPrices,
+                payload: Some(tick),
+            };
+
+            let stream_txt = serde_json::to_string(&stream_msg).unwrap();
+            let tick_txt = serde_json::to_string(&tick_msg).unwrap();
+
+            if message::send(&session, Message::Text(stream_txt))
+                .await
+                .and(message::send(&session, Message::Text(tick_txt)).await)
+                .is_err()
+            {
+                log::warn!("Paper stream {} stopped!", session.bot_name());
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f64 = 1e-9;
+
+    fn lookup(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> + '_ {
+        move |key| {
+            vars.iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    #[test]
+    fn config_defaults_without_env_vars() {
+        assert_eq!(
+            PaperConfig::from_lookup(lookup(&[])).unwrap(),
+            PaperConfig::default()
+        );
+    }
+
+    #[test]
+    fn config_reads_env_vars() {
+        let config = PaperConfig::from_lookup(lookup(&[
+            ("PAPER_BROKER_SPREAD_PIPS", "2.5"),
+            ("PAPER_BROKER_STREAM_INTERVAL", "250"),
+            ("PAPER_BROKER_DATA_DIR", "data"),
+            ("PAPER_BROKER_LOT_STEP", "0.1"),
+        ]))
+        .unwrap();
+
+        assert_eq!(config.spread_pips, 2.5);
+        assert_eq!(config.stream_interval, 250);
+        assert_eq!(config.data_dir.as_deref(), Some("data"));
+        assert_eq!(config.lot_limits.lot_step, 0.1);
+        assert_eq!(config.lot_limits.min_size, 0.01);
+    }
+
+    #[test]
+    fn invalid_config_is_an_error() {
+        let config = PaperConfig::from_lookup(lookup(&[("PAPER_BROKER_SPREAD_PIPS", "wide")]));
+
+        assert_eq!(config, Err(RsAlgoErrorKind::InvalidEnvVar));
+    }
+
+    #[test]
+    fn entries_fill_on_the_opposite_side() {
+        assert_eq!(entry_price(true, 1.1002, 1.1), 1.1002);
+        assert_eq!(entry_price(false, 1.1002, 1.1), 1.1);
+    }
+
+    #[test]
+    fn long_exit_closes_at_the_bid() {
+        let exit = exit_result(true, 1.1, 1.1052, 1.105);
+
+        assert_eq!(exit.price_out, 1.105);
+        assert!((exit.profit - 0.005).abs() < EPSILON);
+        assert!((exit.profit_per - 0.005 / 1.1 * 100.).abs() < EPSILON);
+    }
+
+    #[test]
+    fn short_exit_closes_at_the_ask() {
+        let exit = exit_result(false, 1.1, 1.1052, 1.105);
+
+        assert_eq!(exit.price_out, 1.1052);
+        assert!((exit.profit + 0.0052).abs() < EPSILON);
+    }
+
+    #[test]
+    fn round_trip_at_the_same_price_costs_the_spread() {
+        let (ask, bid) = (1.1001, 1.1);
+        let price_in = entry_price(true, ask, bid);
+        let exit = exit_result(true, price_in, ask, bid);
+
+        assert!((exit.profit + 0.0001).abs() < EPSILON);
+    }
+
+    #[test]
+    fn bars_are_aggregated_by_period() {
+        let date = |minute: u32| Local.with_ymd_and_hms(2024, 1, 2, 10, minute, 0).unwrap();
+        let bars = vec![
+            (date(0), 1., 2., 0.5, 1.5, 10.),
+            (date(1), 1.5, 3., 1., 2.5, 5.),
+            (date(5), 2.5, 2.6, 2.4, 2.5, 1.),
+        ];
+
+        let aggregated = aggregate(&bars, 5);
+
+        assert_eq!(aggregated.len(), 2);
+        assert_eq!(aggregated[0], (date(0), 1., 3., 0.5, 2.5, 15.));
+        assert_eq!(aggregated[1], (date(5), 2.5, 2.6, 2.4, 2.5, 1.));
+    }
+}
@@ -0,0 +1,168 @@
+use crate::settings;
+
+use rs_algo_shared::broker::BrokerStream;
+use rs_algo_shared::error::Result;
+
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Semaphore};
+use tokio::time;
+
+/// Execution commands never wait behind history downloads on the data lane.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+    Execution,
+    Data,
+}
+
+struct Client<BK> {
+    broker: Arc<Mutex<BK>>,
+    logged_at: Instant,
+}
+
+struct Slot<BK> {
+    client: Mutex<Option<Client<BK>>>,
+}
+
+struct LanePool<BK> {
+    slots: Vec<Slot<BK>>,
+    next: AtomicUsize,
+}
+
+impl<BK> LanePool<BK> {
+    fn new(size: usize) -> Self {
+        Self {
+            slots: (0..size.max(1))
+                .map(|_| Slot {
+                    client: Mutex::new(None),
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn next_slot(&self) -> &Slot<BK> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.slots.len();
+        &self.slots[index]
+    }
+}
+
+/// Logged in broker clients shared by every session. Clients are logged in
+/// on first use and again once their session expires.
+pub struct BrokerPool<BK> {
+    execution: LanePool<BK>,
+    data: LanePool<BK>,
+    logins: Semaphore,
+    session_ttl: Duration,
+}
+
+fn env_or(key: &str, default: u64) -> u64 {
+    env::var(key)
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(default)
+}
+
+impl<BK> BrokerPool<BK>
+where
+    BK: BrokerStream + Send + Sync + 'static,
+{
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            execution: LanePool::new(env_or("BROKER_POOL_EXECUTION", 1) as usize),
+            data: LanePool::new(env_or("BROKER_POOL_DATA", 2) as usize),
+            logins: Semaphore::new(env_or("BROKER_MAX_LOGINS", 2) as usize),
+            session_ttl: Duration::from_secs(env_or("BROKER_SESSION_TTL", 3600)),
+        })
+    }
+
+    fn lane(&self, lane: Lane) -> &LanePool<BK> {
+        match lane {
+            Lane::Execution => &self.execution,
+            Lane::Data => &self.data,
+        }
+    }
+
+    pub async fn get(&self, lane: Lane) -> Result<Arc<Mutex<BK>>> {
+        let mut client = self.lane(lane).next_slot().client.lock().await;
+
+        if let Some(current) = client.as_ref() {
+            if current.logged_at.elapsed() < self.session_ttl {
+                return Ok(Arc::clone(&current.broker));
+            }
+            log::info!("{:?} broker session expired", lane);
+        }
+
+        let broker = match self.login::<BK>().await {
+            Ok(broker) => broker,
+            Err(err) => {
+                *client = None;
+                log::error!("{:?} broker login failed {}", lane, err);
+                return Err(err);
+            }
+        };
+
+        log::info!("{:?} broker logged in", lane);
+
+        let broker = Arc::new(Mutex::new(broker));
+        *client = Some(Client {
+            broker: Arc::clone(&broker),
+            logged_at: Instant::now(),
+        });
+
+        Ok(broker)
+    }
+
+    /// Drops the client so its next user logs in again
+    pub async fn discard(&self, broker: &Arc<Mutex<BK>>) {
+        for slot in self.execution.slots.iter().chain(self.data.slots.iter()) {
+            let mut client = slot.client.lock().await;
+
+            if let Some(current) = client.as_ref() {
+                if Arc::ptr_eq(&current.broker, broker) {
+                    log::warn!("Broker client discarded. Logging in again");
+                    *client = None;
+                }
+            }
+        }
+    }
+
+    /// Logs in a client owned by the caller, like symbol streams holding
+    /// their own connection. It waits for BROKER_MAX_LOGINS as pooled ones.
+    pub async fn login<T: BrokerStream>(&self) -> Result<T> {
+        let _permit = self.logins.acquire().await.unwrap();
+        let username = env::var("BROKER_USERNAME").unwrap_or_default();
+        let password = env::var("BROKER_PASSWORD").unwrap_or_default();
+
+        let mut broker = T::new().await;
+        broker.login(&username, &password).await?;
+        Ok(broker)
+    }
+
+    /// Pings every logged in client. Clients failing the ping are dropped
+    /// and logged in again on their next use.
+    pub fn keepalive(self: &Arc<Self>) {
+        let pool = Arc::clone(self);
+        let mut interval = time::interval(settings::get().keepalive_interval());
+
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+
+                for slot in pool.execution.slots.iter().chain(pool.data.slots.iter()) {
+                    let mut client = slot.client.lock().await;
+
+                    if let Some(current) = client.as_ref() {
+                        let ping = current.broker.lock().await.keepalive_ping().await;
+                        if let Err(err) = ping {
+                            log::warn!("Broker keepalive failed {}. Logging in again", err);
+                            *client = None;
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
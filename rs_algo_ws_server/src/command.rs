@@ -0,0 +1,228 @@
+use crate::error::{self, ErrorCode};
+
+use rs_algo_shared::models::order::Order;
+use rs_algo_shared::models::trade::{PositionResult, TradeOptions};
+use rs_algo_shared::ws::message::Command;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt::Debug;
+
+// Used in error replies to frames that are not a command
+pub const UNKNOWN_COMMAND: &str = "Unknown";
+
+/// Commands not defined in rs_algo_shared CommandType
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExtendedCommandType {
+    ModifyOrder,
+    GetLotLimits,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExtendedCommand {
+    pub command: ExtendedCommandType,
+    pub data: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExtendedResponse<T> {
+    pub response: ExtendedCommandType,
+    pub payload: Option<T>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SymbolPayload {
+    pub symbol: String,
+    #[serde(default, alias = "strategy")]
+    pub strategy_name: String,
+}
+
+/// Bot identification sent along the BotData on InitSession and UpdateBotData
+#[derive(Debug, Deserialize)]
+pub struct BotPayload {
+    pub symbol: String,
+    pub time_frame: String,
+    pub strategy_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InstrumentDataPayload {
+    pub symbol: String,
+    pub time_frame: String,
+    pub num_bars: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ExecutePositionPayload {
+    pub symbol: String,
+    pub strategy_name: String,
+    pub options: TradeOptions,
+    pub data: PositionResult,
+}
+
+#[derive(Deserialize)]
+pub struct ModifyOrderPayload {
+    pub symbol: String,
+    pub strategy_name: String,
+    pub data: Order,
+}
+
+pub fn parse(msg: &str) -> Result<Command<Value>, String> {
+    serde_json::from_str(msg).map_err(|err| {
+        error::response(
+            UNKNOWN_COMMAND,
+            ErrorCode::Validation,
+            format!("Wrong command format. {}", err),
+        )
+    })
+}
+
+/// None for frames that are not an extended command, handled as a
+/// rs_algo_shared Command instead
+pub fn parse_extended(msg: &str) -> Option<ExtendedCommand> {
+    serde_json::from_str(msg).ok()
+}
+
+/// Command data as the payload struct of the command, or the error reply
+pub fn payload<T, C>(command: C, data: Option<&Value>) -> Result<T, String>
+where
+    T: DeserializeOwned,
+    C: Serialize + Debug,
+{
+    let data = match data {
+        Some(data) if !data.is_null() => data,
+        _ => {
+            return Err(error::response(
+                command,
+                ErrorCode::Validation,
+                "Missing command data",
+            ))
+        }
+    };
+
+    T::deserialize(data).map_err(|err| {
+        error::response(
+            command,
+            ErrorCode::Validation,
+            format!("Invalid command data. {}", err),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rs_algo_shared::ws::message::CommandType;
+    use serde_json::json;
+
+    fn error_payload(reply: &str) -> Value {
+        let reply: Value = serde_json::from_str(reply).unwrap();
+        assert_eq!(reply["response"], "Error");
+        reply["payload"].clone()
+    }
+
+    #[test]
+    fn malformed_json_is_a_validation_error() {
+        for frame in ["", "{", "not json", "[1, 2]", r#"{"data": {}}"#] {
+            let reply = parse(frame).err().unwrap();
+            let error = error_payload(&reply);
+
+            assert_eq!(error["command"], UNKNOWN_COMMAND);
+            assert_eq!(error["code"], "Validation");
+        }
+    }
+
+    #[test]
+    fn unknown_command_is_a_validation_error() {
+        let reply = parse(r#"{"command": "DropDatabase", "data": null}"#)
+            .err()
+            .unwrap();
+
+        assert_eq!(error_payload(&reply)["code"], "Validation");
+    }
+
+    #[test]
+    fn parses_valid_command() {
+        let query =
+            parse(r#"{"command": "GetMarketHours", "data": {"symbol": "EURUSD"}}"#).unwrap();
+        let payload: SymbolPayload = payload(&query.command, query.data.as_ref()).unwrap();
+
+        assert_eq!(payload.symbol, "EURUSD");
+        assert_eq!(payload.strategy_name, "");
+    }
+
+    #[test]
+    fn missing_data_is_reported_with_the_command() {
+        for data in [None, Some(Value::Null)] {
+            let reply = payload::<SymbolPayload, _>(&CommandType::GetInstrumentTick, data.as_ref())
+                .unwrap_err();
+            let error = error_payload(&reply);
+
+            assert_eq!(error["command"], "GetInstrumentTick");
+            assert_eq!(error["code"], "Validation");
+        }
+    }
+
+    #[test]
+    fn wrong_field_types_are_rejected() {
+        let frames = [
+            json!({ "symbol": 42 }),
+            json!({ "symbol": "EURUSD", "time_frame": "H1", "num_bars": "many" }),
+            json!({ "time_frame": "H1" }),
+        ];
+
+        for data in frames {
+            let reply =
+                payload::<InstrumentDataPayload, _>(&CommandType::GetInstrumentData, Some(&data))
+                    .unwrap_err();
+
+            assert_eq!(error_payload(&reply)["code"], "Validation");
+        }
+    }
+
+    #[test]
+    fn malformed_positions_are_rejected() {
+        let data = json!({
+            "symbol": "EURUSD",
+            "strategy_name": "BollingerBandsReversals",
+            "options": { "non_profitable_out": false },
+            "data": { "MarketIn": "not a trade" },
+        });
+
+        let reply =
+            payload::<ExecutePositionPayload, _>(&CommandType::ExecutePosition, Some(&data))
+                .err()
+                .unwrap();
+
+        assert_eq!(error_payload(&reply)["command"], "ExecutePosition");
+    }
+
+    #[test]
+    fn malformed_orders_are_rejected() {
+        let data = json!({ "symbol": "EURUSD", "strategy_name": "Test", "data": {} });
+
+        let reply = payload::<ModifyOrderPayload, _>(ExtendedCommandType::ModifyOrder, Some(&data))
+            .err()
+            .unwrap();
+        let error = error_payload(&reply);
+
+        assert_eq!(error["command"], "ModifyOrder");
+        assert_eq!(error["code"], "Validation");
+    }
+
+    #[test]
+    fn extended_commands_are_told_apart() {
+        let query =
+            parse_extended(r#"{"command": "ModifyOrder", "data": {}, "request_id": 3}"#).unwrap();
+        assert_eq!(query.command, ExtendedCommandType::ModifyOrder);
+
+        for frame in [
+            r#"{"command": "GetMarketHours", "data": {"symbol": "EURUSD"}}"#,
+            r#"{"data": {}}"#,
+            "not json",
+        ] {
+            assert!(parse_extended(frame).is_none());
+        }
+    }
+}
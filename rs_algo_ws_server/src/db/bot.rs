@@ -1,12 +1,12 @@
 use mongodb::error::Error;
-use mongodb::options::{FindOneAndReplaceOptions, FindOneOptions};
-use mongodb::results::InsertOneResult;
+use mongodb::options::{FindOneOptions, UpdateOptions};
+use mongodb::results::{InsertOneResult, UpdateResult};
 pub use mongodb::Client;
 use rs_algo_shared::helpers::uuid::Uuid;
 use rs_algo_shared::models::bot::BotData;
 use std::env;
 
-use bson::doc;
+use bson::{doc, Document};
 
 pub struct Db {
     pub client: Client,
@@ -23,7 +23,7 @@ pub struct Db {
 //     client.database(db).collection::<T>(collection)
 // }
 
-pub async fn find_by_uuid(client: &Client, uuid: &Uuid) -> Option<BotData> {
+pub async fn find_by_uuid(client: &Client, uuid: &Uuid) -> Result<Option<BotData>, Error> {
     let db_name = &env::var("MONGO_BOT_DB_NAME").unwrap();
     let collection_name = &env::var("DB_BOT_COLLECTION").unwrap();
     let collection = client
@@ -33,7 +33,6 @@ pub async fn find_by_uuid(client: &Client, uuid: &Uuid) -> Option<BotData> {
     collection
         .find_one(doc! { "_id": uuid}, FindOneOptions::builder().build())
         .await
-        .unwrap()
 }
 
 pub async fn insert(client: &Client, bot_data: &BotData) -> Result<InsertOneResult, Error> {
@@ -46,20 +45,54 @@ pub async fn insert(client: &Client, bot_data: &BotData) -> Result<InsertOneResu
     collection.insert_one(bot_data, None).await
 }
 
-pub async fn upsert(client: &Client, doc: &BotData) -> Result<Option<BotData>, Error> {
+// Extra fields are bot values not modeled in BotData. They are stored next to
+// the bot document and sent back on InitSession.
+pub async fn find_extras(client: &Client, bot_data: &BotData) -> Result<Document, Error> {
     let db_name = &env::var("MONGO_BOT_DB_NAME").unwrap();
     let collection_name = &env::var("DB_BOT_COLLECTION").unwrap();
     let collection = client
         .database(db_name)
-        .collection::<BotData>(collection_name);
+        .collection::<Document>(collection_name);
+
+    let known_fields = bson::to_document(bot_data).unwrap_or_default();
+
+    let extras = match collection
+        .find_one(
+            doc! { "_id": bot_data.uuid()},
+            FindOneOptions::builder().build(),
+        )
+        .await?
+    {
+        Some(raw) => raw
+            .into_iter()
+            .filter(|(key, _)| !known_fields.contains_key(key))
+            .collect(),
+        None => Document::new(),
+    };
+
+    Ok(extras)
+}
+
+pub async fn upsert(
+    client: &Client,
+    doc: &BotData,
+    extras: Document,
+) -> Result<UpdateResult, Error> {
+    let db_name = &env::var("MONGO_BOT_DB_NAME").unwrap();
+    let collection_name = &env::var("DB_BOT_COLLECTION").unwrap();
+    let collection = client
+        .database(db_name)
+        .collection::<Document>(collection_name);
+
+    let mut fields = bson::to_document(doc)?;
+    fields.remove("_id");
+    fields.extend(extras);
 
     collection
-        .find_one_and_replace(
+        .update_one(
             doc! {"_id": *doc.uuid()},
-            doc,
-            FindOneAndReplaceOptions::builder()
-                .upsert(Some(true))
-                .build(),
+            doc! {"$set": fields},
+            UpdateOptions::builder().upsert(Some(true)).build(),
         )
         .await
 }
@@ -1,3 +1,4 @@
 pub mod bot;
 pub mod mongo;
+pub mod portfolio;
 //pub mod session;
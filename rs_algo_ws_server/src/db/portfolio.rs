@@ -0,0 +1,46 @@
+use crate::handlers::state::Portfolio;
+
+use bson::doc;
+use mongodb::error::Error;
+use mongodb::options::FindOneOptions;
+use mongodb::results::InsertOneResult;
+pub use mongodb::Client;
+use mongodb::Collection;
+use std::env;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PortfolioDbError {
+    #[error("{0} env var not found")]
+    EnvVarNotFound(&'static str),
+    #[error(transparent)]
+    Mongo(#[from] Error),
+}
+
+fn collection(client: &Client) -> Result<Collection<Portfolio>, PortfolioDbError> {
+    let db_name = env::var("MONGO_BOT_DB_NAME")
+        .map_err(|_| PortfolioDbError::EnvVarNotFound("MONGO_BOT_DB_NAME"))?;
+    let collection_name = env::var("DB_PORTFOLIO_COLLECTION")
+        .map_err(|_| PortfolioDbError::EnvVarNotFound("DB_PORTFOLIO_COLLECTION"))?;
+
+    Ok(client
+        .database(&db_name)
+        .collection::<Portfolio>(&collection_name))
+}
+
+pub async fn insert_snapshot(
+    client: &Client,
+    portfolio: &Portfolio,
+) -> Result<InsertOneResult, PortfolioDbError> {
+    Ok(collection(client)?.insert_one(portfolio, None).await?)
+}
+
+// Snapshots are only inserted, so the last ObjectId is the last one
+pub async fn find_last_snapshot(client: &Client) -> Result<Option<Portfolio>, PortfolioDbError> {
+    Ok(collection(client)?
+        .find_one(
+            None,
+            FindOneOptions::builder().sort(doc! { "_id": -1 }).build(),
+        )
+        .await?)
+}
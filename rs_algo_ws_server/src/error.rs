@@ -1,6 +1,9 @@
 pub use rs_algo_shared::error::RsAlgoError;
 use rs_algo_shared::ws::message::CommandType;
 
+use serde::Serialize;
+use std::fmt::{Debug, Display};
+use std::time::Duration;
 use thiserror::Error;
 
 pub type Result<T> = ::anyhow::Result<T, RsAlgoError>;
@@ -21,6 +24,28 @@ pub enum RsAlgoErrorKind {
     InvalidPeak,
     #[error("Error on Request!")]
     RequestError,
+    #[error("Invalid TLS config!")]
+    TlsError,
+    #[error("Invalid env var!")]
+    InvalidEnvVar,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize)]
+pub enum ErrorCode {
+    BrokerRejected,
+    Timeout,
+    Validation,
+    DbFailure,
+    Unauthorized,
+    /// Not supported by the broker
+    Unsupported,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse<C> {
+    pub command: C,
+    pub code: ErrorCode,
+    pub message: String,
 }
 
 // #[derive(Debug, Error)]
@@ -55,6 +80,47 @@ pub fn executed_command(
     err: rs_algo_shared::error::RsAlgoError,
     command: &CommandType,
 ) -> Option<String> {
-    log::error!("{:?} Command error {}", command, err);
-    None
+    Some(response(command, ErrorCode::BrokerRejected, err))
+}
+
+pub fn broker_unavailable(
+    err: rs_algo_shared::error::RsAlgoError,
+    command: &CommandType,
+) -> String {
+    response(
+        command,
+        ErrorCode::BrokerRejected,
+        format!("Broker not available. {}", err),
+    )
+}
+
+pub fn timeout<C>(command: C, timeout: Duration) -> String
+where
+    C: Serialize + Debug,
+{
+    response(
+        command,
+        ErrorCode::Timeout,
+        format!("No broker response after {:?}", timeout),
+    )
+}
+
+/// Error reply sent to the bot so it never waits for a response that is not
+/// coming.
+pub fn response<C, M>(command: C, code: ErrorCode, message: M) -> String
+where
+    C: Serialize + Debug,
+    M: Display,
+{
+    log::error!("{:?} {:?} error. {}", command, code, message);
+
+    serde_json::json!({
+        "response": "Error",
+        "payload": ErrorResponse {
+            command,
+            code,
+            message: message.to_string(),
+        }
+    })
+    .to_string()
 }
@@ -0,0 +1,285 @@
+use rs_algo_shared::models::trade::TradeIn;
+
+use std::collections::HashMap;
+use std::env;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ExposureError {
+    #[error("{0} open positions reached")]
+    MaxPositions(usize),
+    #[error("{0} total lots reached")]
+    MaxLots(f64),
+    #[error("{currency} net exposure {net} over {max} lots")]
+    MaxCurrency {
+        currency: String,
+        net: f64,
+        max: f64,
+    },
+}
+
+/// Server wide limits, disabled when their env var is not set.
+#[derive(Debug, Clone, Default)]
+pub struct ExposureLimits {
+    pub max_positions: Option<usize>,
+    pub max_lots: Option<f64>,
+    pub max_currency_lots: Option<f64>,
+}
+
+impl ExposureLimits {
+    pub fn from_env() -> Self {
+        Self {
+            max_positions: env_var("MAX_OPEN_POSITIONS"),
+            max_lots: env_var("MAX_TOTAL_LOTS"),
+            max_currency_lots: env_var("MAX_CURRENCY_EXPOSURE"),
+        }
+    }
+}
+
+fn env_var<T: std::str::FromStr>(key: &str) -> Option<T> {
+    env::var(key).ok().and_then(|value| value.parse::<T>().ok())
+}
+
+#[derive(Debug, Clone)]
+struct OpenPosition {
+    id: usize,
+    symbol: String,
+    size: f64,
+    is_long: bool,
+}
+
+impl OpenPosition {
+    fn new(symbol: &str, trade_in: &TradeIn) -> Self {
+        Self {
+            id: trade_in.id,
+            symbol: symbol.to_owned(),
+            size: trade_in.size,
+            is_long: trade_in.trade_type.is_long_entry(),
+        }
+    }
+}
+
+/// Open positions by bot uuid
+#[derive(Debug, Clone)]
+pub struct Exposure {
+    limits: ExposureLimits,
+    positions: HashMap<String, Vec<OpenPosition>>,
+}
+
+impl Exposure {
+    pub fn new(limits: ExposureLimits) -> Self {
+        Self {
+            limits,
+            positions: HashMap::new(),
+        }
+    }
+
+    /// Counts the entry as open if it is within the limits, so entries sent
+    /// at the same time can't exceed them together. Released with `close`
+    /// when the broker doesn't fill it.
+    pub fn reserve(
+        &mut self,
+        bot_id: &str,
+        symbol: &str,
+        trade_in: &TradeIn,
+    ) -> Result<(), ExposureError> {
+        let position = OpenPosition::new(symbol, trade_in);
+        self.check(&position)?;
+        self.insert(bot_id, position);
+        Ok(())
+    }
+
+    fn check(&self, new_position: &OpenPosition) -> Result<(), ExposureError> {
+        let positions: Vec<&OpenPosition> = self.positions.values().flatten().collect();
+
+        if let Some(max) = self.limits.max_positions {
+            if positions.len() >= max {
+                return Err(ExposureError::MaxPositions(max));
+            }
+        }
+
+        if let Some(max) = self.limits.max_lots {
+            let lots: f64 = positions.iter().map(|position| position.size).sum();
+            if lots + new_position.size > max {
+                return Err(ExposureError::MaxLots(max));
+            }
+        }
+
+        if let (Some(max), Some(legs)) = (
+            self.limits.max_currency_lots,
+            currency_legs(&new_position.symbol),
+        ) {
+            for (currency, delta) in leg_exposure(legs, new_position.size, new_position.is_long) {
+                let net: f64 = positions
+                    .iter()
+                    .filter_map(|position| {
+                        currency_legs(&position.symbol).map(|legs| {
+                            leg_exposure(legs, position.size, position.is_long)
+                                .into_iter()
+                                .filter(|(leg, _)| *leg == currency)
+                                .map(|(_, exposure)| exposure)
+                                .sum::<f64>()
+                        })
+                    })
+                    .sum();
+
+                // Trades reducing the net exposure are always allowed
+                let new_net = net + delta;
+                if new_net.abs() > max && new_net.abs() > net.abs() {
+                    return Err(ExposureError::MaxCurrency {
+                        currency: currency.to_owned(),
+                        net: new_net,
+                        max,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn insert(&mut self, bot_id: &str, position: OpenPosition) {
+        self.positions
+            .entry(bot_id.to_owned())
+            .or_default()
+            .push(position);
+    }
+
+    pub fn close(&mut self, bot_id: &str, id: usize) {
+        if let Some(positions) = self.positions.get_mut(bot_id) {
+            positions.retain(|position| position.id != id);
+        }
+    }
+
+    /// Drops the positions of a disconnected bot, so a crashed one doesn't
+    /// block entries. They are synced again when it reconnects.
+    pub fn release_bot(&mut self, bot_id: &str) {
+        self.positions.remove(bot_id);
+    }
+
+    /// Replaces the bot positions with the open trades it reports, so the
+    /// exposure is rebuilt after a server restart.
+    pub fn sync_bot(&mut self, bot_id: &str, symbol: &str, open_trades: &[TradeIn]) {
+        self.positions.remove(bot_id);
+        for trade_in in open_trades {
+            self.insert(bot_id, OpenPosition::new(symbol, trade_in));
+        }
+    }
+}
+
+/// Base and quote currencies of six letter forex symbols like EURUSD.
+fn currency_legs(symbol: &str) -> Option<(&str, &str)> {
+    match symbol.len() == 6 && symbol.chars().all(|c| c.is_ascii_uppercase()) {
+        true => Some((&symbol[..3], &symbol[3..])),
+        false => None,
+    }
+}
+
+fn leg_exposure<'a>(
+    (base, quote): (&'a str, &'a str),
+    size: f64,
+    is_long: bool,
+) -> [(&'a str, f64); 2] {
+    match is_long {
+        true => [(base, size), (quote, -size)],
+        false => [(base, -size), (quote, size)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(id: usize, symbol: &str, size: f64, is_long: bool) -> OpenPosition {
+        OpenPosition {
+            id,
+            symbol: symbol.to_owned(),
+            size,
+            is_long,
+        }
+    }
+
+    fn exposure(max_currency_lots: f64) -> Exposure {
+        Exposure::new(ExposureLimits {
+            max_currency_lots: Some(max_currency_lots),
+            ..ExposureLimits::default()
+        })
+    }
+
+    #[test]
+    fn forex_symbols_have_two_legs() {
+        assert_eq!(currency_legs("EURUSD"), Some(("EUR", "USD")));
+        assert_eq!(currency_legs("US500"), None);
+        assert_eq!(currency_legs("eurusd"), None);
+    }
+
+    #[test]
+    fn shorts_sell_the_base_and_buy_the_quote() {
+        assert_eq!(
+            leg_exposure(("EUR", "USD"), 1., true),
+            [("EUR", 1.), ("USD", -1.)]
+        );
+        assert_eq!(
+            leg_exposure(("EUR", "USD"), 1., false),
+            [("EUR", -1.), ("USD", 1.)]
+        );
+    }
+
+    #[test]
+    fn shared_legs_add_up_across_symbols() {
+        let mut exposure = exposure(1.5);
+        exposure.insert("a", position(1, "EURUSD", 1., true));
+
+        // Short USD twice
+        let err = exposure
+            .check(&position(2, "GBPUSD", 1., true))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ExposureError::MaxCurrency { ref currency, net, .. } if currency == "USD" && net == -2.
+        ));
+
+        // Long USD offsets the EURUSD leg
+        assert!(exposure.check(&position(2, "USDJPY", 1., true)).is_ok());
+    }
+
+    #[test]
+    fn trades_reducing_the_exposure_are_allowed() {
+        let mut exposure = exposure(1.);
+        exposure.insert("a", position(1, "EURUSD", 2., true));
+
+        assert!(exposure.check(&position(2, "EURUSD", 1., false)).is_ok());
+        assert!(exposure.check(&position(2, "EURGBP", 1., true)).is_err());
+    }
+
+    #[test]
+    fn closed_positions_release_their_exposure() {
+        let mut exposure = Exposure::new(ExposureLimits {
+            max_positions: Some(1),
+            ..ExposureLimits::default()
+        });
+        exposure.insert("a", position(1, "EURUSD", 1., true));
+        assert!(matches!(
+            exposure.check(&position(2, "GBPUSD", 1., true)),
+            Err(ExposureError::MaxPositions(1))
+        ));
+
+        exposure.close("a", 1);
+        assert!(exposure.check(&position(2, "GBPUSD", 1., true)).is_ok());
+    }
+
+    #[test]
+    fn disconnected_bots_release_their_exposure() {
+        let mut exposure = Exposure::new(ExposureLimits {
+            max_positions: Some(2),
+            ..ExposureLimits::default()
+        });
+        exposure.insert("a", position(1, "EURUSD", 1., true));
+        exposure.insert("a", position(2, "USDJPY", 1., true));
+        exposure.insert("b", position(1, "GBPUSD", 1., true));
+
+        exposure.release_bot("a");
+        assert!(exposure.check(&position(3, "AUDUSD", 1., true)).is_ok());
+        assert!(exposure.positions.get("b").is_some());
+    }
+}
@@ -1,3 +1,4 @@
+pub mod exposure;
 pub mod session;
 pub mod state;
 pub mod stream;
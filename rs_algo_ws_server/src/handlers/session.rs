@@ -1,3 +1,5 @@
+use crate::auth::Grant;
+
 use rs_algo_shared::helpers::date::*;
 use rs_algo_shared::helpers::uuid::*;
 use rs_algo_shared::models::market::MarketHours;
@@ -41,13 +43,16 @@ pub struct Session {
     pub last_ping: DateTime<Local>,
     pub last_data: DateTime<Local>,
     pub client_status: SessionStatus,
+    pub grant: Grant,
+    // Set on InitSession. Trading commands are only accepted for the bound
+    // symbol and strategy.
+    bound: bool,
 }
 
 pub type Sessions = Arc<Mutex<HashMap<SocketAddr, Session>>>;
-pub type Sessions2 = HashMap<SocketAddr, Session>;
 
 impl Session {
-    pub fn new(recipient: UnboundedSender<Message>) -> Self {
+    pub fn new(recipient: UnboundedSender<Message>, grant: Grant) -> Self {
         Self {
             session_id: mongodb::bson::uuid::Uuid::new(),
             recipient,
@@ -60,9 +65,24 @@ impl Session {
             last_ping: Local::now(),
             last_data: Local::now(),
             client_status: SessionStatus::Up,
+            grant,
+            bound: false,
         }
     }
 
+    pub fn bind(&mut self, symbol: &str, time_frame: &str, strategy: &str) -> &Self {
+        self.update_bot_name(symbol, time_frame, strategy);
+        self.bound = true;
+        self
+    }
+
+    pub fn trades(&self, symbol: &str, strategy: &str) -> bool {
+        self.bound
+            && self.symbol == symbol
+            && self.strategy == strategy
+            && self.grant.allows_trading(symbol, strategy)
+    }
+
     pub fn update_bot_name(&mut self, symbol: &str, time_frame: &str, strategy: &str) -> &Self {
         self.symbol = symbol.to_owned();
         self.time_frame = TimeFrame::new(time_frame);
@@ -134,7 +154,8 @@ impl Session {
 //     };
 // }
 
-pub async fn find<'a, F>(sessions: &'a mut Sessions, addr: &SocketAddr, callback: F)
+/// Returns false when the session is already gone
+pub async fn find<'a, F>(sessions: &'a mut Sessions, addr: &SocketAddr, callback: F) -> bool
 where
     F: Send + FnOnce(&mut Session),
     // F: 'static + Send + FnMut(Message) -> T,
@@ -142,17 +163,24 @@ where
 {
     let mut sessions_guard = sessions.lock().await;
     match sessions_guard.get_mut(addr) {
-        Some(session) => callback(&mut *session),
-        None => panic!("Session not found!"),
-    };
+        Some(session) => {
+            callback(&mut *session);
+            true
+        }
+        None => {
+            log::warn!("Session {} not found", addr);
+            false
+        }
+    }
 }
 
 pub async fn create<'a>(
     sessions: &'a mut Sessions,
     addr: &SocketAddr,
     recipient: UnboundedSender<tungstenite::Message>,
+    grant: Grant,
 ) -> Session {
-    let session = Session::new(recipient);
+    let session = Session::new(recipient, grant);
 
     {
         sessions.lock().await.insert(*addr, session.clone());
@@ -187,3 +215,45 @@ pub async fn destroy<'a>(sessions: &'a mut Sessions, addr: &SocketAddr) {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_channel::mpsc::unbounded;
+
+    fn session(grant: Grant) -> Session {
+        let (recipient, _) = unbounded();
+        Session::new(recipient, grant)
+    }
+
+    fn grant(strategies: &[&str]) -> Grant {
+        Grant {
+            symbols: vec!["EURUSD".to_owned()],
+            strategies: strategies.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn unbound_sessions_do_not_trade() {
+        assert!(!session(grant(&["*"])).trades("EURUSD", "BB_Reversals"));
+    }
+
+    #[test]
+    fn bound_sessions_only_trade_their_strategy() {
+        let mut session = session(grant(&["*"]));
+        session.bind("EURUSD", "M15", "BB_Reversals");
+
+        assert!(session.trades("EURUSD", "BB_Reversals"));
+        assert!(!session.trades("EURUSD", "BB_Reversals_Sell"));
+        assert!(!session.trades("EURUSD", ""));
+        assert!(!session.trades("GBPUSD", "BB_Reversals"));
+    }
+
+    #[test]
+    fn bound_strategies_still_need_the_grant() {
+        let mut session = session(grant(&["BB_Reversals"]));
+        session.bind("EURUSD", "M15", "BB_Reversals_Sell");
+
+        assert!(!session.trades("EURUSD", "BB_Reversals_Sell"));
+    }
+}
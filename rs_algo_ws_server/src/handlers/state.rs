@@ -1,67 +1,241 @@
+use super::exposure::{Exposure, ExposureLimits};
+use super::stream::StreamHub;
+use crate::error::RsAlgoErrorKind;
+
 use rs_algo_shared::helpers::date::*;
+use rs_algo_shared::models::trade::{TradeIn, TradeOut};
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::{env, sync::Arc};
+use tokio::sync::Mutex;
 
+const MAX_EQUITY_POINTS: usize = 1000;
+
+/// Realized results of a single bot, in account currency. Exits are added
+/// once, as the bot only keeps its last trades.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BotSummary {
+    pub name: String,
+    pub profit: f64,
+    pub gross_profit: f64,
+    pub gross_loss: f64,
+    pub won_positions: usize,
+    pub lost_positions: usize,
+    /// Exit date of the last trade counted
+    #[serde(default)]
+    pub counted_until: Option<DateTime<Local>>,
+    pub last_updated: DateTime<Local>,
+}
 
+/// Conversion of trade profits, reported as price differences, to currency
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct PipValue {
+    #[serde(default)]
+    pub pip_value: f64,
+    #[serde(default)]
+    pub pip_size: f64,
+}
 
+impl PipValue {
+    fn is_valid(&self) -> bool {
+        self.pip_size.is_finite() && self.pip_size > 0. && self.pip_value.is_finite()
+    }
 
+    fn to_currency(self, price_diff: f64, size: f64) -> f64 {
+        price_diff / self.pip_size * self.pip_value * size
+    }
+}
+
+impl BotSummary {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            profit: 0.,
+            gross_profit: 0.,
+            gross_loss: 0.,
+            won_positions: 0,
+            lost_positions: 0,
+            counted_until: None,
+            last_updated: Local::now(),
+        }
+    }
 
+    // Exits are sized with their TradeIn. The ones whose entry is no longer
+    // kept can't be converted and are skipped.
+    fn add_exits(&mut self, trades_in: &[TradeIn], trades_out: &[TradeOut], pip_value: PipValue) {
+        for trade_out in trades_out {
+            let date_out = from_dbtime(&trade_out.date_out);
+            if matches!(self.counted_until, Some(until) if date_out <= until) {
+                continue;
+            }
 
+            let trade_in = trades_in
+                .iter()
+                .find(|trade_in| trade_in.id == trade_out.id);
 
+            if let Some(trade_in) = trade_in {
+                self.add_profit(pip_value.to_currency(trade_out.profit, trade_in.size));
+            }
 
-use std::{sync::Arc};
-use tokio::sync::Mutex;
+            self.counted_until = Some(date_out);
+        }
 
+        self.last_updated = Local::now();
+    }
 
+    fn add_profit(&mut self, profit: f64) {
+        if profit > 0. {
+            self.gross_profit += profit;
+            self.won_positions += 1;
+        } else if profit < 0. {
+            self.gross_loss += profit.abs();
+            self.lost_positions += 1;
+        }
 
-use super::session::Sessions2;
+        self.profit = self.gross_profit - self.gross_loss;
+    }
+}
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EquityPoint {
+    pub date: DateTime<Local>,
+    pub value: f64,
+}
+
+/// Saved in snapshots, the last one is restored on start
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Portfolio {
     initial_value: f64,
     current_value: f64,
     cash_balance: f64,
+    peak_value: f64,
     profit: f64,
     profit_per: f64,
     profit_factor: f64,
     winrate: f64,
-    drawdown: usize,
+    drawdown: f64,
+    max_drawdown: f64,
     won_positions: usize,
     lost_positions: usize,
+    bots: HashMap<String, BotSummary>,
+    equity_curve: VecDeque<EquityPoint>,
     last_updated: DateTime<Local>,
 }
 
-impl Default for Portfolio {
-    fn default() -> Self {
+impl Portfolio {
+    pub fn new(initial_value: f64) -> Self {
         Portfolio {
-            initial_value: 0.0,
-            current_value: 0.0,
-            cash_balance: 0.0,
+            initial_value,
+            current_value: initial_value,
+            cash_balance: initial_value,
+            peak_value: initial_value,
             profit: 0.0,
             profit_per: 0.0,
             profit_factor: 0.0,
             winrate: 0.0,
-            drawdown: 0,
+            drawdown: 0.0,
+            max_drawdown: 0.0,
             won_positions: 0,
             lost_positions: 0,
+            bots: HashMap::new(),
+            equity_curve: VecDeque::new(),
             last_updated: Local::now(),
         }
     }
+
+    pub fn initial_value_from_env() -> Result<f64, RsAlgoErrorKind> {
+        match env::var("PORTFOLIO_INITIAL_VALUE") {
+            Ok(value) => value.parse::<f64>().map_err(|_| {
+                log::error!("Invalid PORTFOLIO_INITIAL_VALUE {:?}", value);
+                RsAlgoErrorKind::InvalidEnvVar
+            }),
+            Err(_) => Ok(10000.),
+        }
+    }
+
+    pub fn last_updated(&self) -> DateTime<Local> {
+        self.last_updated
+    }
+
+    /// Exits are only counted once the bot reports its pip value
+    pub fn update_bot(
+        &mut self,
+        bot_id: &str,
+        name: &str,
+        trades_in: &[TradeIn],
+        trades_out: &[TradeOut],
+        pip_value: PipValue,
+    ) {
+        if !pip_value.is_valid() {
+            return;
+        }
+
+        self.bots
+            .entry(bot_id.to_owned())
+            .or_insert_with(|| BotSummary::new(name))
+            .add_exits(trades_in, trades_out, pip_value);
+
+        let (gross_profit, gross_loss) =
+            self.bots.values().fold((0., 0.), |(profit, loss), bot| {
+                (profit + bot.gross_profit, loss + bot.gross_loss)
+            });
+
+        self.profit = gross_profit - gross_loss;
+        self.won_positions = self.bots.values().map(|bot| bot.won_positions).sum();
+        self.lost_positions = self.bots.values().map(|bot| bot.lost_positions).sum();
+        self.current_value = self.initial_value + self.profit;
+        self.cash_balance = self.current_value;
+        self.profit_per = self.profit / self.initial_value * 100.;
+        self.profit_factor = match gross_loss > 0. {
+            true => gross_profit / gross_loss,
+            false => 0.,
+        };
+        self.winrate = match self.won_positions + self.lost_positions {
+            0 => 0.,
+            total => self.won_positions as f64 / total as f64 * 100.,
+        };
+
+        self.peak_value = self.peak_value.max(self.current_value);
+        self.drawdown = (self.peak_value - self.current_value) / self.peak_value * 100.;
+        self.max_drawdown = self.max_drawdown.max(self.drawdown);
+        self.last_updated = Local::now();
+
+        let value_changed = self
+            .equity_curve
+            .back()
+            .map(|point| point.value != self.current_value)
+            .unwrap_or(true);
+
+        if value_changed {
+            if self.equity_curve.len() == MAX_EQUITY_POINTS {
+                self.equity_curve.pop_front();
+            }
+            self.equity_curve.push_back(EquityPoint {
+                date: self.last_updated,
+                value: self.current_value,
+            });
+        }
+    }
 }
 
 pub type AppState = Arc<Mutex<State>>;
 
 #[derive(Debug, Clone)]
 pub struct State {
-    pub sessions: Sessions2,
     pub portfolio: Portfolio,
+    pub exposure: Exposure,
+    pub streams: StreamHub,
 }
 
 impl State {
-    pub fn new() -> AppState {
-        let portfolio = Portfolio::default();
+    pub fn new(portfolio: Portfolio) -> AppState {
+        let exposure = Exposure::new(ExposureLimits::from_env());
 
         Arc::new(Mutex::new(State {
             portfolio,
-            sessions: Sessions2::new(),
+            exposure,
+            streams: StreamHub::default(),
         }))
     }
 }
@@ -207,3 +381,37 @@ impl State {
 //         }
 //     };
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f64 = 1e-9;
+
+    #[test]
+    fn price_differences_are_converted_to_currency() {
+        let pip_value = PipValue {
+            pip_value: 10.,
+            pip_size: 0.0001,
+        };
+
+        // 20 pips of half a lot at 10 per pip and lot
+        assert!((pip_value.to_currency(0.002, 0.5) - 100.).abs() < EPSILON);
+        assert!(pip_value.is_valid());
+        assert!(!PipValue::default().is_valid());
+    }
+
+    #[test]
+    fn bot_profits_are_running_totals() {
+        let mut bot = BotSummary::new("EURUSD_H1_BB_Reversals");
+
+        bot.add_profit(100.);
+        bot.add_profit(-40.);
+        bot.add_profit(0.);
+
+        assert_eq!(bot.profit, 60.);
+        assert_eq!(bot.gross_profit, 100.);
+        assert_eq!(bot.gross_loss, 40.);
+        assert_eq!((bot.won_positions, bot.lost_positions), (1, 1));
+    }
+}
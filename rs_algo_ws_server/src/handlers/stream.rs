@@ -1,122 +1,220 @@
-use crate::error::RsAlgoErrorKind;
+use crate::broker::pool::BrokerPool;
+use crate::broker::{self, paper, BrokerKind};
 use crate::handlers::session::Session;
+use crate::handlers::state::AppState;
 use crate::message;
+use crate::settings;
+use rs_algo_shared::broker::xtb_stream::*;
 pub use rs_algo_shared::broker::BrokerStream;
+use rs_algo_shared::error::Result;
 use rs_algo_shared::helpers::date::Local;
-use rs_algo_shared::{broker::xtb_stream::*, models::environment};
 
 use futures_util::StreamExt;
 use rs_algo_shared::ws::message::ReconnectOptions;
-use std::env;
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::Sender;
-use tokio::sync::Mutex;
 use tokio::time;
-use tungstenite::{Error, Message};
+use tungstenite::Message;
 
-async fn initialize_broker_stream(symbol: &str) -> Result<Xtb, RsAlgoErrorKind> {
-    let env = environment::from_str(&env::var("ENV").unwrap());
-    let username = env::var("BROKER_USERNAME").map_err(|_| RsAlgoErrorKind::EnvVarNotFound)?;
-    let password = env::var("BROKER_PASSWORD").map_err(|_| RsAlgoErrorKind::EnvVarNotFound)?;
-
-    let symbol = symbol.to_string();
-    let mut broker_stream = Xtb::new().await;
+async fn initialize_broker_stream<BK>(symbol: &str, pool: &BrokerPool<BK>) -> Result<Xtb>
+where
+    BK: BrokerStream + Send + Sync + 'static,
+{
+    let env = settings::get().env();
+    let mut broker_stream = pool.login::<Xtb>().await?;
 
-    broker_stream.login(&username, &password).await.unwrap();
     broker_stream
-        .get_instrument_data(&symbol, 1, Local::now().timestamp())
-        .await
-        .unwrap();
+        .get_instrument_data(symbol, 1, Local::now().timestamp())
+        .await?;
 
-    broker_stream.subscribe_stream(&symbol).await.unwrap();
-    broker_stream.subscribe_tick_prices(&symbol).await.unwrap();
+    broker_stream.subscribe_stream(symbol).await?;
+    broker_stream.subscribe_tick_prices(symbol).await?;
 
     if env.is_prod() {
-        broker_stream.subscribe_trades(&symbol).await.unwrap();
+        broker_stream.subscribe_trades(symbol).await?;
     }
 
     Ok(broker_stream)
 }
 
-pub async fn handle_strean_data<BK: BrokerStream + Send + 'static>(
-    tx: &Sender<()>,
-    session: &Session,
-    data: Result<Message, Error>,
-) {
-    match data {
-        Ok(msg) => {
-            if msg.is_text() {
-                let symbol = session.symbol.as_ref();
-                let strategy_name = session.strategy.as_ref();
-                let parsed = BK::parse_stream_data(msg, &symbol, &strategy_name).await;
-                match parsed {
-                    Some(txt) => match message::send(session, Message::Text(txt)).await {
-                        Ok(_) => (),
-                        Err(_) => {
-                            log::error!("Can't send stream data to {:?}", session.bot_name());
-                            tx.send(()).await.unwrap();
-                        }
-                    },
-                    None => (),
-                };
-            } else if msg.is_close() {
-                log::error!("Stream closed by broker");
-                message::send_reconnect(session, ReconnectOptions { clean_data: true }).await;
-                tx.send(()).await.unwrap();
-            }
+#[derive(Debug, Clone)]
+struct SymbolStream {
+    subscribers: HashMap<SocketAddr, Session>,
+    stop: Sender<()>,
+}
+
+/// One broker stream per symbol, shared by every session trading it. The
+/// stream is closed when its last subscriber is gone.
+#[derive(Debug, Clone, Default)]
+pub struct StreamHub {
+    symbols: HashMap<String, SymbolStream>,
+}
+
+impl StreamHub {
+    fn subscribers(&self, symbol: &str) -> Vec<(SocketAddr, Session)> {
+        match self.symbols.get(symbol) {
+            Some(stream) => stream
+                .subscribers
+                .iter()
+                .map(|(addr, session)| (*addr, session.clone()))
+                .collect(),
+            None => vec![],
         }
-        Err(err) => {
-            log::error!("Stream error {:?}", (err, &session));
+    }
+}
+
+pub async fn unsubscribe(state: &AppState, addr: &SocketAddr) {
+    let mut state = state.lock().await;
+    let streams = &mut state.streams.symbols;
+
+    for stream in streams.values_mut() {
+        stream.subscribers.remove(addr);
+    }
+
+    streams.retain(|symbol, stream| match stream.subscribers.is_empty() {
+        true => {
+            log::info!("No {} subscribers left. Closing broker stream", symbol);
+            stream.stop.try_send(()).ok();
+            false
+        }
+        false => true,
+    });
+}
+
+/// Stops every broker stream. Used on shutdown, once sessions were sent a
+/// reconnect.
+pub async fn close_all(state: &AppState) {
+    let mut state = state.lock().await;
+
+    for (symbol, stream) in state.streams.symbols.drain() {
+        log::info!("Closing {} broker stream", symbol);
+        stream.stop.try_send(()).ok();
+    }
+}
+
+// Sessions are sent a reconnect and subscribe again to a new broker stream
+async fn close_symbol_stream(state: &AppState, symbol: &str) {
+    let stream = state.lock().await.streams.symbols.remove(symbol);
+
+    if let Some(stream) = stream {
+        for session in stream.subscribers.values() {
             message::send_reconnect(session, ReconnectOptions { clean_data: true }).await;
-            tx.send(()).await.unwrap();
         }
     }
 }
 
-pub fn listen<BK>(broker: Arc<Mutex<BK>>, session: Session)
-where
-    BK: BrokerStream + Send + 'static,
+async fn fan_out<BK: BrokerStream + Send + 'static>(state: &AppState, symbol: &str, msg: Message) {
+    let subscribers = state.lock().await.streams.subscribers(symbol);
+
+    for (addr, session) in subscribers {
+        let parsed = BK::parse_stream_data(msg.clone(), &session.symbol, &session.strategy).await;
+
+        if let Some(txt) = parsed {
+            if message::send(&session, Message::Text(txt)).await.is_err() {
+                log::error!("Can't send stream data to {:?}", session.bot_name());
+                unsubscribe(state, &addr).await;
+            }
+        }
+    }
+}
+
+fn spawn_symbol_stream<BK>(
+    symbol: String,
+    state: AppState,
+    pool: Arc<BrokerPool<BK>>,
+    mut stop: mpsc::Receiver<()>,
+) where
+    BK: BrokerStream + Send + Sync + 'static,
 {
-    let (tx, mut rx) = mpsc::channel::<()>(1);
-
-    tokio::spawn({
-        async move {
-            let keepalive_interval = env::var("KEEPALIVE_INTERVAL")
-                .map_err(|_| RsAlgoErrorKind::EnvVarNotFound)
-                .unwrap()
-                .parse::<u64>()
-                .unwrap();
-
-            let symbol = session.symbol.as_ref();
-            let mut broker_stream = initialize_broker_stream(&symbol).await.unwrap();
-            let mut interval = time::interval(Duration::from_millis(keepalive_interval));
-
-            loop {
-                tokio::select! {
-                    stream = broker_stream.get_stream().await.next() => {
-                        match stream {
-                            Some(data) => {
-                                handle_strean_data::<BK>(&tx, &session, data).await;
-                            }
-                            None => {
-                                log::error!("No stream data");
-                                message::send_reconnect(&session, ReconnectOptions { clean_data: true }).await;
-                                tx.send(()).await.unwrap();
-                            }
+    tokio::spawn(async move {
+        let mut broker_stream = match initialize_broker_stream(&symbol, &pool).await {
+            Ok(broker_stream) => broker_stream,
+            Err(err) => {
+                log::error!("Can't open {} broker stream {:?}", symbol, err);
+                close_symbol_stream(&state, &symbol).await;
+                return;
+            }
+        };
+
+        let mut interval = time::interval(settings::get().keepalive_interval());
+
+        loop {
+            tokio::select! {
+                stream = broker_stream.get_stream().await.next() => {
+                    match stream {
+                        Some(Ok(msg)) if msg.is_text() => {
+                            fan_out::<BK>(&state, &symbol, msg).await;
+                        }
+                        Some(Ok(msg)) if msg.is_close() => {
+                            log::error!("{} stream closed by broker", symbol);
+                            close_symbol_stream(&state, &symbol).await;
+                            break;
+                        }
+                        Some(Ok(_)) => (),
+                        Some(Err(err)) => {
+                            log::error!("{} stream error {:?}", symbol, err);
+                            close_symbol_stream(&state, &symbol).await;
+                            break;
+                        }
+                        None => {
+                            log::error!("No {} stream data", symbol);
+                            close_symbol_stream(&state, &symbol).await;
+                            break;
                         }
                     }
-                    _ = interval.tick() => {
-                        broker_stream.keepalive_ping().await.unwrap();
-                        let mut guard = broker.lock().await;
-                        guard.keepalive_ping().await.unwrap();
-                    }
-                    _ = rx.recv() => {
-                        log::warn!("Stream {} stopped!", session.bot_name());
+                }
+                _ = interval.tick() => {
+                    if let Err(err) = broker_stream.keepalive_ping().await {
+                        log::error!("{} stream keepalive failed {}", symbol, err);
+                        close_symbol_stream(&state, &symbol).await;
                         break;
                     }
                 }
+                _ = stop.recv() => {
+                    log::warn!("Stream {} stopped!", symbol);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+pub fn listen<BK>(session: Session, addr: SocketAddr, state: AppState, pool: Arc<BrokerPool<BK>>)
+where
+    BK: BrokerStream + Send + Sync + 'static,
+{
+    if broker::kind() == BrokerKind::Paper {
+        paper::listen(session);
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut guard = state.lock().await;
+        let symbol = session.symbol.clone();
+
+        match guard.streams.symbols.get_mut(&symbol) {
+            Some(stream) => {
+                stream.subscribers.insert(addr, session);
+                log::info!(
+                    "{} stream shared by {} sessions",
+                    symbol,
+                    stream.subscribers.len()
+                );
+            }
+            None => {
+                let (stop_tx, stop_rx) = mpsc::channel::<()>(1);
+                guard.streams.symbols.insert(
+                    symbol.clone(),
+                    SymbolStream {
+                        subscribers: HashMap::from([(addr, session)]),
+                        stop: stop_tx,
+                    },
+                );
+                log::info!("Opening {} broker stream", symbol);
+                spawn_symbol_stream::<BK>(symbol, state.clone(), pool, stop_rx);
             }
         }
     });
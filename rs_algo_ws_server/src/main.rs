@@ -1,12 +1,19 @@
 use dotenv::dotenv;
 use std::{env, io::Error as IoError};
 
+mod auth;
+mod broker;
+mod command;
 mod db;
 mod error;
 mod handlers;
 mod heart_beat;
 mod message;
+mod portfolio;
 mod server;
+mod settings;
+mod shutdown;
+mod tls;
 
 #[tokio::main]
 async fn main() -> Result<(), IoError> {
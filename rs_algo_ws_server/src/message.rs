@@ -1,18 +1,32 @@
+use crate::broker::pool::{BrokerPool, Lane};
+use crate::broker::OrderModifier;
+use crate::command::{
+    self, BotPayload, ExecutePositionPayload, ExtendedCommand, ExtendedCommandType,
+    ExtendedResponse, InstrumentDataPayload, ModifyOrderPayload, SymbolPayload,
+};
 use crate::db;
 use crate::error;
+use crate::settings;
 
+use crate::handlers::exposure::ExposureError;
+use crate::handlers::state::{AppState, PipValue};
 use crate::handlers::*;
 use crate::handlers::{session::Session, session::Sessions};
 
+use bson::{Bson, Document};
 use rs_algo_shared::models::bot::BotData;
-use rs_algo_shared::models::mode;
 use rs_algo_shared::models::time_frame::*;
 use rs_algo_shared::models::trade::*;
 use rs_algo_shared::ws::message::*;
-use serde_json::Value;
+use serde::Serialize;
+use serde_json::{json, Value};
 use std::env;
-use std::{net::SocketAddr, sync::Arc};
+use std::fmt::Debug;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::sync::Mutex;
+use tokio::time;
 
 pub async fn send(
     session: &Session,
@@ -35,7 +49,9 @@ pub async fn send_reconnect(session: &Session, options: ReconnectOptions) {
         payload: Some(options),
     };
     let txt_msg = serde_json::to_string(&msg).unwrap();
-    send(session, Message::Text(txt_msg)).await.unwrap();
+    if send(session, Message::Text(txt_msg)).await.is_err() {
+        log::error!("Can't send Reconnect to {:?}", session.bot_name());
+    }
 }
 
 // pub async fn broadcast(sessions: &mut Sessions, _addr: &SocketAddr, msg: Message) {
@@ -52,14 +68,48 @@ pub async fn send_reconnect(session: &Session, options: ReconnectOptions) {
 
 pub async fn handle<'a, BK>(
     sessions: &'a mut Sessions,
+    state: &AppState,
     addr: &SocketAddr,
     msg: Message,
-    broker: Arc<Mutex<BK>>,
+    pool: &Arc<BrokerPool<BK>>,
     db_client: &mongodb::Client,
 ) -> Option<String>
 where
-    BK: stream::BrokerStream + Send + Sync + 'static,
+    BK: stream::BrokerStream + OrderModifier + Send + Sync + 'static,
 {
+    let request_id = match &msg {
+        Message::Text(msg) => serde_json::from_str::<Value>(msg)
+            .ok()
+            .map(|query| query["request_id"].clone())
+            .filter(|request_id| !request_id.is_null()),
+        _ => None,
+    };
+
+    let response = handle_command(sessions, state, addr, msg, pool, db_client).await;
+
+    match request_id {
+        Some(request_id) => response.map(|response| with_request_id(response, request_id)),
+        None => response,
+    }
+}
+
+async fn handle_command<'a, BK>(
+    sessions: &'a mut Sessions,
+    state: &AppState,
+    addr: &SocketAddr,
+    msg: Message,
+    pool: &Arc<BrokerPool<BK>>,
+    db_client: &mongodb::Client,
+) -> Option<String>
+where
+    BK: stream::BrokerStream + OrderModifier + Send + Sync + 'static,
+{
+    if let Message::Text(txt) = &msg {
+        if let Some(query) = command::parse_extended(txt) {
+            return handle_extended(sessions, addr, query, pool).await;
+        }
+    }
+
     let data = match msg {
         Message::Ping(_bytes) => {
             log::info!("Client Ping received from {addr}");
@@ -74,79 +124,129 @@ where
             None
         }
         Message::Text(msg) => {
-            let query: Command<Value> =
-                serde_json::from_str(&msg).expect("ERROR parsing Command JSON");
+            let query = match command::parse(&msg) {
+                Ok(query) => query,
+                Err(err) => return Some(err),
+            };
 
             let command = query.command;
+            let data = query.data.as_ref();
 
-            let symbol = match &query.data {
-                Some(data) => data["symbol"].as_str().unwrap(),
-                None => "",
+            let symbol_payload: SymbolPayload = match command::payload(&command, data) {
+                Ok(payload) => payload,
+                Err(err) => return Some(err),
             };
+            let symbol = symbol_payload.symbol.as_str();
+
+            if !is_authorized(sessions, addr, symbol, &symbol_payload.strategy_name).await {
+                return Some(error::response(
+                    &command,
+                    error::ErrorCode::Unauthorized,
+                    format!("Token not allowed to trade {}", symbol),
+                ));
+            }
 
             let data = match command {
                 CommandType::InitSession => {
-                    let session_data = match &query.data {
-                        Some(data) => {
-                            let bot: BotData = serde_json::from_value(data.clone()).unwrap();
-                            let uuid = bot.uuid();
-                            let symbol = data["symbol"].as_str().unwrap();
-                            let time_frame = data["time_frame"].as_str().unwrap();
-                            let strategy_name = data["strategy_name"].as_str().unwrap();
-                            let id = data["_id"].as_str().unwrap();
-
-                            let bot_data = match db::bot::find_by_uuid(db_client, uuid).await {
-                                Some(bot) => {
-                                    log::info!(
-                                        "Restoring session data for {}_{} {}",
-                                        symbol,
-                                        time_frame,
-                                        id
-                                    );
-                                    bot
-                                }
-                                None => {
-                                    db::bot::insert(db_client, &bot).await.unwrap();
-                                    log::info!(
-                                        "Creating session data for {}_{} {}",
-                                        symbol,
-                                        time_frame,
-                                        id
-                                    );
-                                    bot
-                                }
-                            };
-
-                            session::find(sessions, addr, |session| {
-                                *session = session
-                                    .update_bot_name(symbol, time_frame, strategy_name)
-                                    .clone();
-                            })
-                            .await;
+                    let bot: BotData = match command::payload(&command, data) {
+                        Ok(bot) => bot,
+                        Err(err) => return Some(err),
+                    };
+                    let BotPayload {
+                        symbol,
+                        time_frame,
+                        strategy_name,
+                    } = match command::payload(&command, data) {
+                        Ok(payload) => payload,
+                        Err(err) => return Some(err),
+                    };
+                    let uuid = bot.uuid();
+
+                    if strategy_name.is_empty() {
+                        return Some(error::response(
+                            &command,
+                            error::ErrorCode::Validation,
+                            "Missing strategy_name",
+                        ));
+                    }
 
-                            Some(bot_data)
+                    let bot_data = match db::bot::find_by_uuid(db_client, uuid).await {
+                        Ok(Some(bot)) => {
+                            log::info!(
+                                "Restoring session data for {}_{} {}",
+                                symbol,
+                                time_frame,
+                                uuid
+                            );
+                            bot
                         }
-                        None => None,
-                    }
-                    .unwrap();
+                        Ok(None) => {
+                            if let Err(err) = db::bot::insert(db_client, &bot).await {
+                                return Some(error::response(
+                                    &command,
+                                    error::ErrorCode::DbFailure,
+                                    err,
+                                ));
+                            }
+                            log::info!(
+                                "Creating session data for {}_{} {}",
+                                symbol,
+                                time_frame,
+                                uuid
+                            );
+                            bot
+                        }
+                        Err(err) => {
+                            return Some(error::response(
+                                &command,
+                                error::ErrorCode::DbFailure,
+                                err,
+                            ))
+                        }
+                    };
+
+                    session::find(sessions, addr, |session| {
+                        session.session_id = *uuid;
+                        *session = session.bind(&symbol, &time_frame, &strategy_name).clone();
+                    })
+                    .await;
 
+                    let extras = match db::bot::find_extras(db_client, &bot_data).await {
+                        Ok(extras) => extras,
+                        Err(err) => {
+                            return Some(error::response(
+                                &command,
+                                error::ErrorCode::DbFailure,
+                                err,
+                            ))
+                        }
+                    };
                     let response = ResponseBody {
                         response: ResponseType::InitSession,
-                        payload: Some(session_data),
+                        payload: Some(with_extras(&bot_data, extras)),
                     };
 
                     match serde_json::to_string(&response) {
-                        Ok(s) => Some(s),
-                        Err(e) => {
-                            log::error!("Failed to serialize {:?} response: {:?}", command, e);
-                            None
-                        }
+                        Ok(json_res) => Some(json_res),
+                        Err(e) => Some(error::serialization(e, &command)),
                     }
                 }
                 CommandType::GetMarketHours => {
+                    let broker = match pool.get(Lane::Data).await {
+                        Ok(broker) => broker,
+                        Err(err) => return Some(error::broker_unavailable(err, &command)),
+                    };
+
                     log::info!("Requesting {} trading hours", symbol);
 
-                    let response = broker.lock().await.get_market_hours(symbol).await;
+                    let response = match with_timeout(&command, pool, &broker, async {
+                        broker.lock().await.get_market_hours(symbol).await
+                    })
+                    .await
+                    {
+                        Ok(response) => response,
+                        Err(err) => return Some(err),
+                    };
 
                     match response {
                         Ok(res) => {
@@ -159,7 +259,13 @@ where
                                     })
                                     .await;
                                 }
-                                None => todo!(),
+                                None => {
+                                    return Some(error::response(
+                                        &command,
+                                        error::ErrorCode::BrokerRejected,
+                                        "No market hours received",
+                                    ))
+                                }
                             };
 
                             match serde_json::to_string(&res) {
@@ -171,8 +277,20 @@ where
                     }
                 }
                 CommandType::IsMarketOpen => {
+                    let broker = match pool.get(Lane::Data).await {
+                        Ok(broker) => broker,
+                        Err(err) => return Some(error::broker_unavailable(err, &command)),
+                    };
+
                     log::info!("Checking {} market is open", symbol);
-                    let response = broker.lock().await.is_market_open(symbol).await;
+                    let response = match with_timeout(&command, pool, &broker, async {
+                        broker.lock().await.is_market_open(symbol).await
+                    })
+                    .await
+                    {
+                        Ok(response) => response,
+                        Err(err) => return Some(err),
+                    };
 
                     match response {
                         Ok(res) => match serde_json::to_string(&res) {
@@ -183,17 +301,34 @@ where
                     }
                 }
                 CommandType::GetInstrumentData => {
-                    let time_frame = match &query.data {
-                        Some(data) => TimeFrame::new(data["time_frame"].as_str().unwrap()),
-                        None => TimeFrameType::ERR,
+                    let payload: InstrumentDataPayload = match command::payload(&command, data) {
+                        Ok(payload) => payload,
+                        Err(err) => return Some(err),
+                    };
+
+                    let num_bars = match payload.num_bars.or_else(|| {
+                        env::var("NUM_BARS")
+                            .ok()
+                            .and_then(|num_bars| num_bars.parse::<i64>().ok())
+                    }) {
+                        Some(num_bars) => num_bars,
+                        None => {
+                            return Some(error::response(
+                                &command,
+                                error::ErrorCode::Validation,
+                                "Missing num_bars",
+                            ))
+                        }
                     };
 
-                    let num_bars = match &query.data {
-                        Some(data) => data["num_bars"].as_i64().unwrap(),
-                        None => env::var("NUM_BARS").unwrap().parse::<i64>().unwrap(),
+                    let broker = match pool.get(Lane::Data).await {
+                        Ok(broker) => broker,
+                        Err(err) => return Some(error::broker_unavailable(err, &command)),
                     };
 
-                    let execution_mode = mode::from_str(&env::var("EXECUTION_MODE").unwrap());
+                    let time_frame = TimeFrame::new(&payload.time_frame);
+
+                    let execution_mode = settings::get().execution_mode();
 
                     let time_frame_number = time_frame.to_number();
                     let time_frame_from =
@@ -206,15 +341,22 @@ where
                         (num_bars)
                     );
 
-                    let response = broker
-                        .lock()
-                        .await
-                        .get_instrument_data(
-                            symbol,
-                            time_frame_number as usize,
-                            time_frame_from.timestamp(),
-                        )
-                        .await;
+                    let response = match with_timeout(&command, pool, &broker, async {
+                        broker
+                            .lock()
+                            .await
+                            .get_instrument_data(
+                                symbol,
+                                time_frame_number as usize,
+                                time_frame_from.timestamp(),
+                            )
+                            .await
+                    })
+                    .await
+                    {
+                        Ok(response) => response,
+                        Err(err) => return Some(err),
+                    };
 
                     match response {
                         Ok(res) => match serde_json::to_string(&res) {
@@ -225,154 +367,189 @@ where
                     }
                 }
                 CommandType::ExecutePosition => {
-                    let json_response = match &query.data {
-                        Some(value) => {
-                            let mut broker_guard = broker.lock().await;
-                            let symbol = value["symbol"].as_str().unwrap();
-                            let strategy_name = value["strategy_name"].as_str().unwrap();
-                            let options: TradeOptions =
-                                serde_json::from_value(value["options"].clone()).unwrap();
-
-                            let position_result: PositionResult =
-                                serde_json::from_value(value["data"].clone()).unwrap();
-
-                            match position_result {
-                                PositionResult::MarketIn(
-                                    TradeResult::TradeIn(trade_in),
-                                    orders,
-                                ) => {
-                                    log::info!(
-                                        "{} TradeIn {} position received",
-                                        symbol,
-                                        trade_in.id
-                                    );
-
-                                    let trade_data =
-                                        TradeData::new(symbol, strategy_name, trade_in, options);
-                                    let trade_response =
-                                        broker_guard.open_trade(trade_data, orders).await;
-
-                                    match trade_response {
-                                        Ok(res) => match serde_json::to_string(&res) {
-                                            Ok(json_res) => Some(json_res),
-                                            Err(e) => Some(error::serialization(e, &command)),
-                                        },
-                                        Err(err) => {
-                                            log::error!("{:?} Command error {}", command, err);
-                                            None
-                                        }
-                                    }
-                                }
-                                PositionResult::MarketOut(TradeResult::TradeOut(trade_out)) => {
-                                    log::info!(
-                                        "{} TradeOut {} position received",
-                                        symbol,
-                                        trade_out.id
-                                    );
-
-                                    let trade_data =
-                                        TradeData::new(symbol, strategy_name, trade_out, options);
-                                    let trade_response = broker_guard.close_trade(trade_data).await;
-
-                                    match trade_response {
-                                        Ok(res) => match serde_json::to_string(&res) {
-                                            Ok(json_res) => Some(json_res),
-                                            Err(e) => Some(error::serialization(e, &command)),
-                                        },
-                                        Err(err) => {
-                                            log::error!("{:?} Command error {}", command, err);
-                                            None
-                                        }
-                                    }
-                                }
-                                PositionResult::MarketInOrder(
-                                    TradeResult::TradeIn(trade_in),
-                                    order,
-                                ) => {
-                                    log::info!(
-                                        "{} MarketInOerder {} position received",
-                                        symbol,
-                                        order.id
-                                    );
-
-                                    let trade_data = TradeData::new(
-                                        symbol,
-                                        strategy_name,
-                                        trade_in,
-                                        options.clone(),
-                                    );
-                                    let order_data =
-                                        TradeData::new(symbol, strategy_name, order, options);
-                                    let trade_response =
-                                        broker_guard.open_order(trade_data, order_data).await;
-
-                                    match trade_response {
-                                        Ok(res) => match serde_json::to_string(&res) {
-                                            Ok(json_res) => Some(json_res),
-                                            Err(e) => Some(error::serialization(e, &command)),
-                                        },
-                                        Err(err) => {
-                                            log::error!("{:?} Command error {}", command, err);
-                                            None
+                    let ExecutePositionPayload {
+                        symbol,
+                        strategy_name,
+                        options,
+                        data: position_result,
+                    } = match command::payload(&command, data) {
+                        Ok(payload) => payload,
+                        Err(err) => return Some(err),
+                    };
+                    let symbol = symbol.as_str();
+                    let strategy_name = strategy_name.as_str();
+
+                    if !is_bound(sessions, addr, symbol, strategy_name).await {
+                        return Some(error::response(
+                            &command,
+                            error::ErrorCode::Unauthorized,
+                            format!("Session not allowed to trade {}_{}", symbol, strategy_name),
+                        ));
+                    }
+
+                    let bot_id = bot_id(sessions, addr).await;
+
+                    let broker = match pool.get(Lane::Execution).await {
+                        Ok(broker) => broker,
+                        Err(err) => return Some(error::broker_unavailable(err, &command)),
+                    };
+
+                    match position_result {
+                        PositionResult::MarketIn(TradeResult::TradeIn(trade_in), orders) => {
+                            log::info!("{} TradeIn {} position received", symbol, trade_in.id);
+
+                            let reserved = state
+                                .lock()
+                                .await
+                                .exposure
+                                .reserve(&bot_id, symbol, &trade_in);
+                            if let Err(err) = reserved {
+                                return Some(reject_trade_in(symbol, trade_in, err));
+                            }
+
+                            let trade_data =
+                                TradeData::new(symbol, strategy_name, trade_in.clone(), options);
+                            let trade_response = with_timeout(&command, pool, &broker, async {
+                                broker.lock().await.open_trade(trade_data, orders).await
+                            })
+                            .await;
+                            release_unfilled(state, &bot_id, &trade_in, &trade_response).await;
+
+                            match trade_response {
+                                Ok(Ok(res)) => match serde_json::to_string(&res) {
+                                    Ok(json_res) => Some(json_res),
+                                    Err(e) => Some(error::serialization(e, &command)),
+                                },
+                                Ok(Err(e)) => error::executed_command(e, &command),
+                                Err(err) => Some(err),
+                            }
+                        }
+                        PositionResult::MarketOut(TradeResult::TradeOut(trade_out)) => {
+                            log::info!("{} TradeOut {} position received", symbol, trade_out.id);
+
+                            let id = trade_out.id;
+                            let trade_data =
+                                TradeData::new(symbol, strategy_name, trade_out, options);
+                            let trade_response =
+                                match with_timeout(&command, pool, &broker, async {
+                                    broker.lock().await.close_trade(trade_data).await
+                                })
+                                .await
+                                {
+                                    Ok(response) => response,
+                                    Err(err) => return Some(err),
+                                };
+
+                            match trade_response {
+                                Ok(res) => match serde_json::to_string(&res) {
+                                    Ok(json_res) => {
+                                        if is_accepted(&res.payload) {
+                                            state.lock().await.exposure.close(&bot_id, id);
                                         }
+                                        Some(json_res)
                                     }
-                                }
-                                PositionResult::MarketOutOrder(
-                                    TradeResult::TradeOut(trade_out),
-                                    order,
-                                ) => {
-                                    log::info!(
-                                        "{} MarketOutOrder {} position received",
-                                        symbol,
-                                        order.id
-                                    );
-                                    let trade_data = TradeData::new(
-                                        symbol,
-                                        strategy_name,
-                                        trade_out,
-                                        options.clone(),
-                                    );
-
-                                    let order_data =
-                                        TradeData::new(symbol, strategy_name, order, options);
-                                    let trade_response =
-                                        broker_guard.close_order(trade_data, order_data).await;
-
-                                    match trade_response {
-                                        Ok(res) => match serde_json::to_string(&res) {
-                                            Ok(json_res) => Some(json_res),
-                                            Err(e) => Some(error::serialization(e, &command)),
-                                        },
-                                        Err(err) => {
-                                            log::error!("{:?} Command error {}", command, err);
-                                            None
+                                    Err(e) => Some(error::serialization(e, &command)),
+                                },
+                                Err(e) => error::executed_command(e, &command),
+                            }
+                        }
+                        PositionResult::MarketInOrder(TradeResult::TradeIn(trade_in), order) => {
+                            log::info!("{} MarketInOerder {} position received", symbol, order.id);
+
+                            let reserved = state
+                                .lock()
+                                .await
+                                .exposure
+                                .reserve(&bot_id, symbol, &trade_in);
+                            if let Err(err) = reserved {
+                                return Some(reject_trade_in(symbol, trade_in, err));
+                            }
+
+                            let trade_data = TradeData::new(
+                                symbol,
+                                strategy_name,
+                                trade_in.clone(),
+                                options.clone(),
+                            );
+                            let order_data = TradeData::new(symbol, strategy_name, order, options);
+                            let trade_response = with_timeout(&command, pool, &broker, async {
+                                broker.lock().await.open_order(trade_data, order_data).await
+                            })
+                            .await;
+                            release_unfilled(state, &bot_id, &trade_in, &trade_response).await;
+
+                            match trade_response {
+                                Ok(Ok(res)) => match serde_json::to_string(&res) {
+                                    Ok(json_res) => Some(json_res),
+                                    Err(e) => Some(error::serialization(e, &command)),
+                                },
+                                Ok(Err(e)) => error::executed_command(e, &command),
+                                Err(err) => Some(err),
+                            }
+                        }
+                        PositionResult::MarketOutOrder(TradeResult::TradeOut(trade_out), order) => {
+                            log::info!("{} MarketOutOrder {} position received", symbol, order.id);
+                            let id = trade_out.id;
+                            let trade_data =
+                                TradeData::new(symbol, strategy_name, trade_out, options.clone());
+
+                            let order_data = TradeData::new(symbol, strategy_name, order, options);
+                            let trade_response =
+                                match with_timeout(&command, pool, &broker, async {
+                                    broker
+                                        .lock()
+                                        .await
+                                        .close_order(trade_data, order_data)
+                                        .await
+                                })
+                                .await
+                                {
+                                    Ok(response) => response,
+                                    Err(err) => return Some(err),
+                                };
+
+                            match trade_response {
+                                Ok(res) => match serde_json::to_string(&res) {
+                                    Ok(json_res) => {
+                                        if is_accepted(&res.payload) {
+                                            state.lock().await.exposure.close(&bot_id, id);
                                         }
+                                        Some(json_res)
                                     }
-                                }
-                                _ => {
-                                    todo!();
-                                }
+                                    Err(e) => Some(error::serialization(e, &command)),
+                                },
+                                Err(e) => error::executed_command(e, &command),
                             }
                         }
-                        None => None,
-                    };
-                    json_response
+                        _ => Some(error::response(
+                            &command,
+                            error::ErrorCode::Validation,
+                            "Unsupported position",
+                        )),
+                    }
                 }
                 CommandType::GetActivePositions => {
-                    let strategy_name = query
-                        .data
-                        .as_ref()
-                        .and_then(|data| data["strategy_name"].as_str())
-                        .unwrap_or_default()
-                        .to_string();
+                    let broker = match pool.get(Lane::Execution).await {
+                        Ok(broker) => broker,
+                        Err(err) => return Some(error::broker_unavailable(err, &command)),
+                    };
+
+                    let strategy_name = &symbol_payload.strategy_name;
 
                     log::info!("Getting {}_{} active positions", symbol, strategy_name);
 
-                    let response = broker
-                        .lock()
-                        .await
-                        .get_active_positions(&symbol, &strategy_name)
-                        .await;
+                    let response = match with_timeout(&command, pool, &broker, async {
+                        broker
+                            .lock()
+                            .await
+                            .get_active_positions(symbol, strategy_name)
+                            .await
+                    })
+                    .await
+                    {
+                        Ok(response) => response,
+                        Err(err) => return Some(err),
+                    };
 
                     match response {
                         Ok(res) => match serde_json::to_string(&res) {
@@ -383,22 +560,68 @@ where
                     }
                 }
                 CommandType::UpdateBotData => {
-                    match &query.data {
-                        Some(data) => {
-                            let bot: BotData = serde_json::from_value(data.clone()).unwrap();
-                            db::bot::upsert(db_client, &bot).await.unwrap();
-                            session::find(sessions, addr, |session| {
-                                *session = session.update_last_data().clone();
-                            })
-                            .await;
-                        }
-                        None => (),
+                    let bot: BotData = match command::payload(&command, data) {
+                        Ok(bot) => bot,
+                        Err(err) => return Some(err),
+                    };
+                    let BotPayload {
+                        symbol,
+                        time_frame,
+                        strategy_name,
+                    } = match command::payload(&command, data) {
+                        Ok(payload) => payload,
+                        Err(err) => return Some(err),
+                    };
+
+                    let pip_value: PipValue = match command::payload(&command, data) {
+                        Ok(pip_value) => pip_value,
+                        Err(err) => return Some(err),
+                    };
+
+                    let extras = data.map(|data| bot_extras(data, &bot)).unwrap_or_default();
+                    if let Err(err) = db::bot::upsert(db_client, &bot, extras).await {
+                        return Some(error::response(&command, error::ErrorCode::DbFailure, err));
                     }
+
+                    let (symbol, strategy_name) = (symbol.as_str(), strategy_name.as_str());
+                    let bot_name = [symbol, "_", &time_frame, "_", strategy_name].concat();
+                    let open_trades = bot
+                        .trades_in()
+                        .get(bot.trades_out().len()..)
+                        .unwrap_or_default();
+
+                    let mut state = state.lock().await;
+                    state.portfolio.update_bot(
+                        &bot.uuid().to_string(),
+                        &bot_name,
+                        bot.trades_in(),
+                        bot.trades_out(),
+                        pip_value,
+                    );
+                    state
+                        .exposure
+                        .sync_bot(&bot.uuid().to_string(), symbol, open_trades);
+                    session::find(sessions, addr, |session| {
+                        *session = session.update_last_data().clone();
+                    })
+                    .await;
                     None
                 }
                 CommandType::GetInstrumentTick => {
+                    let broker = match pool.get(Lane::Data).await {
+                        Ok(broker) => broker,
+                        Err(err) => return Some(error::broker_unavailable(err, &command)),
+                    };
+
                     log::info!("Getting {} tick data", symbol);
-                    let response = broker.lock().await.get_instrument_tick(symbol).await;
+                    let response = match with_timeout(&command, pool, &broker, async {
+                        broker.lock().await.get_instrument_tick(symbol).await
+                    })
+                    .await
+                    {
+                        Ok(response) => response,
+                        Err(err) => return Some(err),
+                    };
                     match response {
                         Ok(res) => match serde_json::to_string(&res) {
                             Ok(json_res) => Some(json_res),
@@ -409,15 +632,21 @@ where
                 }
                 CommandType::SubscribeStream => {
                     session::find(sessions, addr, |session| {
-                        stream::listen(broker.clone(), session.clone());
+                        stream::listen::<BK>(
+                            session.clone(),
+                            *addr,
+                            state.clone(),
+                            Arc::clone(pool),
+                        );
                     })
                     .await;
                     Some("".to_string())
                 }
-                _ => {
-                    log::error!("Unknown command received {:?}", &command);
-                    None
-                }
+                _ => Some(error::response(
+                    &command,
+                    error::ErrorCode::Validation,
+                    "Unsupported command",
+                )),
             };
             data
         }
@@ -428,6 +657,7 @@ where
             .await;
 
             session::destroy(sessions, addr).await;
+            stream::unsubscribe(state, addr).await;
             None
         }
         _ => {
@@ -437,3 +667,297 @@ where
     };
     data
 }
+
+// Fields sent by the bot that BotData doesn't know about
+fn bot_extras(data: &Value, bot: &BotData) -> Document {
+    let known_fields = serde_json::to_value(bot).unwrap_or_default();
+
+    match data.as_object() {
+        Some(fields) => fields
+            .iter()
+            .filter(|(key, _)| known_fields.get(key.as_str()).is_none())
+            .filter_map(|(key, value)| {
+                bson::to_bson(value)
+                    .ok()
+                    .map(|value| (key.to_owned(), value))
+            })
+            .collect(),
+        None => Document::new(),
+    }
+}
+
+fn with_extras(bot: &BotData, extras: Document) -> Value {
+    let mut value = serde_json::to_value(bot).unwrap();
+
+    if let Some(fields) = value.as_object_mut() {
+        for (key, extra) in extras {
+            fields.insert(key, Bson::into_relaxed_extjson(extra));
+        }
+    }
+
+    value
+}
+
+// Echoes the command request id so the bot can match the response
+fn with_request_id(response: String, request_id: Value) -> String {
+    match serde_json::from_str::<Value>(&response) {
+        Ok(Value::Object(mut fields)) => {
+            fields.insert("request_id".to_owned(), request_id);
+            Value::Object(fields).to_string()
+        }
+        _ => response,
+    }
+}
+
+fn is_accepted<T>(payload: &Option<TradeResponse<T>>) -> bool {
+    payload.as_ref().map_or(false, |payload| payload.accepted)
+}
+
+// The session id is the bot uuid once InitSession is received
+async fn bot_id(sessions: &mut Sessions, addr: &SocketAddr) -> String {
+    let mut bot_id = String::new();
+    session::find(sessions, addr, |session| {
+        bot_id = session.session_id.to_string();
+    })
+    .await;
+    bot_id
+}
+
+// Entries reserved before being sent are released unless the broker fills
+// them. On timeouts the bot checks its active positions and syncs them back.
+async fn release_unfilled<T, E>(
+    state: &AppState,
+    bot_id: &str,
+    trade_in: &TradeIn,
+    trade_response: &Result<Result<ResponseBody<TradeResponse<T>>, E>, String>,
+) {
+    let filled = matches!(trade_response, Ok(Ok(res)) if is_accepted(&res.payload));
+
+    if !filled {
+        state.lock().await.exposure.close(bot_id, trade_in.id);
+    }
+}
+
+// Sent as a not accepted TradeInFulfilled so the bot rolls the trade back
+fn reject_trade_in(symbol: &str, trade_in: TradeIn, err: ExposureError) -> String {
+    log::warn!("{} TradeIn {} rejected. {}", symbol, trade_in.id, err);
+
+    let response = ResponseBody {
+        response: ResponseType::TradeInFulfilled,
+        payload: Some(TradeResponse {
+            symbol: symbol.to_owned(),
+            accepted: false,
+            data: trade_in,
+        }),
+    };
+
+    serde_json::to_string(&response).unwrap()
+}
+
+async fn is_authorized(
+    sessions: &mut Sessions,
+    addr: &SocketAddr,
+    symbol: &str,
+    strategy_name: &str,
+) -> bool {
+    let mut authorized = false;
+    session::find(sessions, addr, |session| {
+        authorized = session.grant.allows(symbol, strategy_name);
+    })
+    .await;
+
+    if !authorized {
+        log::warn!("{} not allowed to trade {}_{}", addr, symbol, strategy_name);
+    }
+    authorized
+}
+
+// Trading commands must match the symbol and strategy bound on InitSession
+async fn is_bound(
+    sessions: &mut Sessions,
+    addr: &SocketAddr,
+    symbol: &str,
+    strategy_name: &str,
+) -> bool {
+    let mut bound = false;
+    session::find(sessions, addr, |session| {
+        bound = session.trades(symbol, strategy_name);
+    })
+    .await;
+
+    if !bound {
+        log::warn!(
+            "{} not bound to trade {}_{}. InitSession first",
+            addr,
+            symbol,
+            strategy_name
+        );
+    }
+    bound
+}
+
+async fn handle_extended<BK>(
+    sessions: &mut Sessions,
+    addr: &SocketAddr,
+    query: ExtendedCommand,
+    pool: &BrokerPool<BK>,
+) -> Option<String>
+where
+    BK: stream::BrokerStream + OrderModifier + Send + Sync + 'static,
+{
+    match query.command {
+        ExtendedCommandType::ModifyOrder => {
+            modify_order(sessions, addr, query.data.as_ref(), pool).await
+        }
+        ExtendedCommandType::GetLotLimits => lot_limits(query.data.as_ref(), pool).await,
+    }
+}
+
+async fn lot_limits<BK>(data: Option<&Value>, pool: &BrokerPool<BK>) -> Option<String>
+where
+    BK: stream::BrokerStream + OrderModifier + Send + Sync + 'static,
+{
+    let command = ExtendedCommandType::GetLotLimits;
+    let SymbolPayload { symbol, .. } = match command::payload(command, data) {
+        Ok(payload) => payload,
+        Err(err) => return Some(err),
+    };
+
+    let broker = match pool.get(Lane::Data).await {
+        Ok(broker) => broker,
+        Err(err) => {
+            return Some(error::response(
+                command,
+                error::ErrorCode::BrokerRejected,
+                format!("Broker not available. {}", err),
+            ))
+        }
+    };
+
+    let res = match with_timeout(command, pool, &broker, async {
+        broker.lock().await.lot_limits(&symbol).await
+    })
+    .await
+    {
+        Ok(res) => res,
+        Err(err) => return Some(err),
+    };
+
+    match res {
+        Ok(limits) => {
+            let response = ExtendedResponse {
+                response: command,
+                payload: limits,
+            };
+            Some(serde_json::to_string(&response).unwrap_or_default())
+        }
+        Err(err) => Some(error::response(
+            command,
+            error::ErrorCode::BrokerRejected,
+            err,
+        )),
+    }
+}
+
+async fn modify_order<BK>(
+    sessions: &mut Sessions,
+    addr: &SocketAddr,
+    data: Option<&Value>,
+    pool: &BrokerPool<BK>,
+) -> Option<String>
+where
+    BK: stream::BrokerStream + OrderModifier + Send + Sync + 'static,
+{
+    let command = ExtendedCommandType::ModifyOrder;
+    let ModifyOrderPayload {
+        symbol,
+        strategy_name,
+        data: order,
+    } = match command::payload(command, data) {
+        Ok(payload) => payload,
+        Err(err) => return Some(err),
+    };
+    let (symbol, strategy_name) = (symbol.as_str(), strategy_name.as_str());
+
+    if !is_bound(sessions, addr, symbol, strategy_name).await {
+        return Some(error::response(
+            command,
+            error::ErrorCode::Unauthorized,
+            format!("Session not allowed to trade {}_{}", symbol, strategy_name),
+        ));
+    }
+
+    let broker = match pool.get(Lane::Execution).await {
+        Ok(broker) => broker,
+        Err(err) => {
+            return Some(error::response(
+                command,
+                error::ErrorCode::BrokerRejected,
+                format!("Broker not available. {}", err),
+            ))
+        }
+    };
+
+    if !broker.lock().await.modifies_orders() {
+        return Some(error::response(
+            command,
+            error::ErrorCode::Unsupported,
+            "The broker can't modify orders",
+        ));
+    }
+
+    log::info!("Modifying {}_{} order {}", symbol, strategy_name, order.id);
+
+    let res = match with_timeout(command, pool, &broker, async {
+        broker
+            .lock()
+            .await
+            .modify_order(symbol, strategy_name, order)
+            .await
+    })
+    .await
+    {
+        Ok(res) => res,
+        Err(err) => return Some(err),
+    };
+
+    match res {
+        Ok(res) => {
+            let response = ExtendedResponse {
+                response: command,
+                payload: Some(res),
+            };
+            Some(serde_json::to_string(&response).unwrap_or_default())
+        }
+        Err(err) => Some(error::response(
+            command,
+            error::ErrorCode::BrokerRejected,
+            err,
+        )),
+    }
+}
+
+// Broker calls are bounded so a stuck broker doesn't leave the bot waiting.
+// Waiting for the client lock counts. A call cancelled half way leaves the
+// client connection in an unknown state, so the client logs in again.
+async fn with_timeout<C, BK, T, F>(
+    command: C,
+    pool: &BrokerPool<BK>,
+    broker: &Arc<Mutex<BK>>,
+    call: F,
+) -> Result<T, String>
+where
+    C: Serialize + Debug,
+    BK: stream::BrokerStream + Send + Sync + 'static,
+    F: Future<Output = T>,
+{
+    let timeout = settings::get().broker_timeout();
+
+    match time::timeout(timeout, call).await {
+        Ok(response) => Ok(response),
+        Err(_) => {
+            pool.discard(broker).await;
+            Err(error::timeout(command, timeout))
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use crate::db;
+use crate::error::RsAlgoErrorKind;
+use crate::handlers::state::{AppState, Portfolio};
+use crate::settings;
+
+use rs_algo_shared::helpers::date::{DateTime, Local};
+
+use std::sync::Arc;
+use tokio::time;
+
+/// Last portfolio snapshot, or a new portfolio when there is none. Failing to
+/// read it stops the server, so snapshots of a reset portfolio don't follow.
+pub async fn restore(db_client: &mongodb::Client) -> Result<Portfolio, RsAlgoErrorKind> {
+    match db::portfolio::find_last_snapshot(db_client).await {
+        Ok(Some(portfolio)) => {
+            log::info!(
+                "Portfolio restored from its {} snapshot",
+                portfolio.last_updated()
+            );
+            Ok(portfolio)
+        }
+        Ok(None) => Ok(Portfolio::new(Portfolio::initial_value_from_env()?)),
+        Err(err) => {
+            log::error!("Portfolio not restored: {}", err);
+            Err(RsAlgoErrorKind::NoDbConnection)
+        }
+    }
+}
+
+pub async fn init(state: AppState, db_client: Arc<mongodb::Client>) {
+    let mut interval = time::interval(settings::get().snapshot_interval());
+
+    tokio::spawn(async move {
+        let mut last_snapshot: Option<DateTime<Local>> = None;
+
+        loop {
+            interval.tick().await;
+
+            let portfolio = { state.lock().await.portfolio.clone() };
+            let last_updated = portfolio.last_updated();
+
+            if last_snapshot.map_or(true, |date| last_updated > date) {
+                match db::portfolio::insert_snapshot(&db_client, &portfolio).await {
+                    Ok(_) => {
+                        log::info!("Portfolio snapshot saved");
+                        last_snapshot = Some(last_updated);
+                    }
+                    Err(err) => log::error!("Portfolio snapshot not saved: {:?}", err),
+                }
+            }
+        }
+    });
+}
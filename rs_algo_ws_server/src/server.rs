@@ -1,10 +1,19 @@
+use crate::auth::{self, Auth};
+use crate::broker::paper::{self, PaperBroker, PaperConfig};
+use crate::broker::pool::BrokerPool;
+use crate::broker::{self, BrokerKind, OrderModifier};
 use crate::db;
 use crate::error::RsAlgoErrorKind;
 use crate::handlers::*;
 use crate::heart_beat;
 use crate::message;
+use crate::portfolio;
+use crate::settings::{self, Settings};
+use crate::shutdown::{self, Tasks};
+use crate::tls;
 
 use crate::handlers::session::Sessions;
+use crate::handlers::state::{AppState, State};
 use rs_algo_shared::broker::xtb_stream::*;
 
 use futures_channel::mpsc::unbounded;
@@ -12,16 +21,21 @@ use futures_util::{future, pin_mut, stream::TryStreamExt, StreamExt};
 
 use std::sync::Arc;
 use std::{collections::HashMap, env, net::SocketAddr};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
 use tokio::sync::Mutex;
-use tokio_tungstenite::accept_async;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::accept_hdr_async;
+use tungstenite::handshake::server::{Request, Response};
 use tungstenite::protocol::Message;
 
 pub async fn run(addr: String) -> Result<(), RsAlgoErrorKind> {
     let addr = addr
         .parse::<SocketAddr>()
         .map_err(|_| RsAlgoErrorKind::InvalidAddress)?;
+    settings::init(Settings::from_env()?);
     let mut sessions = Sessions::new(Mutex::new(HashMap::new()));
+    let tls = tls::acceptor()?;
     let socket = TcpListener::bind(&addr)
         .await
         .map_err(|_| RsAlgoErrorKind::SocketError)?;
@@ -38,55 +52,168 @@ pub async fn run(addr: String) -> Result<(), RsAlgoErrorKind> {
     heart_beat::init(&mut sessions).await;
 
     let db_client = Arc::new(mongo_client);
+    let state = State::new(portfolio::restore(&db_client).await?);
+
+    portfolio::init(state.clone(), Arc::clone(&db_client)).await;
+
+    let broker_kind = broker::kind();
+    log::info!("Using {:?} broker", broker_kind);
+
+    match broker_kind {
+        BrokerKind::Xtb => accept_connections::<Xtb>(socket, tls, sessions, state, db_client).await,
+        BrokerKind::Paper => {
+            paper::init(PaperConfig::from_env()?);
+            accept_connections::<PaperBroker>(socket, tls, sessions, state, db_client).await
+        }
+    };
+
+    Ok(())
+}
+
+async fn accept_connections<BK>(
+    socket: TcpListener,
+    tls: Option<TlsAcceptor>,
+    sessions: Sessions,
+    state: AppState,
+    db_client: Arc<mongodb::Client>,
+) where
+    BK: stream::BrokerStream + OrderModifier + Send + Sync + 'static,
+{
+    let pool = BrokerPool::<BK>::new();
+    pool.keepalive();
+    let auth = Arc::new(Auth::from_env());
+    let tasks = Arc::new(Tasks::default());
+
+    let signal = shutdown::signal();
+    pin_mut!(signal);
+
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = socket.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    log::error!("Can't accept connections {}", err);
+                    break;
+                }
+            },
+            _ = &mut signal => break,
+        };
 
-    while let Ok((mut stream, addr)) = socket.accept().await {
         let sessions = sessions.clone();
         let db_client = Arc::clone(&db_client);
+        let state = state.clone();
+        let pool = Arc::clone(&pool);
+        let auth = Arc::clone(&auth);
+        let tls = tls.clone();
+        let tasks = Arc::clone(&tasks);
 
         tokio::spawn(async move {
-            handle_connection(sessions, &mut stream, addr, db_client).await;
+            let _connection = tasks.connections.track();
+
+            match tls {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(mut stream) => {
+                        handle_connection::<BK, _>(
+                            sessions,
+                            state,
+                            pool,
+                            auth,
+                            &tasks,
+                            &mut stream,
+                            addr,
+                            db_client,
+                        )
+                        .await
+                    }
+                    Err(err) => log::error!("TLS handshake with {addr} failed {err}"),
+                },
+                None => {
+                    let mut stream = stream;
+                    handle_connection::<BK, _>(
+                        sessions,
+                        state,
+                        pool,
+                        auth,
+                        &tasks,
+                        &mut stream,
+                        addr,
+                        db_client,
+                    )
+                    .await
+                }
+            }
         });
     }
 
-    Ok(())
+    shutdown::run(&sessions, &state, &db_client, &tasks).await;
 }
 
-async fn handle_connection(
+async fn handle_connection<BK, S>(
     mut sessions: Sessions,
-    raw_stream: &mut TcpStream,
+    state: AppState,
+    pool: Arc<BrokerPool<BK>>,
+    auth: Arc<Auth>,
+    tasks: &Tasks,
+    raw_stream: &mut S,
     addr: SocketAddr,
     db_client: Arc<mongodb::Client>,
-) {
+) where
+    BK: stream::BrokerStream + OrderModifier + Send + Sync + 'static,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     loop {
         let (recipient, receiver) = unbounded();
-
-        match accept_async(&mut *raw_stream).await {
+        let mut grant = None;
+
+        let handshake = accept_hdr_async(
+            &mut *raw_stream,
+            |request: &Request, response: Response| match auth.authenticate(request) {
+                Some(token_grant) => {
+                    grant = Some(token_grant);
+                    Ok(response)
+                }
+                None => {
+                    log::warn!("Unauthorized connection from {addr} rejected");
+                    Err(auth::unauthorized())
+                }
+            },
+        )
+        .await;
+
+        match handshake {
             Ok(msg) => {
                 log::info!("New connection from: {addr}");
 
-                let username = &env::var("BROKER_USERNAME").unwrap();
-                let password = &env::var("BROKER_PASSWORD").unwrap();
-                let mut broker = Xtb::new().await;
-                broker.login(username, password).await.unwrap();
-
-                let broker = Arc::new(Mutex::new(broker));
-                let new_session = session::create(&mut sessions, &addr, recipient).await;
+                let grant = grant.take().unwrap_or_default();
+                let new_session = session::create(&mut sessions, &addr, recipient, grant).await;
                 let (outgoing, incoming) = msg.split();
 
+                // Each command runs in its own task so a slow broker call
+                // doesn't hold up the rest of the bot's commands
                 let broadcast_incoming = incoming.try_for_each(|msg| {
-                    let broker = Arc::clone(&broker);
+                    let pool = Arc::clone(&pool);
                     let db_client = Arc::clone(&db_client);
                     let mut sessions = Arc::clone(&sessions);
+                    let state = Arc::clone(&state);
                     let new_session = new_session.clone();
-                    async move {
-                        match message::handle(&mut sessions, &addr, msg, broker, &db_client).await {
-                            Some(msg) => message::send(&new_session, Message::Text(msg))
-                                .await
-                                .unwrap(),
+                    let command = tasks.commands.track();
+                    tokio::spawn(async move {
+                        let _command = command;
+                        match message::handle(&mut sessions, &state, &addr, msg, &pool, &db_client)
+                            .await
+                        {
+                            Some(msg) => {
+                                if message::send(&new_session, Message::Text(msg))
+                                    .await
+                                    .is_err()
+                                {
+                                    log::error!("Can't reply to {}. Connection closed", addr);
+                                }
+                            }
                             None => (),
                         }
-                        Ok(())
-                    }
+                    });
+                    future::ok(())
                 });
 
                 let receive_from_others = receiver.map(Ok).forward(outgoing);
@@ -96,12 +223,19 @@ async fn handle_connection(
             Err(err) => {
                 log::error!("Client connection error: {:?}", err);
 
+                let mut bot_id = None;
                 session::find(&mut sessions, &addr, |session| {
                     log::error!("Communication with {} {} lost!", session.bot_name(), addr);
+                    bot_id = Some(session.session_id.to_string());
                 })
                 .await;
 
+                if let Some(bot_id) = bot_id {
+                    state.lock().await.exposure.release_bot(&bot_id);
+                }
+
                 session::destroy(&mut sessions, &addr).await;
+                stream::unsubscribe(&state, &addr).await;
 
                 break;
             }
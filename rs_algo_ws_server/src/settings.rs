@@ -0,0 +1,157 @@
+use crate::error::RsAlgoErrorKind;
+
+use rs_algo_shared::models::environment::{self, Environment};
+use rs_algo_shared::models::mode::{self, ExecutionMode};
+
+use std::env;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Server settings, read once from env vars at startup so a missing or
+/// invalid value stops the server instead of failing a command later.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    env: String,
+    execution_mode: String,
+    keepalive_interval: u64,
+    broker_timeout: u64,
+    snapshot_interval: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            env: String::new(),
+            execution_mode: String::new(),
+            keepalive_interval: 30000,
+            broker_timeout: 30,
+            snapshot_interval: 300,
+        }
+    }
+}
+
+impl Settings {
+    pub fn from_env() -> Result<Self, RsAlgoErrorKind> {
+        Self::from_lookup(|key| env::var(key).ok())
+    }
+
+    fn from_lookup<F>(lookup: F) -> Result<Self, RsAlgoErrorKind>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let default = Self::default();
+
+        Ok(Self {
+            env: required(&lookup, "ENV")?,
+            execution_mode: required(&lookup, "EXECUTION_MODE")?,
+            keepalive_interval: parse(&lookup, "KEEPALIVE_INTERVAL", default.keepalive_interval)?,
+            broker_timeout: parse(&lookup, "BROKER_TIMEOUT", default.broker_timeout)?,
+            snapshot_interval: parse(
+                &lookup,
+                "PORTFOLIO_SNAPSHOT_INTERVAL",
+                default.snapshot_interval,
+            )?,
+        })
+    }
+
+    pub fn env(&self) -> Environment {
+        environment::from_str(&self.env)
+    }
+
+    pub fn execution_mode(&self) -> ExecutionMode {
+        mode::from_str(&self.execution_mode)
+    }
+
+    pub fn keepalive_interval(&self) -> Duration {
+        Duration::from_millis(self.keepalive_interval)
+    }
+
+    pub fn broker_timeout(&self) -> Duration {
+        Duration::from_secs(self.broker_timeout)
+    }
+
+    pub fn snapshot_interval(&self) -> Duration {
+        Duration::from_secs(self.snapshot_interval)
+    }
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Sets the server settings. Called once at startup, before any connection
+/// is accepted.
+pub fn init(settings: Settings) {
+    if SETTINGS.set(settings).is_err() {
+        log::warn!("Settings already initialized");
+    }
+}
+
+pub fn get() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
+
+fn required<F>(lookup: &F, key: &str) -> Result<String, RsAlgoErrorKind>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    match lookup(key) {
+        Some(value) if !value.is_empty() => Ok(value),
+        _ => {
+            log::error!("{} not found", key);
+            Err(RsAlgoErrorKind::EnvVarNotFound)
+        }
+    }
+}
+
+fn parse<F, T>(lookup: &F, key: &str, default: T) -> Result<T, RsAlgoErrorKind>
+where
+    F: Fn(&str) -> Option<String>,
+    T: FromStr,
+{
+    match lookup(key) {
+        Some(value) => value.parse::<T>().map_err(|_| {
+            log::error!("Invalid {} {:?}", key, value);
+            RsAlgoErrorKind::InvalidEnvVar
+        }),
+        None => Ok(default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> + '_ {
+        |key| {
+            vars.iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    const REQUIRED: [(&str, &str); 2] = [("ENV", "development"), ("EXECUTION_MODE", "Bot")];
+
+    #[test]
+    fn optional_settings_have_defaults() {
+        let settings = Settings::from_lookup(lookup(&REQUIRED)).unwrap();
+
+        assert_eq!(settings.keepalive_interval(), Duration::from_millis(30000));
+        assert_eq!(settings.broker_timeout(), Duration::from_secs(30));
+        assert_eq!(settings.snapshot_interval(), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn missing_required_settings_are_an_error() {
+        let settings = Settings::from_lookup(lookup(&[("ENV", "development")]));
+
+        assert_eq!(settings, Err(RsAlgoErrorKind::EnvVarNotFound));
+    }
+
+    #[test]
+    fn invalid_settings_are_an_error() {
+        let vars = [REQUIRED[0], REQUIRED[1], ("KEEPALIVE_INTERVAL", "30s")];
+        let settings = Settings::from_lookup(lookup(&vars));
+
+        assert_eq!(settings, Err(RsAlgoErrorKind::InvalidEnvVar));
+    }
+}
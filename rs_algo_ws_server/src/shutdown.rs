@@ -0,0 +1,124 @@
+use crate::db;
+use crate::handlers::session::Sessions;
+use crate::handlers::state::AppState;
+use crate::handlers::stream;
+use crate::message;
+
+use rs_algo_shared::ws::message::ReconnectOptions;
+
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{self, Instant};
+
+/// Number of tasks of one kind still running.
+#[derive(Debug, Default)]
+pub struct Running(AtomicUsize);
+
+/// Counts its task as running until dropped
+pub struct RunningGuard(Arc<Running>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Running {
+    pub fn track(self: &Arc<Self>) -> RunningGuard {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        RunningGuard(Arc::clone(self))
+    }
+
+    fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    // Returns the tasks still running at the deadline
+    async fn finished(&self, deadline: Instant) -> usize {
+        while self.count() > 0 && Instant::now() < deadline {
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        self.count()
+    }
+}
+
+/// Open bot connections and commands being handled, waited for on shutdown.
+#[derive(Debug, Default)]
+pub struct Tasks {
+    pub connections: Arc<Running>,
+    pub commands: Arc<Running>,
+}
+
+/// Resolves on SIGTERM or SIGINT
+pub async fn signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Can't listen to SIGTERM");
+
+    tokio::select! {
+        _ = terminate.recv() => log::warn!("SIGTERM received"),
+        _ = tokio::signal::ctrl_c() => log::warn!("SIGINT received"),
+    }
+}
+
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 20;
+
+// An invalid value can't stop the shutdown, so the default is used instead
+fn shutdown_timeout() -> u64 {
+    match env::var("SHUTDOWN_TIMEOUT") {
+        Ok(value) => value.parse::<u64>().unwrap_or_else(|_| {
+            log::error!(
+                "Invalid SHUTDOWN_TIMEOUT {:?}. Using {} secs",
+                value,
+                DEFAULT_SHUTDOWN_TIMEOUT
+            );
+            DEFAULT_SHUTDOWN_TIMEOUT
+        }),
+        Err(_) => DEFAULT_SHUTDOWN_TIMEOUT,
+    }
+}
+
+/// Bots are told to reconnect, to another replica or to this one once
+/// restarted, before the broker streams are closed. In flight commands are
+/// given SHUTDOWN_TIMEOUT secs to finish their Mongo writes.
+pub async fn run(
+    sessions: &Sessions,
+    state: &AppState,
+    db_client: &mongodb::Client,
+    tasks: &Tasks,
+) {
+    let deadline = Instant::now() + Duration::from_secs(shutdown_timeout());
+    let sessions: Vec<_> = sessions.lock().await.values().cloned().collect();
+
+    log::warn!("Shutting down. Reconnecting {} sessions", sessions.len());
+
+    for session in sessions.iter() {
+        message::send_reconnect(session, ReconnectOptions { clean_data: false }).await;
+    }
+
+    stream::close_all(state).await;
+
+    let commands = tasks.commands.finished(deadline).await;
+    if commands > 0 {
+        log::error!("{} commands didn't finish before shutdown", commands);
+    }
+
+    // Queued messages are sent before the connections are closed
+    for session in sessions.iter() {
+        session.recipient.close_channel();
+    }
+
+    let connections = tasks.connections.finished(deadline).await;
+    if connections > 0 {
+        log::warn!("{} connections still open on shutdown", connections);
+    }
+
+    let portfolio = { state.lock().await.portfolio.clone() };
+    match db::portfolio::insert_snapshot(db_client, &portfolio).await {
+        Ok(_) => log::info!("Final portfolio snapshot saved"),
+        Err(err) => log::error!("Final portfolio snapshot not saved: {:?}", err),
+    }
+
+    log::info!("Shutdown completed");
+}
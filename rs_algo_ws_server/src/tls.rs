@@ -0,0 +1,83 @@
+use crate::error::RsAlgoErrorKind;
+
+use rustls_pemfile::Item;
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// TLS is terminated by the server when WS_TLS_CERT and WS_TLS_KEY point to
+/// PEM files. Without them connections are plain ws://. Setting only one of
+/// them is an error, so a half configured server doesn't start without TLS.
+pub fn acceptor() -> Result<Option<TlsAcceptor>, RsAlgoErrorKind> {
+    let (cert_path, key_path) = match (var("WS_TLS_CERT"), var("WS_TLS_KEY")) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        (None, None) => return Ok(None),
+        _ => {
+            log::error!("WS_TLS_CERT and WS_TLS_KEY must be set together");
+            return Err(RsAlgoErrorKind::TlsError);
+        }
+    };
+
+    let certs = load_certs(&cert_path)?;
+    let key = load_key(&key_path)?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| {
+            log::error!("Invalid TLS certificate {}", err);
+            RsAlgoErrorKind::TlsError
+        })?;
+
+    log::info!("TLS enabled with {}", cert_path);
+
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+fn var(key: &str) -> Option<String> {
+    env::var(key).ok().filter(|value| !value.is_empty())
+}
+
+fn open(path: &str) -> Result<BufReader<File>, RsAlgoErrorKind> {
+    File::open(path).map(BufReader::new).map_err(|err| {
+        log::error!("Can't open {} {}", path, err);
+        RsAlgoErrorKind::TlsError
+    })
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, RsAlgoErrorKind> {
+    let certs = rustls_pemfile::certs(&mut open(path)?).map_err(|err| {
+        log::error!("Can't read certificates from {} {}", path, err);
+        RsAlgoErrorKind::TlsError
+    })?;
+
+    match certs.is_empty() {
+        true => {
+            log::error!("No certificates found in {}", path);
+            Err(RsAlgoErrorKind::TlsError)
+        }
+        false => Ok(certs.into_iter().map(Certificate).collect()),
+    }
+}
+
+fn load_key(path: &str) -> Result<PrivateKey, RsAlgoErrorKind> {
+    let items = rustls_pemfile::read_all(&mut open(path)?).map_err(|err| {
+        log::error!("Can't read private key from {} {}", path, err);
+        RsAlgoErrorKind::TlsError
+    })?;
+
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            log::error!("No private key found in {}", path);
+            RsAlgoErrorKind::TlsError
+        })
+}